@@ -0,0 +1,364 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bollard::auth::DockerCredentials;
+use lazy_static::lazy_static;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, env};
+
+use super::{DeployBackend, Machine, MachineSpec};
+
+lazy_static! {
+    static ref FLY_HOSTNAME: String = env::var("FLY_API_HOSTNAME").unwrap();
+    pub static ref FLY_API_TOKEN: String = env::var("FLY_API_TOKEN").unwrap();
+    static ref AUTH_HEADER: String = format!("Bearer {}", *FLY_API_TOKEN);
+}
+
+#[derive(Deserialize, Clone, JsonSchema)]
+#[schemars(rename = "FlyConfig")]
+pub struct Config {
+    pub org: String,
+    pub app_name: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct MachineConfig {
+    pub image: String,
+    pub auto_destroy: Option<bool>,
+    pub env: Option<HashMap<String, String>>,
+    pub guest: Option<AllocatedResources>,
+    pub services: Option<Vec<MachineService>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct MachineService {
+    // TODO make type with "udp" and "tcp" as arguments
+    pub protocol: String,
+    pub internal_port: u32,
+    pub ports: Vec<MachinePort>,
+    pub concurrency: Option<MachineConcurrency>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct MachineConcurrency {
+    pub soft_limit: u32,
+    pub hard_limit: u32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct MachinePort {
+    pub port: Option<u32>,
+    pub start_port: Option<u32>,
+    pub end_port: Option<u32>,
+    pub handlers: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct AllocatedResources {
+    pub cpu_kind: String,
+    pub cpus: Option<u32>,
+    pub kernel_args: Option<Vec<String>>,
+    pub memory_mb: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct MachineInfo {
+    pub id: String,
+    pub name: String,
+    pub state: String,
+    pub region: String,
+    pub image_ref: MachineImageDetails,
+    pub config: MachineConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct MachineImageDetails {
+    pub registry: String,
+    pub repository: String,
+    pub tag: String,
+    pub digest: String,
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+pub struct AppInfo {
+    pub id: String,
+    pub name: String,
+    pub status: String,
+}
+
+fn create_app(name: &str, org: &str) -> Result<AppInfo> {
+    let app: AppInfo = ureq::post(&format!("{}/v1/apps", *FLY_HOSTNAME))
+        .set("Authorization", &AUTH_HEADER)
+        .send_json(ureq::json!({
+            "app_name": name,
+            "org_slug": org,
+        }))?
+        .into_json()?;
+    Ok(app)
+}
+
+fn get_app(name: &str) -> Result<AppInfo> {
+    let machine = ureq::get(&format!("{}/v1/apps/{name}", *FLY_HOSTNAME))
+        .set("Authorization", &AUTH_HEADER)
+        .call()?
+        .into_json()?;
+    Ok(machine)
+}
+
+pub fn ensure_app(config: &Config) -> Result<AppInfo> {
+    let app = get_app(&config.app_name);
+    if let Err(ref e) = app {
+        if let Some(ureq::Error::Status(404, _)) = e.downcast_ref::<ureq::Error>() {
+            eprintln!("App {} not found. Creating...", config.app_name);
+            return create_app(&config.app_name, &config.org);
+        }
+    }
+    app
+}
+
+pub fn create_machine(
+    app: &str,
+    name: &str,
+    machine_config: &MachineConfig,
+) -> Result<MachineInfo> {
+    let url = format!("{}/v1/apps/{}/machines", *FLY_HOSTNAME, app);
+
+    let json = ureq::post(&url)
+        .set("Authorization", &AUTH_HEADER)
+        .send_json(ureq::json!({
+            "name": name,
+            "config": machine_config,
+        }))
+        .map_err(|err| {
+            anyhow!(
+                "Create machine failed: {:?}",
+                err.into_response().unwrap().into_string()
+            )
+        })?
+        .into_json()?;
+
+    Ok(json)
+}
+
+pub fn update_machine(app: &str, id: &str, machine_config: &MachineConfig) -> Result<MachineInfo> {
+    let url = format!("{}/v1/apps/{app}/machines/{id}", *FLY_HOSTNAME);
+
+    let json = ureq::post(&url)
+        .set("Authorization", &AUTH_HEADER)
+        .send_json(ureq::json!({
+            "config": machine_config,
+        }))
+        .map_err(|err| {
+            anyhow!(
+                "{:?}",
+                match err.into_response() {
+                    Some(resp) => resp.into_string(),
+                    None => Ok(String::from("None???")),
+                }
+            )
+        })?
+        .into_json()?;
+
+    Ok(json)
+}
+
+pub fn wait_for_machine(app: &str, id: &str) -> Result<()> {
+    ureq::get(&format!(
+        "{}/v1/apps/{app}/machines/{id}/wait",
+        *FLY_HOSTNAME
+    ))
+    .set("Authorization", &AUTH_HEADER)
+    .call()?
+    .into_string()?;
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ExecResponse {
+    pub exit_code: Option<i32>,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+}
+
+pub fn execute_command(app: &str, id: &str, command: Vec<String>) -> Result<ExecResponse> {
+    Ok(ureq::post(&format!(
+        "{}/v1/apps/{app}/machines/{id}/exec",
+        *FLY_HOSTNAME
+    ))
+    .set("Authorization", &AUTH_HEADER)
+    .send_json(ureq::json!({
+        "command": command
+    }))?
+    .into_json()?)
+}
+
+pub fn destroy_machine(app: &str, id: &str) -> Result<()> {
+    ureq::delete(&format!(
+        "{}/v1/apps/{app}/machines/{id}?force=true",
+        *FLY_HOSTNAME
+    ))
+    .set("Authorization", &AUTH_HEADER)
+    .call()
+    .map_err(|err| {
+        anyhow!(
+            "Destroy machine failed: {:?}",
+            err.into_response().map(|resp| resp.into_string())
+        )
+    })?;
+    Ok(())
+}
+
+pub fn list_machines(app: &str) -> Result<Vec<MachineInfo>> {
+    let machines = ureq::get(&format!("{}/v1/apps/{}/machines", *FLY_HOSTNAME, app))
+        .set("Authorization", &AUTH_HEADER)
+        .call()?
+        .into_json()?;
+    Ok(machines)
+}
+
+/// Run one of the api calls above on a blocking thread, ureq would stall the runtime otherwise
+async fn blocking<T: Send + 'static>(
+    call: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(call).await?
+}
+
+pub struct FlyBackend {
+    config: Config,
+}
+
+impl FlyBackend {
+    pub fn new(config: &Config) -> FlyBackend {
+        FlyBackend {
+            config: config.clone(),
+        }
+    }
+
+    fn machine(&self, info: MachineInfo) -> Machine {
+        Machine {
+            address: format!("{}.vm.{}.internal", info.id, self.config.app_name),
+            id: info.id,
+            name: info.name,
+            state: info.state,
+            image: info.config.image,
+            digest: Some(info.image_ref.digest),
+        }
+    }
+
+    fn machine_config(spec: &MachineSpec) -> MachineConfig {
+        MachineConfig {
+            image: spec.image.clone(),
+            env: spec.env.clone(),
+            guest: Some(AllocatedResources {
+                cpu_kind: "shared".to_string(),
+                cpus: spec.cpus,
+                memory_mb: spec.memory_mb,
+                kernel_args: None,
+            }),
+            services: if spec.services.is_empty() {
+                None
+            } else {
+                Some(
+                    spec.services
+                        .iter()
+                        .map(|service| MachineService {
+                            ports: vec![MachinePort {
+                                port: Some(service.port),
+                                ..Default::default()
+                            }],
+                            protocol: "tcp".to_string(),
+                            internal_port: service.port,
+                            concurrency: Some(MachineConcurrency {
+                                soft_limit: service.concurrency,
+                                hard_limit: service.concurrency,
+                            }),
+                        })
+                        .collect(),
+                )
+            },
+            ..Default::default()
+        }
+    }
+}
+
+#[async_trait]
+impl DeployBackend for FlyBackend {
+    async fn ensure(&self) -> Result<()> {
+        let config = self.config.clone();
+        blocking(move || ensure_app(&config)).await?;
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<Machine>> {
+        let app = self.config.app_name.clone();
+        Ok(blocking(move || list_machines(&app))
+            .await?
+            .into_iter()
+            .map(|info| self.machine(info))
+            .collect())
+    }
+
+    async fn create(&self, name: &str, spec: &MachineSpec) -> Result<Machine> {
+        if !spec.memory_mb.unwrap_or_default().is_multiple_of(256) {
+            Err(anyhow!("Memory must be a multiple of 256."))?;
+        }
+        let (app, name) = (self.config.app_name.clone(), name.to_string());
+        let machine_config = Self::machine_config(spec);
+        let info = blocking(move || create_machine(&app, &name, &machine_config)).await?;
+        Ok(self.machine(info))
+    }
+
+    async fn update(&self, machine: &Machine, spec: &MachineSpec) -> Result<Machine> {
+        if !spec.memory_mb.unwrap_or_default().is_multiple_of(256) {
+            Err(anyhow!("Memory must be a multiple of 256."))?;
+        }
+        let (app, id) = (self.config.app_name.clone(), machine.id.clone());
+        let machine_config = Self::machine_config(spec);
+        let info = blocking(move || update_machine(&app, &id, &machine_config)).await?;
+        Ok(self.machine(info))
+    }
+
+    async fn destroy(&self, machine: &Machine) -> Result<()> {
+        let (app, id) = (self.config.app_name.clone(), machine.id.clone());
+        blocking(move || destroy_machine(&app, &id)).await
+    }
+
+    async fn wait(&self, machine: &Machine) -> Result<()> {
+        let (app, id) = (self.config.app_name.clone(), machine.id.clone());
+        blocking(move || wait_for_machine(&app, &id)).await
+    }
+
+    async fn exec(&self, machine: &Machine, command: Vec<&str>) -> Result<Vec<u8>> {
+        let (app, id) = (self.config.app_name.clone(), machine.id.clone());
+        let command = command.into_iter().map(str::to_string).collect();
+        let response = blocking(move || execute_command(&app, &id, command)).await?;
+        if let Some(code) = response.exit_code.filter(|code| *code != 0) {
+            Err(anyhow!(
+                "command exited with {code}: {}",
+                response.stderr.unwrap_or_default()
+            ))?;
+        }
+        Ok(response.stdout.unwrap_or_default().into_bytes())
+    }
+
+    async fn push_image(&self, image: &str) -> Result<String> {
+        let tag = format!("registry.fly.io/{}:{image}", self.config.app_name);
+        super::push_to_registry(
+            image,
+            &tag,
+            Some(DockerCredentials {
+                // https://community.fly.io/t/push-to-fly-io-image-registry-via-docker-api/9132
+                // I have NO idea why the username is x and why the password is the api token,
+                // this took 2 hours to figure out and probably took a couple years off my life as well.
+                username: Some("x".to_string()),
+                password: Some(FLY_API_TOKEN.clone()),
+                ..Default::default()
+            }),
+        )
+        .await?;
+        Ok(tag)
+    }
+}
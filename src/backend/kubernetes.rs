@@ -0,0 +1,894 @@
+//! Runs challenges on a kubernetes cluster.
+//!
+//! Every machine becomes a Deployment with a ClusterIP Service in front of it. Instead of the
+//! caddy ingress, http exposes become rules on a single Ingress and tcp exposes get their own
+//! LoadBalancer (or NodePort) Service. Everything is sent to the API server with server side
+//! apply, so `kubectl proxy` or a mock API server work just as well as a real cluster.
+
+use std::{
+    collections::HashMap,
+    env,
+    ops::RangeInclusive,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bollard::auth::DockerCredentials;
+use lazy_static::lazy_static;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use super::{DeployBackend, Machine, MachineSpec};
+use crate::ingress::{Route, Routes};
+
+lazy_static! {
+    static ref KUBE_API_TOKEN: Option<String> = env::var("KUBE_API_TOKEN").ok();
+    static ref REGISTRY_USERNAME: Option<String> = env::var("REGISTRY_USERNAME").ok();
+    static ref REGISTRY_PASSWORD: Option<String> = env::var("REGISTRY_PASSWORD").ok();
+}
+
+const MANAGED_BY: &str = "app.kubernetes.io/managed-by=bear-cds";
+const MACHINE_LABEL: &str = "bear-cds/machine";
+const NAME_ANNOTATION: &str = "bear-cds/name";
+const EXPOSE_LABEL: &str = "bear-cds/expose";
+/// Ports NodePort services can use on a cluster with the default `--service-node-port-range`
+const NODE_PORTS: RangeInclusive<u32> = 30000..=32767;
+
+#[derive(Deserialize, Clone, JsonSchema)]
+#[schemars(rename = "KubernetesConfig")]
+pub struct Config {
+    /// Url of the API server, e.g. `http://localhost:8001` when using `kubectl proxy`
+    pub api_server: String,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    /// Registry images are pushed to, e.g. `ghcr.io/les-amateurs/ctf`
+    pub registry: String,
+    /// `spec.ingressClassName` of the generated Ingress
+    pub ingress_class: Option<String>,
+    /// Secret holding a wildcard certificate for the hostname
+    pub tls_secret: Option<String>,
+    #[serde(default)]
+    /// Service type used for tcp exposes
+    pub tcp_service_type: ServiceType,
+}
+
+/// Kubernetes service types tcp exposes can get, spelled like in `spec.type`
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, JsonSchema)]
+pub enum ServiceType {
+    #[default]
+    LoadBalancer,
+    /// Only takes ports from 30000 to 32767
+    NodePort,
+}
+
+fn default_namespace() -> String {
+    "bear-cds".to_string()
+}
+
+pub struct KubernetesBackend {
+    config: Config,
+}
+
+fn request(method: &str, url: &str) -> ureq::Request {
+    let request = ureq::request(method, url);
+    match &*KUBE_API_TOKEN {
+        Some(token) => request.set("Authorization", &format!("Bearer {token}")),
+        None => request,
+    }
+}
+
+fn api_error(action: &str, err: ureq::Error) -> anyhow::Error {
+    anyhow!(
+        "{action} failed (kubernetes): {:?}",
+        err.into_response().map(|resp| resp.into_string())
+    )
+}
+
+/// Send a request to the API server, with an optional `(content type, body)`. ureq blocks, so
+/// the request runs on a blocking thread. Objects that don't exist come back as `None`.
+async fn send(
+    action: String,
+    method: &'static str,
+    url: String,
+    body: Option<(&'static str, String)>,
+) -> Result<Option<Value>> {
+    tokio::task::spawn_blocking(move || {
+        let request = request(method, &url);
+        let response = match body {
+            Some((content_type, body)) => {
+                request.set("Content-Type", content_type).send_string(&body)
+            }
+            None => request.call(),
+        };
+        match response {
+            Ok(response) => Ok(Some(response.into_json()?)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(api_error(&action, e)),
+        }
+    })
+    .await?
+}
+
+/// Object names have to be dns labels, and service names also have to start with a letter.
+/// Names longer than a label can be are cut short and end in a hash of the whole name, so two
+/// names that only differ past the cut still get different objects.
+fn object_name(name: &str) -> String {
+    let label = name
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect::<String>();
+    let label = match label.trim_matches('-') {
+        label if label.starts_with(|c: char| c.is_ascii_lowercase()) => label.to_string(),
+        label => format!("c-{label}").trim_end_matches('-').to_string(),
+    };
+    if label.len() <= 63 {
+        return label;
+    }
+    let hash = hex::encode(Sha256::digest(name));
+    format!("{}-{}", label[..54].trim_end_matches('-'), &hash[..8])
+}
+
+impl KubernetesBackend {
+    pub fn new(config: &Config) -> KubernetesBackend {
+        KubernetesBackend {
+            config: config.clone(),
+        }
+    }
+
+    fn url(&self, group: &str, kind: &str) -> String {
+        format!(
+            "{}/{group}/namespaces/{}/{kind}",
+            self.config.api_server, self.config.namespace
+        )
+    }
+
+    async fn get(&self, url: &str) -> Result<Value> {
+        send("GET".to_string(), "GET", url.to_string(), None)
+            .await?
+            .ok_or_else(|| anyhow!("GET failed (kubernetes): {url} not found"))
+    }
+
+    /// Create or update an object with server side apply
+    async fn apply(&self, group: &str, kind: &str, name: &str, object: Value) -> Result<Value> {
+        let url = format!(
+            "{}/{name}?fieldManager=bear-cds&force=true",
+            self.url(group, kind)
+        );
+        let body = ("application/apply-patch+yaml", object.to_string());
+        send(format!("Apply {kind}/{name}"), "PATCH", url, Some(body))
+            .await?
+            .ok_or_else(|| anyhow!("Apply {kind}/{name} failed (kubernetes): not found"))
+    }
+
+    /// Delete an object, deleting something that doesn't exist is not an error
+    async fn delete(&self, group: &str, kind: &str, name: &str) -> Result<()> {
+        let url = format!("{}/{name}", self.url(group, kind));
+        send(format!("Delete {kind}/{name}"), "DELETE", url, None).await?;
+        Ok(())
+    }
+
+    fn machine(&self, deployment: &Value) -> Machine {
+        let metadata = &deployment["metadata"];
+        let id = metadata["name"].as_str().unwrap_or_default().to_string();
+        let available = deployment["status"]["availableReplicas"]
+            .as_u64()
+            .unwrap_or_default();
+        Machine {
+            name: metadata["annotations"][NAME_ANNOTATION]
+                .as_str()
+                .unwrap_or(&id)
+                .to_string(),
+            address: id.clone(),
+            id,
+            state: if available > 0 { "started" } else { "stopped" }.to_string(),
+            image: deployment["spec"]["template"]["spec"]["containers"][0]["image"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            digest: None,
+        }
+    }
+
+    fn metadata(&self, name: &str, id: &str) -> Value {
+        json!({
+            "name": id,
+            "namespace": self.config.namespace,
+            "labels": {
+                "app.kubernetes.io/managed-by": "bear-cds",
+                MACHINE_LABEL: id,
+            },
+            "annotations": {
+                NAME_ANNOTATION: name,
+            },
+        })
+    }
+}
+
+#[async_trait]
+impl DeployBackend for KubernetesBackend {
+    async fn ensure(&self) -> Result<()> {
+        let url = format!(
+            "{}/api/v1/namespaces/{}",
+            self.config.api_server, self.config.namespace
+        );
+        if send("GET".to_string(), "GET", url, None).await?.is_none() {
+            eprintln!("Namespace {} not found. Creating...", self.config.namespace);
+            let namespace = json!({
+                "apiVersion": "v1",
+                "kind": "Namespace",
+                "metadata": { "name": self.config.namespace },
+            });
+            send(
+                "Create namespace".to_string(),
+                "POST",
+                format!("{}/api/v1/namespaces", self.config.api_server),
+                Some(("application/json", namespace.to_string())),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Deployments only know the tag, so the digest comes from the pods. It's left out while
+    /// the pods of a machine run different images, e.g. in the middle of a rollout.
+    async fn list(&self) -> Result<Vec<Machine>> {
+        let deployments = self
+            .get(&format!(
+                "{}?labelSelector={MANAGED_BY}",
+                self.url("apis/apps/v1", "deployments")
+            ))
+            .await?;
+        let pods = self
+            .get(&format!(
+                "{}?labelSelector={MACHINE_LABEL}",
+                self.url("api/v1", "pods")
+            ))
+            .await?;
+        let mut digests = HashMap::<&str, Vec<Option<&str>>>::new();
+        for pod in pods["items"].as_array().into_iter().flatten() {
+            if let Some(id) = pod["metadata"]["labels"][MACHINE_LABEL].as_str() {
+                let digest = pod["status"]["containerStatuses"][0]["imageID"]
+                    .as_str()
+                    .and_then(|image| image.rsplit_once('@'))
+                    .map(|(_, digest)| digest);
+                digests.entry(id).or_default().push(digest);
+            }
+        }
+        Ok(deployments["items"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|deployment| {
+                let mut machine = self.machine(deployment);
+                machine.digest = match digests.get(machine.id.as_str()).map(Vec::as_slice) {
+                    Some([first, rest @ ..]) if rest.iter().all(|d| d == first) => {
+                        first.map(str::to_string)
+                    }
+                    _ => None,
+                };
+                machine
+            })
+            .collect())
+    }
+
+    async fn create(&self, name: &str, spec: &MachineSpec) -> Result<Machine> {
+        let id = object_name(name);
+        let mut limits = serde_json::Map::new();
+        if let Some(cpus) = spec.cpus {
+            limits.insert("cpu".to_string(), json!(cpus.to_string()));
+        }
+        if let Some(mem) = spec.memory_mb {
+            limits.insert("memory".to_string(), json!(format!("{mem}Mi")));
+        }
+        let env = spec
+            .env
+            .iter()
+            .flatten()
+            .map(|(k, v)| json!({ "name": k, "value": v }))
+            .collect::<Vec<Value>>();
+        // tags get reused between deploys, so force a rollout every time
+        let deployed_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        let deployment = self.apply(
+            "apis/apps/v1",
+            "deployments",
+            &id,
+            json!({
+                "apiVersion": "apps/v1",
+                "kind": "Deployment",
+                "metadata": self.metadata(name, &id),
+                "spec": {
+                    "replicas": 1,
+                    "selector": { "matchLabels": { MACHINE_LABEL: id } },
+                    "template": {
+                        "metadata": {
+                            "labels": { MACHINE_LABEL: id },
+                            "annotations": { "bear-cds/deployed-at": deployed_at.to_string() },
+                        },
+                        "spec": {
+                            "automountServiceAccountToken": false,
+                            "containers": [{
+                                "name": "challenge",
+                                "image": spec.image,
+                                "imagePullPolicy": "Always",
+                                "env": env,
+                                "ports": spec.ports.iter().map(|port| json!({ "containerPort": port })).collect::<Vec<Value>>(),
+                                "resources": { "limits": limits },
+                            }],
+                        },
+                    },
+                },
+            }),
+        ).await?;
+
+        if spec.ports.is_empty() {
+            // the machine might have had ports before
+            self.delete("api/v1", "services", &id).await?;
+        } else {
+            self.apply(
+                "api/v1",
+                "services",
+                &id,
+                json!({
+                    "apiVersion": "v1",
+                    "kind": "Service",
+                    "metadata": self.metadata(name, &id),
+                    "spec": {
+                        "selector": { MACHINE_LABEL: id },
+                        "ports": spec.ports.iter().map(|port| json!({
+                            "name": format!("p{port}"),
+                            "port": port,
+                            "targetPort": port,
+                        })).collect::<Vec<Value>>(),
+                    },
+                }),
+            )
+            .await?;
+        }
+
+        Ok(self.machine(&deployment))
+    }
+
+    async fn update(&self, machine: &Machine, spec: &MachineSpec) -> Result<Machine> {
+        self.create(&machine.name, spec).await
+    }
+
+    /// Deletes the Deployment along with its Service and any tcp expose Services pointing at it
+    async fn destroy(&self, machine: &Machine) -> Result<()> {
+        self.delete("apis/apps/v1", "deployments", &machine.id)
+            .await?;
+        self.delete("api/v1", "services", &machine.id).await?;
+        let exposes = self
+            .get(&format!(
+                "{}?labelSelector={EXPOSE_LABEL}=tcp",
+                self.url("api/v1", "services")
+            ))
+            .await?;
+        for service in exposes["items"].as_array().into_iter().flatten() {
+            if service["spec"]["selector"][MACHINE_LABEL] == machine.id.as_str() {
+                if let Some(name) = service["metadata"]["name"].as_str() {
+                    self.delete("api/v1", "services", name).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn wait(&self, machine: &Machine) -> Result<()> {
+        let url = format!("{}/{}", self.url("apis/apps/v1", "deployments"), machine.id);
+        for _ in 0..60 {
+            if self.machine(&self.get(&url).await?).state == "started" {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+        Err(anyhow!("{} did not become available", machine.name))
+    }
+
+    async fn exec(&self, _machine: &Machine, _command: Vec<&str>) -> Result<Vec<u8>> {
+        Err(anyhow!("exec is not supported by the kubernetes backend"))
+    }
+
+    async fn push_image(&self, image: &str) -> Result<String> {
+        let tag = format!("{}:{image}", self.config.registry);
+        let credentials = REGISTRY_USERNAME
+            .as_ref()
+            .map(|username| DockerCredentials {
+                username: Some(username.clone()),
+                password: REGISTRY_PASSWORD.clone(),
+                ..Default::default()
+            });
+        super::push_to_registry(image, &tag, credentials).await?;
+        Ok(tag)
+    }
+
+    /// NodePort services use the tcp port as the node port, so it has to be in the node port range
+    fn check_tcp_port(&self, port: u32) -> Result<()> {
+        if self.config.tcp_service_type == ServiceType::NodePort && !NODE_PORTS.contains(&port) {
+            return Err(anyhow!(
+                "tcp port {port} can't be used with NodePort services, those only take ports {} to {}",
+                NODE_PORTS.start(),
+                NODE_PORTS.end()
+            ));
+        }
+        Ok(())
+    }
+
+    async fn ingress(
+        &self,
+        config: &crate::Config,
+        _existing: Option<&Machine>,
+        routes: &Routes,
+    ) -> Result<()> {
+        let mut tcp_services = Vec::with_capacity(routes.tcp.len());
+        for (port, route) in &routes.tcp {
+            let id = object_name(&format!("{}-tcp-{port}", route.address));
+            let mut service_port = json!({
+                "port": port,
+                "targetPort": route.port,
+            });
+            if self.config.tcp_service_type == ServiceType::NodePort {
+                service_port["nodePort"] = json!(port);
+            }
+            let mut metadata = self.metadata(&route.machine, &id);
+            metadata["labels"][EXPOSE_LABEL] = json!("tcp");
+            self.apply(
+                "api/v1",
+                "services",
+                &id,
+                json!({
+                    "apiVersion": "v1",
+                    "kind": "Service",
+                    "metadata": metadata,
+                    "spec": {
+                        "type": self.config.tcp_service_type,
+                        "selector": { MACHINE_LABEL: route.address },
+                        "ports": [service_port],
+                    },
+                }),
+            )
+            .await?;
+            tcp_services.push(id);
+        }
+
+        // remove tcp services for exposes that no longer exist
+        let existing = self
+            .get(&format!(
+                "{}?labelSelector={EXPOSE_LABEL}=tcp",
+                self.url("api/v1", "services")
+            ))
+            .await?;
+        for service in existing["items"].as_array().into_iter().flatten() {
+            if let Some(name) = service["metadata"]["name"].as_str() {
+                if !tcp_services.iter().any(|s| s == name) {
+                    self.delete("api/v1", "services", name).await?;
+                }
+            }
+        }
+
+        if routes.http.is_empty() {
+            return self
+                .delete("apis/networking.k8s.io/v1", "ingresses", "bear-cds")
+                .await;
+        }
+        let rules = routes
+            .http
+            .iter()
+            .map(|(sub, route)| {
+                json!({
+                    "host": format!("{sub}.{}", config.hostname),
+                    "http": {
+                        "paths": [{
+                            "path": "/",
+                            "pathType": "Prefix",
+                            "backend": {
+                                "service": {
+                                    "name": route.address,
+                                    "port": { "number": route.port },
+                                },
+                            },
+                        }],
+                    },
+                })
+            })
+            .collect::<Vec<Value>>();
+        let mut spec = json!({ "rules": rules });
+        if let Some(class) = &self.config.ingress_class {
+            spec["ingressClassName"] = json!(class);
+        }
+        if let Some(secret) = &self.config.tls_secret {
+            spec["tls"] = json!([{
+                "hosts": [format!("*.{}", config.hostname)],
+                "secretName": secret,
+            }]);
+        }
+        self.apply(
+            "apis/networking.k8s.io/v1",
+            "ingresses",
+            "bear-cds",
+            json!({
+                "apiVersion": "networking.k8s.io/v1",
+                "kind": "Ingress",
+                "metadata": self.metadata("ingress", "bear-cds"),
+                "spec": spec,
+            }),
+        )
+        .await?;
+        println!("Ingress Updated");
+        Ok(())
+    }
+
+    async fn current_routes(
+        &self,
+        config: &crate::Config,
+        _existing: Option<&Machine>,
+        machines: &[Machine],
+    ) -> Result<Routes> {
+        let machine_name = |address: &str| {
+            machines
+                .iter()
+                .find(|m| m.address == address)
+                .map(|m| m.name.clone())
+                .unwrap_or(address.to_string())
+        };
+        let mut routes = Routes::default();
+
+        let url = format!(
+            "{}/bear-cds",
+            self.url("apis/networking.k8s.io/v1", "ingresses")
+        );
+        let ingress = send("GET".to_string(), "GET", url, None)
+            .await?
+            .unwrap_or_default();
+        for rule in ingress["spec"]["rules"].as_array().into_iter().flatten() {
+            let host = rule["host"].as_str().unwrap_or_default();
+            let service = &rule["http"]["paths"][0]["backend"]["service"];
+            if let (Some(address), Some(port)) =
+                (service["name"].as_str(), service["port"]["number"].as_u64())
+            {
+                let sub = host
+                    .strip_suffix(&format!(".{}", config.hostname))
+                    .unwrap_or(host);
+                routes.http.insert(
+                    sub.to_string(),
+                    Route {
+                        machine: machine_name(address),
+                        address: address.to_string(),
+                        port: port as u32,
+                    },
+                );
+            }
+        }
+
+        let services = self
+            .get(&format!(
+                "{}?labelSelector={EXPOSE_LABEL}=tcp",
+                self.url("api/v1", "services")
+            ))
+            .await?;
+        for service in services["items"].as_array().into_iter().flatten() {
+            let port = &service["spec"]["ports"][0];
+            if let (Some(address), Some(public), Some(target)) = (
+                service["spec"]["selector"][MACHINE_LABEL].as_str(),
+                port["port"].as_u64(),
+                port["targetPort"].as_u64(),
+            ) {
+                routes.tcp.insert(
+                    public as u32,
+                    Route {
+                        machine: machine_name(address),
+                        address: address.to_string(),
+                        port: target as u32,
+                    },
+                );
+            }
+        }
+        Ok(routes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Request, Server};
+
+    /// Answers applies with the object that was sent, lists with two deployments and one tcp
+    /// expose, and deletes with 404 for anything called `gone`
+    fn api_server() -> (Server, KubernetesBackend) {
+        let server = Server::start(|req: &Request| {
+            let path = req.path.split('?').next().unwrap();
+            match req.method.as_str() {
+                "PATCH" => (200, req.json()),
+                "DELETE" if path.ends_with("/gone") => (404, json!({ "kind": "Status" })),
+                "DELETE" => (200, json!({ "kind": "Status" })),
+                "GET" if path.ends_with("/deployments") => (
+                    200,
+                    json!({ "items": [
+                        {
+                            "metadata": {
+                                "name": "web-foo-main",
+                                "annotations": { NAME_ANNOTATION: "web-foo-main" },
+                            },
+                            "spec": { "template": { "spec": { "containers": [{ "image": "registry/web-foo-main" }] } } },
+                            "status": { "availableReplicas": 1 },
+                        },
+                        {
+                            "metadata": {
+                                "name": "pwn-bar-main",
+                                "annotations": { NAME_ANNOTATION: "pwn/bar-main" },
+                            },
+                            "spec": { "template": { "spec": { "containers": [{ "image": "registry/pwn-bar-main" }] } } },
+                            "status": {},
+                        },
+                    ]}),
+                ),
+                "GET" if path.ends_with("/pods") => (
+                    200,
+                    json!({ "items": [
+                        {
+                            "metadata": { "labels": { MACHINE_LABEL: "web-foo-main" } },
+                            "status": { "containerStatuses": [{ "imageID": "registry/web-foo-main@sha256:aaa" }] },
+                        },
+                        {
+                            "metadata": { "labels": { MACHINE_LABEL: "pwn-bar-main" } },
+                            "status": { "containerStatuses": [{ "imageID": "registry/pwn-bar-main@sha256:old" }] },
+                        },
+                        {
+                            "metadata": { "labels": { MACHINE_LABEL: "pwn-bar-main" } },
+                            "status": { "containerStatuses": [{ "imageID": "registry/pwn-bar-main@sha256:new" }] },
+                        },
+                    ]}),
+                ),
+                "GET" if path.ends_with("/services") => (
+                    200,
+                    json!({ "items": [{
+                        "metadata": { "name": "pwn-bar-main-tcp-31337" },
+                        "spec": {
+                            "selector": { MACHINE_LABEL: "pwn-bar-main" },
+                            "ports": [{ "port": 31337, "targetPort": 5000 }],
+                        },
+                    }]}),
+                ),
+                _ => (404, json!({})),
+            }
+        });
+        let backend = KubernetesBackend::new(&Config {
+            api_server: server.url.clone(),
+            namespace: "ctf".to_string(),
+            registry: "registry".to_string(),
+            ingress_class: None,
+            tls_secret: None,
+            tcp_service_type: ServiceType::NodePort,
+        });
+        (server, backend)
+    }
+
+    fn machine(id: &str) -> Machine {
+        Machine {
+            id: id.to_string(),
+            name: id.to_string(),
+            state: "started".to_string(),
+            image: String::new(),
+            digest: None,
+            address: id.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn create_applies_deployment_and_service() {
+        let (server, backend) = api_server();
+        let spec = MachineSpec {
+            image: "registry/web-foo-main".to_string(),
+            env: Some(HashMap::from([("A".to_string(), "b".to_string())])),
+            cpus: Some(1),
+            memory_mb: Some(256),
+            ports: vec![8080],
+            services: vec![],
+        };
+        let machine = backend.create("web/foo-main", &spec).await.unwrap();
+        assert_eq!(machine.id, "web-foo-main");
+        assert_eq!(machine.name, "web/foo-main");
+        assert_eq!(machine.image, "registry/web-foo-main");
+
+        let requests = server.requests();
+        assert_eq!(
+            server.changes(),
+            [
+                "PATCH /apis/apps/v1/namespaces/ctf/deployments/web-foo-main?fieldManager=bear-cds&force=true",
+                "PATCH /api/v1/namespaces/ctf/services/web-foo-main?fieldManager=bear-cds&force=true",
+            ]
+        );
+        for request in &requests {
+            assert_eq!(
+                request.header("Content-Type"),
+                Some("application/apply-patch+yaml")
+            );
+        }
+        let deployment = requests[0].json();
+        assert_eq!(deployment["kind"], "Deployment");
+        assert_eq!(deployment["metadata"]["namespace"], "ctf");
+        assert_eq!(
+            deployment["metadata"]["labels"][MACHINE_LABEL],
+            "web-foo-main"
+        );
+        let container = &deployment["spec"]["template"]["spec"]["containers"][0];
+        assert_eq!(container["image"], "registry/web-foo-main");
+        assert_eq!(container["env"], json!([{ "name": "A", "value": "b" }]));
+        assert_eq!(container["ports"], json!([{ "containerPort": 8080 }]));
+        assert_eq!(
+            container["resources"]["limits"],
+            json!({ "cpu": "1", "memory": "256Mi" })
+        );
+        let service = requests[1].json();
+        assert_eq!(service["spec"]["selector"][MACHINE_LABEL], "web-foo-main");
+        assert_eq!(
+            service["spec"]["ports"],
+            json!([{ "name": "p8080", "port": 8080, "targetPort": 8080 }])
+        );
+    }
+
+    #[test]
+    fn long_names_are_cut_without_a_trailing_dash() {
+        // the cut falls right after the dash following the a's
+        let name = format!("web/{}-{}", "a".repeat(49), "b".repeat(20));
+        let object = object_name(&name);
+        assert!(object.len() <= 63);
+        assert!(object.starts_with(&format!("web-{}-", "a".repeat(49))));
+        assert!(!object.contains("--"));
+    }
+
+    #[test]
+    fn long_names_stay_unique() {
+        let long = format!("web/{}", "a".repeat(70));
+        let (one, two) = (
+            object_name(&format!("{long}-one")),
+            object_name(&format!("{long}-two")),
+        );
+        assert!(one.len() <= 63 && two.len() <= 63);
+        assert_ne!(one, two);
+        assert_eq!(object_name("web/short-main"), "web-short-main");
+    }
+
+    #[tokio::test]
+    async fn names_starting_with_a_digit_get_a_letter() {
+        let (server, backend) = api_server();
+        let spec = MachineSpec {
+            image: "registry/1337-chall-main".to_string(),
+            ports: vec![1337],
+            ..Default::default()
+        };
+        let machine = backend.create("1337/chall-main", &spec).await.unwrap();
+        assert_eq!(machine.id, "c-1337-chall-main");
+        assert_eq!(
+            server.changes(),
+            [
+                "PATCH /apis/apps/v1/namespaces/ctf/deployments/c-1337-chall-main?fieldManager=bear-cds&force=true",
+                "PATCH /api/v1/namespaces/ctf/services/c-1337-chall-main?fieldManager=bear-cds&force=true",
+            ]
+        );
+        assert_eq!(object_name("--"), "c");
+    }
+
+    #[tokio::test]
+    async fn create_without_ports_deletes_service() {
+        let (server, backend) = api_server();
+        let spec = MachineSpec {
+            image: "registry/gone".to_string(),
+            ..Default::default()
+        };
+        backend.create("gone", &spec).await.unwrap();
+        assert_eq!(
+            server.changes(),
+            [
+                "PATCH /apis/apps/v1/namespaces/ctf/deployments/gone?fieldManager=bear-cds&force=true",
+                "DELETE /api/v1/namespaces/ctf/services/gone",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn list_reads_managed_deployments() {
+        let (server, backend) = api_server();
+        let machines = backend.list().await.unwrap();
+        assert_eq!(
+            server.requests()[0].path,
+            "/apis/apps/v1/namespaces/ctf/deployments?labelSelector=app.kubernetes.io/managed-by=bear-cds"
+        );
+        let summary = machines
+            .iter()
+            .map(|m| {
+                (
+                    m.id.as_str(),
+                    m.name.as_str(),
+                    m.state.as_str(),
+                    m.image.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "web-foo-main",
+                    "web-foo-main",
+                    "started",
+                    "registry/web-foo-main"
+                ),
+                (
+                    "pwn-bar-main",
+                    "pwn/bar-main",
+                    "stopped",
+                    "registry/pwn-bar-main"
+                ),
+            ]
+        );
+        assert_eq!(machines[1].address, "pwn-bar-main");
+        assert_eq!(machines[0].digest.as_deref(), Some("sha256:aaa"));
+        // still rolling out, so there's no one digest
+        assert_eq!(machines[1].digest, None);
+    }
+
+    #[tokio::test]
+    async fn destroy_deletes_everything_for_the_machine() {
+        let (server, backend) = api_server();
+        backend.destroy(&machine("pwn-bar-main")).await.unwrap();
+        assert_eq!(
+            server.changes(),
+            [
+                "DELETE /apis/apps/v1/namespaces/ctf/deployments/pwn-bar-main",
+                "DELETE /api/v1/namespaces/ctf/services/pwn-bar-main",
+                "DELETE /api/v1/namespaces/ctf/services/pwn-bar-main-tcp-31337",
+            ]
+        );
+
+        // already gone is fine, and other machines' exposes are left alone
+        server.clear();
+        backend.destroy(&machine("gone")).await.unwrap();
+        assert_eq!(
+            server.changes(),
+            [
+                "DELETE /apis/apps/v1/namespaces/ctf/deployments/gone",
+                "DELETE /api/v1/namespaces/ctf/services/gone",
+            ]
+        );
+    }
+
+    #[test]
+    fn node_ports_are_checked() {
+        let (_server, backend) = api_server();
+        assert!(backend.check_tcp_port(31337).is_ok());
+        assert!(backend.check_tcp_port(1337).is_err());
+        let backend = KubernetesBackend::new(&Config {
+            tcp_service_type: ServiceType::LoadBalancer,
+            ..backend.config
+        });
+        assert!(backend.check_tcp_port(1337).is_ok());
+    }
+
+    #[test]
+    fn service_types_are_checked_when_parsing() {
+        let parse = |extra: &str| {
+            toml::from_str::<Config>(&format!(
+                "api_server = \"http://localhost:8001\"\nregistry = \"registry\"\n{extra}"
+            ))
+        };
+        assert_eq!(
+            parse("").unwrap().tcp_service_type,
+            ServiceType::LoadBalancer
+        );
+        assert_eq!(
+            parse("tcp_service_type = \"NodePort\"")
+                .unwrap()
+                .tcp_service_type,
+            ServiceType::NodePort
+        );
+        let Err(e) = parse("tcp_service_type = \"Nodeport\"") else {
+            panic!("Nodeport isn't a service type");
+        };
+        assert!(e.message().contains("unknown variant `Nodeport`"));
+    }
+}
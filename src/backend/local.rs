@@ -0,0 +1,292 @@
+//! Runs every challenge as a container on the local docker daemon.
+//!
+//! All containers are attached to a shared bridge network so the ingress can reach them by
+//! container name, and only the ingress publishes ports on the host.
+
+use std::{collections::HashMap, time::Duration};
+
+use crate::{ingress, DOCKER};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bollard::{
+    exec::{CreateExecOptions, StartExecResults},
+    models::{
+        ContainerCreateBody, ContainerStateStatusEnum, HostConfig, NetworkCreateRequest,
+        PortBinding,
+    },
+    query_parameters::{
+        CreateContainerOptionsBuilder, InspectContainerOptions, InspectNetworkOptions,
+        ListContainersOptionsBuilder, RemoveContainerOptionsBuilder, StartContainerOptions,
+    },
+};
+use futures::StreamExt;
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{DeployBackend, Machine, MachineSpec};
+
+/// Label used to find containers managed by bear-cds, the value is the machine name.
+const NAME_LABEL: &str = "bear-cds.name";
+/// Label holding the [`MachineSpec::fingerprint`] the container was created with
+const FINGERPRINT_LABEL: &str = "bear-cds.fingerprint";
+
+#[derive(Deserialize, Clone, JsonSchema)]
+#[schemars(rename = "LocalConfig")]
+pub struct Config {
+    #[serde(default = "default_network")]
+    /// Name of the bridge network, also used to prefix container names
+    pub network: String,
+    #[serde(default = "default_bind")]
+    /// Host address published ports are bound to
+    pub bind: String,
+}
+
+fn default_network() -> String {
+    "bear-cds".to_string()
+}
+
+fn default_bind() -> String {
+    "0.0.0.0".to_string()
+}
+
+pub struct LocalBackend {
+    config: Config,
+}
+
+impl LocalBackend {
+    pub fn new(config: &Config) -> LocalBackend {
+        LocalBackend {
+            config: config.clone(),
+        }
+    }
+
+    fn container_name(&self, name: &str) -> String {
+        format!("{}-{name}", self.config.network)
+    }
+
+    async fn inspect(&self, id: &str) -> Result<Machine> {
+        let container = DOCKER
+            .inspect_container(id, None::<InspectContainerOptions>)
+            .await?;
+        let config = container.config.unwrap_or_default();
+        let name = config
+            .labels
+            .unwrap_or_default()
+            .remove(NAME_LABEL)
+            .ok_or(anyhow!("container {id} is not managed by bear-cds"))?;
+        Ok(Machine {
+            id: container.id.unwrap_or_default(),
+            address: self.container_name(&name),
+            name,
+            // "started" like fly calls it, docker's own status otherwise
+            state: match container.state.and_then(|state| state.status) {
+                Some(ContainerStateStatusEnum::RUNNING) => "started".to_string(),
+                Some(status) => status.to_string(),
+                None => String::new(),
+            },
+            image: config.image.unwrap_or_default(),
+            // local images are never pushed, so the image id is their digest
+            digest: container.image,
+        })
+    }
+
+    /// Whether the container is running, was created from the same config as `spec` and still
+    /// runs the image `spec.image` points at
+    async fn unchanged(&self, machine: &Machine, spec: &MachineSpec) -> Result<bool> {
+        let container = DOCKER
+            .inspect_container(&machine.id, None::<InspectContainerOptions>)
+            .await?;
+        let running = container.state.and_then(|state| state.running) == Some(true);
+        let fingerprint = container
+            .config
+            .and_then(|config| config.labels)
+            .and_then(|mut labels| labels.remove(FINGERPRINT_LABEL));
+        let image = DOCKER
+            .inspect_image(&spec.image)
+            .await
+            .ok()
+            .and_then(|i| i.id);
+        Ok(running
+            && fingerprint == Some(spec.fingerprint())
+            && image.is_some()
+            && container.image == image)
+    }
+}
+
+#[async_trait]
+impl DeployBackend for LocalBackend {
+    async fn ensure(&self) -> Result<()> {
+        if DOCKER
+            .inspect_network(&self.config.network, None::<InspectNetworkOptions>)
+            .await
+            .is_err()
+        {
+            eprintln!("Network {} not found. Creating...", self.config.network);
+            DOCKER
+                .create_network(NetworkCreateRequest {
+                    name: self.config.network.clone(),
+                    driver: Some("bridge".to_string()),
+                    ..Default::default()
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<Machine>> {
+        let containers = DOCKER
+            .list_containers(Some(
+                ListContainersOptionsBuilder::new()
+                    .all(true)
+                    .filters(&HashMap::from([("label", vec![NAME_LABEL])]))
+                    .build(),
+            ))
+            .await?;
+        let mut machines = Vec::with_capacity(containers.len());
+        for container in containers {
+            if let Some(id) = container.id {
+                machines.push(self.inspect(&id).await?);
+            }
+        }
+        Ok(machines)
+    }
+
+    async fn create(&self, name: &str, spec: &MachineSpec) -> Result<Machine> {
+        let container_name = self.container_name(name);
+        let port_bindings = spec
+            .services
+            .iter()
+            .map(|service| {
+                (
+                    format!("{}/tcp", service.port),
+                    Some(vec![PortBinding {
+                        host_ip: Some(self.config.bind.clone()),
+                        host_port: Some(service.port.to_string()),
+                    }]),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        let exposed_ports = port_bindings
+            .keys()
+            .map(|port| (port.clone(), HashMap::new()))
+            .collect::<HashMap<_, _>>();
+        let env = spec.env.as_ref().map(|env| {
+            env.iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect::<Vec<String>>()
+        });
+
+        let container = DOCKER
+            .create_container(
+                Some(
+                    CreateContainerOptionsBuilder::new()
+                        .name(&container_name)
+                        .build(),
+                ),
+                ContainerCreateBody {
+                    image: Some(spec.image.clone()),
+                    env,
+                    labels: Some(HashMap::from([
+                        (NAME_LABEL.to_string(), name.to_string()),
+                        (FINGERPRINT_LABEL.to_string(), spec.fingerprint()),
+                    ])),
+                    exposed_ports: Some(exposed_ports),
+                    host_config: Some(HostConfig {
+                        network_mode: Some(self.config.network.clone()),
+                        port_bindings: Some(port_bindings),
+                        nano_cpus: spec.cpus.map(|cpus| cpus as i64 * 1_000_000_000),
+                        memory: spec.memory_mb.map(|mem| mem as i64 * 1024 * 1024),
+                        restart_policy: Some(bollard::models::RestartPolicy {
+                            name: Some(bollard::models::RestartPolicyNameEnum::UNLESS_STOPPED),
+                            maximum_retry_count: None,
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|e| anyhow!("Create container {container_name} failed: {e}"))?;
+        DOCKER
+            .start_container(&container.id, None::<StartContainerOptions>)
+            .await?;
+        self.inspect(&container.id).await
+    }
+
+    async fn update(&self, machine: &Machine, spec: &MachineSpec) -> Result<Machine> {
+        // docker can't change ports or images of an existing container, so it's replaced unless
+        // nothing changed
+        if self.unchanged(machine, spec).await? {
+            return Ok(machine.clone());
+        }
+        self.destroy(machine).await?;
+        self.create(&machine.name, spec).await
+    }
+
+    async fn destroy(&self, machine: &Machine) -> Result<()> {
+        DOCKER
+            .remove_container(
+                &machine.id,
+                Some(RemoveContainerOptionsBuilder::new().force(true).build()),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Waits for the container to run, and for the ingress, until caddy's admin api answers
+    async fn wait(&self, machine: &Machine) -> Result<()> {
+        for _ in 0..30 {
+            let state = DOCKER
+                .inspect_container(&machine.id, None::<InspectContainerOptions>)
+                .await?
+                .state;
+            if let Some(true) = state.and_then(|state| state.running) {
+                if machine.name != ingress::NAME
+                    || self
+                        .exec(machine, vec!["curl", "-sf", "localhost:2019/config/"])
+                        .await
+                        .is_ok()
+                {
+                    return Ok(());
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        Err(anyhow!("{} did not start", machine.name))
+    }
+
+    async fn exec(&self, machine: &Machine, command: Vec<&str>) -> Result<Vec<u8>> {
+        let exec = DOCKER
+            .create_exec(
+                &machine.id,
+                CreateExecOptions {
+                    cmd: Some(command),
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        let mut buf = Vec::new();
+        if let StartExecResults::Attached { mut output, .. } =
+            DOCKER.start_exec(&exec.id, None).await?
+        {
+            while let Some(chunk) = output.next().await {
+                buf.extend_from_slice(&chunk?.into_bytes());
+            }
+        }
+        let exit_code = DOCKER.inspect_exec(&exec.id).await?.exit_code;
+        if let Some(code) = exit_code.filter(|code| *code != 0) {
+            Err(anyhow!(
+                "command exited with {code}: {}",
+                String::from_utf8_lossy(&buf)
+            ))?;
+        }
+        Ok(buf)
+    }
+
+    async fn push_image(&self, image: &str) -> Result<String> {
+        // images are built on the same daemon the containers run on
+        Ok(image.to_string())
+    }
+}
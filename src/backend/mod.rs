@@ -0,0 +1,165 @@
+//! Deployment backends.
+//!
+//! A backend knows how to run a challenge container somewhere. Everything that talks to the
+//! actual infrastructure goes through the [`DeployBackend`] trait so the rest of the tool does
+//! not care whether machines live on fly.io or somewhere else.
+
+use crate::{
+    ingress::{self, Routes},
+    DOCKER,
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bollard::{
+    auth::DockerCredentials,
+    query_parameters::{PushImageOptions, TagImageOptionsBuilder},
+};
+use futures::StreamExt;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+
+pub mod fly;
+pub mod kubernetes;
+pub mod local;
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "lowercase")]
+/// The `[backend]` section of bear.toml, `type` selects the implementation.
+#[schemars(rename = "Backend")]
+pub enum Config {
+    Fly(fly::Config),
+    Local(local::Config),
+    Kubernetes(kubernetes::Config),
+}
+
+/// A running (or stopped) challenge container, as reported by the backend.
+#[derive(Debug, Clone)]
+pub struct Machine {
+    pub id: String,
+    pub name: String,
+    pub state: String,
+    /// Image reference the machine is currently running
+    pub image: String,
+    /// Digest of the image the machine is actually running, when the backend can tell. Comparable
+    /// with [`crate::state::image_digest`].
+    pub digest: Option<String>,
+    /// Address other machines (i.e. the ingress) can reach this machine at
+    pub address: String,
+}
+
+/// What a machine should look like once it is created or updated.
+#[derive(Debug, Clone, Default)]
+pub struct MachineSpec {
+    pub image: String,
+    pub env: Option<HashMap<String, String>>,
+    pub cpus: Option<u32>,
+    pub memory_mb: Option<u32>,
+    /// Ports the machine listens on
+    pub ports: Vec<u32>,
+    /// Ports that should be reachable from the internet
+    pub services: Vec<Service>,
+}
+
+impl MachineSpec {
+    /// Hash of everything but the image, to tell whether a machine's config changed.
+    pub fn fingerprint(&self) -> String {
+        let mut ports = self.ports.clone();
+        ports.sort();
+        let mut services = self
+            .services
+            .iter()
+            .map(|s| (s.port, s.concurrency))
+            .collect::<Vec<_>>();
+        services.sort();
+        let config = json!({
+            "env": self.env.as_ref().map(|env| env.iter().collect::<BTreeMap<_, _>>()),
+            "cpus": self.cpus,
+            "memory_mb": self.memory_mb,
+            "ports": ports,
+            "services": services,
+        });
+        hex::encode(Sha256::digest(config.to_string()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Service {
+    pub port: u32,
+    pub concurrency: u32,
+}
+
+#[async_trait]
+pub trait DeployBackend: Send + Sync {
+    /// Make sure whatever the machines live in (app, network, namespace, ...) exists.
+    async fn ensure(&self) -> Result<()>;
+    async fn list(&self) -> Result<Vec<Machine>>;
+    async fn create(&self, name: &str, spec: &MachineSpec) -> Result<Machine>;
+    async fn update(&self, machine: &Machine, spec: &MachineSpec) -> Result<Machine>;
+    async fn destroy(&self, machine: &Machine) -> Result<()>;
+    /// Block until the machine is started.
+    async fn wait(&self, machine: &Machine) -> Result<()>;
+    /// Run a command inside the machine and return its output. Fails if the command exits with
+    /// anything but 0.
+    async fn exec(&self, machine: &Machine, command: Vec<&str>) -> Result<Vec<u8>>;
+    /// Make a locally built image available to the backend, returns the image reference
+    /// machines should be created with.
+    async fn push_image(&self, image: &str) -> Result<String>;
+    /// Whether a tcp expose on `port` can be served, checked before anything is deployed.
+    fn check_tcp_port(&self, _port: u32) -> Result<()> {
+        Ok(())
+    }
+    /// Route traffic from the internet to the challenges. By default this runs the caddy
+    /// ingress as a machine on the backend.
+    async fn ingress(
+        &self,
+        config: &crate::Config,
+        existing: Option<&Machine>,
+        routes: &Routes,
+    ) -> Result<()> {
+        ingress::update(config, self, existing, routes).await?;
+        Ok(())
+    }
+    /// Routes that are currently live, `machines` is everything returned by [`Self::list`].
+    async fn current_routes(
+        &self,
+        config: &crate::Config,
+        existing: Option<&Machine>,
+        machines: &[Machine],
+    ) -> Result<Routes> {
+        match existing {
+            Some(ingress) => ingress::current_routes(config, self, ingress, machines).await,
+            None => Ok(Routes::default()),
+        }
+    }
+}
+
+pub fn from_config(config: &Config) -> Box<dyn DeployBackend> {
+    match config {
+        Config::Fly(fly) => Box::new(fly::FlyBackend::new(fly)),
+        Config::Local(local) => Box::new(local::LocalBackend::new(local)),
+        Config::Kubernetes(kubernetes) => Box::new(kubernetes::KubernetesBackend::new(kubernetes)),
+    }
+}
+
+/// Tag a local image as `tag` and push it with the given credentials.
+pub async fn push_to_registry(
+    image: &str,
+    tag: &str,
+    credentials: Option<DockerCredentials>,
+) -> Result<()> {
+    DOCKER
+        .tag_image(image, Some(TagImageOptionsBuilder::new().repo(tag).build()))
+        .await?;
+    println!("pushing image: {tag}");
+    let mut push = DOCKER.push_image(tag, None::<PushImageOptions>, credentials);
+    while let Some(push_step) = push.next().await {
+        let push_step = push_step.map_err(|e| anyhow!("failed to push {tag}: {e:?}"))?;
+        if let Some(error) = push_step.error {
+            Err(anyhow!("failed to push {tag}: {error}"))?;
+        }
+    }
+    Ok(())
+}
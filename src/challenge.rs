@@ -1,245 +1,1027 @@
-use crate::DOCKER;
+use crate::{context::BuildContext, Config};
 use anyhow::{anyhow, Result};
-use bollard::auth::DockerCredentials;
-use bollard::image::BuildImageOptions;
+use ignore::gitignore::GitignoreBuilder;
+use schemars::JsonSchema;
+use serde::{
+    de::{self, Deserializer, MapAccess, Visitor},
+    Deserialize, Serialize,
+};
+use sha2::{Digest, Sha256};
 
-use futures::stream::StreamExt;
-use serde::Deserialize;
-use std::default::Default;
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
 
-use std::fs::File;
-use std::io::Read;
-use std::{collections::HashMap, fs, io, path::PathBuf};
-use temp_dir::TempDir;
-use toml;
-
-use crate::fly;
-
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
 pub struct Challenge {
+    /// Defaults to the path of the challenge relative to the challenge root, e.g. `pwn/heap/part1`
+    #[serde(default)]
     pub id: String,
+    /// Category shown on the scoreboard. Defaults to the name in category.toml, or the first
+    /// directory in the path of the challenge
+    #[serde(default)]
+    pub category: String,
+    /// Directory challenge.toml was read from
+    #[serde(skip)]
+    pub dir: PathBuf,
     pub name: String,
+    /// Defaults to the author in category.toml
+    #[serde(default)]
     pub author: String,
+    /// `{{name.url}}` is replaced with how to connect to `expose.name`
     pub description: String,
     pub flag: Flag,
+    /// Deploy the challenge but don't put it on the scoreboard
     pub hidden: Option<bool>,
+    /// Files handed out with the challenge
     pub provide: Option<Vec<Attachment>>,
-    #[serde(default = "empty")]
+    #[serde(default)]
     pub containers: HashMap<String, Container>,
-    #[serde(default = "empty")]
+    /// Keyed by container name
+    #[serde(default)]
     pub expose: HashMap<String, Expose>,
+    /// Defaults to the points in category.toml, then bear.toml, then 100 to 500
+    pub points: Option<Points>,
+    /// Whether solving the challenge counts for breaking ties. Defaults like `points`, then true
+    pub tiebreak: Option<bool>,
+    /// Only shown on CTFd, rCTF doesn't have hints
+    #[serde(default)]
+    pub hints: Vec<Hint>,
+    /// Only shown on CTFd
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct Hint {
+    pub content: String,
+    /// Points it costs to unlock the hint
+    #[serde(default)]
+    pub cost: u32,
+}
+
+/// A provided file, ready to be uploaded to a scoreboard
+pub struct ProvidedFile {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+impl ProvidedFile {
+    /// Hex sha256 of the contents, folders are archived reproducibly so this only changes when
+    /// what's in them does
+    pub fn sha256(&self) -> String {
+        hex::encode(Sha256::digest(&self.data))
+    }
+}
+
+/// `category.toml` in a category folder, defaults for every challenge in it.
+#[derive(Deserialize, Debug, Clone, Default, JsonSchema)]
+pub struct Category {
+    /// Name shown on the scoreboard, e.g. `Web Exploitation` for `web`
+    pub name: Option<String>,
+    pub author: Option<String>,
+    pub points: Option<Points>,
+    pub tiebreak: Option<bool>,
+    /// Limits for containers that don't set their own
+    #[serde(default)]
+    pub limits: Limits,
+}
+
+#[derive(Debug, Clone, JsonSchema)]
+#[serde(untagged)]
+pub enum Points {
+    /// Always worth this many points, e.g. for sanity checks
+    Fixed(u32),
+    /// Worth `max` points, decaying towards `min` as more teams solve it
+    Range { min: u32, max: u32 },
+}
+
+impl Default for Points {
+    fn default() -> Points {
+        Points::Range { min: 100, max: 500 }
+    }
 }
 
-fn empty<K, V>() -> HashMap<K, V> {
-    HashMap::new()
+impl Points {
+    pub fn min(&self) -> u32 {
+        match self {
+            Points::Fixed(points) => *points,
+            Points::Range { min, .. } => *min,
+        }
+    }
+
+    pub fn max(&self) -> u32 {
+        match self {
+            Points::Fixed(points) => *points,
+            Points::Range { max, .. } => *max,
+        }
+    }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+// Flag, Attachment and Expose deserialize by hand below, so a typo gets a better error than
+// "data did not match any variant of untagged enum". The schemars attributes keep the schema as
+// strict as those: no unknown keys, and optional keys can be left out but not set to null.
+#[derive(Debug, Clone, JsonSchema)]
 #[serde(untagged)]
+#[schemars(deny_unknown_fields)]
 pub enum Flag {
+    /// The flag itself
     Raw(String),
+    /// Read the flag from a file in the challenge directory
     File { file: PathBuf },
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Debug, Clone, JsonSchema)]
 #[serde(untagged)]
+#[schemars(deny_unknown_fields)]
 pub enum Attachment {
+    /// A file, uploaded under its own name
     File(PathBuf),
-    Named {
-        file: PathBuf,
-        r#as: String,
-    },
+    /// A file, uploaded as `as`
+    Named { file: PathBuf, r#as: String },
+    /// A directory, uploaded as a `.tar.gz`
     Folder {
         dir: PathBuf,
+        #[schemars(default, with = "String", skip_serializing_if = "Option::is_none")]
         r#as: Option<String>,
+        #[schemars(
+            default,
+            with = "Vec<PathBuf>",
+            skip_serializing_if = "Option::is_none"
+        )]
         exclude: Option<Vec<PathBuf>>,
     },
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
 pub struct Container {
-    pub build: PathBuf,
+    #[serde(flatten)]
+    pub image: BuildConfig,
+    #[serde(default)]
     pub limits: Limits,
-    ports: Option<Vec<u32>>,
+    /// Ports other containers of the challenge can reach this one on
+    pub ports: Option<Vec<u32>>,
     pub env: Option<HashMap<String, String>>,
 }
 
+/// How to build an image, shared by challenge containers and the base images in bear.toml.
+#[derive(Deserialize, Debug, Clone, JsonSchema)]
+pub struct BuildConfig {
+    /// Directory the image is built from
+    pub build: PathBuf,
+    /// Dockerfile to use, relative to `build`. Defaults to `Dockerfile`
+    pub dockerfile: Option<PathBuf>,
+    /// Build context, defaults to `build`
+    pub context: Option<PathBuf>,
+    #[serde(default)]
+    pub args: BTreeMap<String, String>,
+    /// Stage of a multi-stage dockerfile to build
+    pub target: Option<String>,
+    /// e.g. `linux/amd64`
+    pub platform: Option<String>,
+}
+
 // im honestly uncertain what types these should be so im using these
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, Default, JsonSchema)]
 pub struct Limits {
     pub cpu: Option<u32>,
+    /// In MB
     pub mem: Option<u32>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Debug, Clone, JsonSchema)]
 #[serde(untagged)]
+#[schemars(deny_unknown_fields)]
 pub enum Expose {
+    /// Forward a public tcp port to `target`
     Tcp { target: u32, tcp: u32 },
+    /// Serve `target` on the `http` subdomain
     Http { target: u32, http: String },
 }
 
+struct PointsVisitor;
+
+impl<'de> Visitor<'de> for PointsVisitor {
+    type Value = Points;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a number of points, or a table with `min` and `max`")
+    }
+
+    fn visit_i64<E: de::Error>(self, points: i64) -> Result<Points, E> {
+        u32::try_from(points)
+            .map(Points::Fixed)
+            .map_err(|_| E::custom(format!("points can't be {points}")))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Points, A::Error> {
+        let (mut min, mut max) = (None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "min" => min = Some(map.next_value()?),
+                "max" => max = Some(map.next_value()?),
+                _ => return Err(de::Error::unknown_field(&key, &["min", "max"])),
+            }
+        }
+        let min = min.ok_or_else(|| de::Error::missing_field("min"))?;
+        let max = max.ok_or_else(|| de::Error::missing_field("max"))?;
+        if min > max {
+            return Err(de::Error::custom(format!(
+                "min ({min}) is more than max ({max})"
+            )));
+        }
+        Ok(Points::Range { min, max })
+    }
+}
+
+impl<'de> Deserialize<'de> for Points {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Points, D::Error> {
+        deserializer.deserialize_any(PointsVisitor)
+    }
+}
+
+struct FlagVisitor;
+
+impl<'de> Visitor<'de> for FlagVisitor {
+    type Value = Flag;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the flag as a string, or a table with `file`")
+    }
+
+    fn visit_str<E: de::Error>(self, flag: &str) -> Result<Flag, E> {
+        Ok(Flag::Raw(flag.to_string()))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Flag, A::Error> {
+        let mut file = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "file" => file = Some(map.next_value()?),
+                _ => return Err(de::Error::unknown_field(&key, &["file"])),
+            }
+        }
+        Ok(Flag::File {
+            file: file.ok_or_else(|| de::Error::missing_field("file"))?,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Flag {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Flag, D::Error> {
+        deserializer.deserialize_any(FlagVisitor)
+    }
+}
+
+struct AttachmentVisitor;
+
+impl<'de> Visitor<'de> for AttachmentVisitor {
+    type Value = Attachment;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a path, or a table with `file` or `dir`")
+    }
+
+    fn visit_str<E: de::Error>(self, path: &str) -> Result<Attachment, E> {
+        Ok(Attachment::File(PathBuf::from(path)))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Attachment, A::Error> {
+        const FIELDS: &[&str] = &["file", "dir", "as", "exclude"];
+        let (mut file, mut dir, mut r#as, mut exclude) = (None, None, None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "file" => file = Some(map.next_value()?),
+                "dir" => dir = Some(map.next_value()?),
+                "as" => r#as = Some(map.next_value()?),
+                "exclude" => exclude = Some(map.next_value()?),
+                _ => return Err(de::Error::unknown_field(&key, FIELDS)),
+            }
+        }
+        match (file, dir) {
+            (Some(_), Some(_)) => Err(de::Error::custom(
+                "an attachment has either `file` or `dir`, not both",
+            )),
+            (Some(_), None) if exclude.is_some() => Err(de::Error::custom(
+                "`exclude` only works for `dir` attachments",
+            )),
+            (Some(file), None) => Ok(Attachment::Named {
+                file,
+                r#as: r#as.ok_or_else(|| de::Error::missing_field("as"))?,
+            }),
+            (None, Some(dir)) => Ok(Attachment::Folder { dir, r#as, exclude }),
+            (None, None) => Err(de::Error::custom(
+                "an attachment needs either `file` or `dir`",
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Attachment {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Attachment, D::Error> {
+        deserializer.deserialize_any(AttachmentVisitor)
+    }
+}
+
+struct ExposeVisitor;
+
+impl<'de> Visitor<'de> for ExposeVisitor {
+    type Value = Expose;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a table with `target` and either `tcp` or `http`")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Expose, A::Error> {
+        const FIELDS: &[&str] = &["target", "tcp", "http"];
+        let (mut target, mut tcp, mut http) = (None, None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "target" => target = Some(map.next_value()?),
+                "tcp" => tcp = Some(map.next_value()?),
+                "http" => http = Some(map.next_value()?),
+                _ => return Err(de::Error::unknown_field(&key, FIELDS)),
+            }
+        }
+        let target = target.ok_or_else(|| de::Error::missing_field("target"))?;
+        match (tcp, http) {
+            (Some(tcp), None) => Ok(Expose::Tcp { target, tcp }),
+            (None, Some(http)) => Ok(Expose::Http { target, http }),
+            (Some(_), Some(_)) => Err(de::Error::custom(
+                "an expose has either `tcp` or `http`, not both",
+            )),
+            (None, None) => Err(de::Error::custom(
+                "an expose needs either `tcp = <port>` or `http = \"<subdomain>\"`",
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Expose {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Expose, D::Error> {
+        deserializer.deserialize_map(ExposeVisitor)
+    }
+}
+
+impl BuildConfig {
+    /// What to build, with `build` and `context` relative to `dir`.
+    pub fn build_context(&self, dir: &Path) -> BuildContext {
+        let build = dir.join(&self.build);
+        BuildContext {
+            dir: dir.join(self.context.as_ref().unwrap_or(&self.build)),
+            dockerfile: build.join(
+                self.dockerfile
+                    .as_deref()
+                    .unwrap_or(Path::new("Dockerfile")),
+            ),
+            args: self.args.clone(),
+            target: self.target.clone(),
+            platform: self.platform.clone(),
+            bases: BTreeMap::new(),
+        }
+    }
+}
+
 impl Challenge {
-    pub fn parse(chall_dir: PathBuf) -> Result<Challenge> {
-        let file_data = fs::read_to_string(&chall_dir.join("challenge.toml")).map_err(|_| anyhow!("Failed to read challenge.toml, Make sure it exists at the root of your challenge directory"))?;
-        let mut toml: toml::Table = toml::from_str(&file_data)?;
-        let mut id_parts = chall_dir
+    /// Path of `chall_dir` relative to `root`, e.g. `pwn/heap/part1`
+    pub fn id_from_path(root: &Path, chall_dir: &Path) -> Result<String> {
+        let id_parts = chall_dir
+            .strip_prefix(root)?
             .iter()
-            .rev()
-            .take(2)
             .map(|c| c.to_str())
             .collect::<Option<Vec<&str>>>()
             .ok_or(anyhow!("Failed to convert OsStr to Str"))?;
-        id_parts.reverse();
-        let id = id_parts.join("/");
-        toml.insert(String::from("id"), toml::Value::String(id.clone()));
+        if id_parts.is_empty() {
+            return Err(anyhow!("the challenge root can't be a challenge itself"));
+        }
+        Ok(id_parts.join("/"))
+    }
 
-        Ok(toml
-            .try_into()
-            .map_err(|e| anyhow!("failed to parse parsing {id}: {e}"))?)
+    /// Parse the contents of a challenge.toml. `id` is the id derived from the path, used unless
+    /// challenge.toml sets its own, and anything left out is filled in from `defaults`. Errors
+    /// keep the location in the file.
+    pub fn from_toml(
+        id: String,
+        dir: PathBuf,
+        defaults: &Category,
+        data: &str,
+    ) -> Result<Challenge, toml::de::Error> {
+        let mut chall: Challenge = toml::from_str(data)?;
+        if chall.author.is_empty() {
+            chall.author = defaults.author.clone().ok_or_else(|| {
+                de::Error::custom("missing field `author`, set it here or in category.toml")
+            })?;
+        }
+        if chall.category.is_empty() {
+            chall.category = defaults
+                .name
+                .clone()
+                .unwrap_or(category_folder(&id).to_string());
+        }
+        if chall.id.is_empty() {
+            chall.id = id;
+        }
+        chall.points = chall.points.or(defaults.points.clone());
+        chall.tiebreak = chall.tiebreak.or(defaults.tiebreak);
+        for container in chall.containers.values_mut() {
+            let limits = &mut container.limits;
+            limits.cpu = limits.cpu.or(defaults.limits.cpu);
+            limits.mem = limits.mem.or(defaults.limits.mem);
+        }
+        chall.dir = dir;
+        Ok(chall)
     }
 
-    pub fn get(root: &PathBuf, chall: String) -> Result<Challenge> {
-        let dir = root.as_path().join(chall);
-        if !dir.is_dir() {
-            return Err(anyhow!("{} is not a directory", dir.display()));
+    pub fn parse(config: &Config, chall_dir: PathBuf) -> Result<Challenge> {
+        let file_data = fs::read_to_string(chall_dir.join("challenge.toml")).map_err(|_| anyhow!("Failed to read challenge.toml, Make sure it exists at the root of your challenge directory"))?;
+        let id = Challenge::id_from_path(&config.chall_root, &chall_dir)?;
+        let category = Category::load(&config.chall_root, category_folder(&id))?.inherit(config);
+        let path = chall_dir.join("challenge.toml");
+        Challenge::from_toml(id.clone(), chall_dir, &category, &file_data)
+            .map_err(|e| anyhow!("failed to parse {id} ({})\n{e}", path.display()))
+    }
+
+    /// Directory of the challenge with id `chall`, or at path `chall` relative to the challenge
+    /// root. Only the `id` of the other challenges is read to find it, so broken challenge.toml
+    /// files elsewhere don't get in the way. None if there is no such challenge.
+    pub fn find_dir(config: &Config, chall: &str) -> Result<Option<PathBuf>> {
+        let dir = config.chall_root.join(chall);
+        if dir.join("challenge.toml").is_file() {
+            return Ok(Some(dir));
+        }
+
+        #[derive(Deserialize)]
+        struct Id {
+            id: Option<String>,
+        }
+        for dir in get_chall_paths(&config.chall_root, &config.ignore)? {
+            let data = fs::read_to_string(dir.join("challenge.toml"))?;
+            let id = match toml::from_str::<Id>(&data).ok().and_then(|c| c.id) {
+                Some(id) => id,
+                None => Challenge::id_from_path(&config.chall_root, &dir)?,
+            };
+            if id == chall {
+                return Ok(Some(dir));
+            }
         }
+        Ok(None)
+    }
 
-        Challenge::parse(dir)
+    /// Find a challenge by id, or by its path relative to the challenge root
+    pub fn get(config: &Config, chall: String) -> Result<Challenge> {
+        match Challenge::find_dir(config, &chall)? {
+            Some(dir) => Challenge::parse(config, dir),
+            None => Err(anyhow!("no challenge with id {chall}")),
+        }
     }
 
-    pub fn get_all(root: &PathBuf) -> Result<Vec<Challenge>> {
-        let paths = get_chall_paths(root)?;
-        paths
+    pub fn get_all(config: &Config) -> Result<Vec<Challenge>> {
+        let paths = get_chall_paths(&config.chall_root, &config.ignore)?;
+        let challs = paths
             .into_iter()
-            .map(|path| Challenge::parse(path))
-            .collect::<Result<Vec<Challenge>, _>>()
+            .map(|path| Challenge::parse(config, path))
+            .collect::<Result<Vec<Challenge>, _>>()?;
+        let mut seen = HashMap::new();
+        for chall in &challs {
+            if let Some(other) = seen.insert(&chall.id, &chall.dir) {
+                return Err(anyhow!(
+                    "{} and {} both have the id {}",
+                    other.display(),
+                    chall.dir.display(),
+                    chall.id
+                ));
+            }
+        }
+        Ok(challs)
     }
 
-    pub fn get_some(root: &PathBuf, challs: Vec<String>) -> Result<Vec<Challenge>> {
+    pub fn get_some(config: &Config, challs: Vec<String>) -> Result<Vec<Challenge>> {
         let mut parsed_challs = Vec::new();
         for chall in challs {
-            parsed_challs.push(Challenge::get(&root, chall)?);
+            parsed_challs.push(Challenge::get(config, chall)?);
         }
         Ok(parsed_challs)
     }
 
-    // TODO return type bad >:(
-    pub async fn build(
-        self,
-        root: &PathBuf,
-        tmp_dir: &TempDir,
-    ) -> Result<Vec<bollard::models::BuildInfo>> {
-        let build_info = vec![];
-        for (name, container) in &self.containers {
-            let mut build_path = root.clone();
-            build_path.push(&self.id);
-            build_path.push(&container.build);
-
-            // build a tar
-            let tar_path =
-                tmp_dir.child(format!("{}-{}.docker.tar", self.id.replace("/", "-"), name));
-            let tar_file = File::create(&tar_path)?;
-            let mut tar = tar::Builder::new(tar_file);
-            tar.append_dir_all(".", &build_path).map_err(|e| {
-                anyhow!(
-                    "Failed to read {}. Make sure it exists and is a directory.\n{e:?}",
-                    build_path.display()
-                )
-            })?;
-            tar.finish()?;
+    pub fn container_id(&self, name: &str) -> String {
+        format!("{}-{}", self.id.replace('/', "-"), name)
+    }
 
-            let options = BuildImageOptions {
-                dockerfile: "Dockerfile",
-                t: &self.container_id(name),
-                rm: true,
-                ..Default::default()
-            };
-            let mut read_tar = File::open(&tar_path)?;
-            let mut contents = Vec::new();
-            read_tar.read_to_end(&mut contents).unwrap();
-            println!("building image: {}", options.t);
-            let mut build = DOCKER.build_image(options, None, Some(contents.into()));
-            while let Some(build_step) = build.next().await {
-                if let Some(stream) = build_step
-                    .map_err(|err| anyhow!("docker error: {err:?}"))?
-                    .stream
-                {
-                    print!("{stream}")
+    /// Files handed out with the challenge, read from disk and packed for upload
+    pub fn provided_files(&self) -> Result<Vec<ProvidedFile>> {
+        let Some(attachments) = &self.provide else {
+            return Ok(vec![]);
+        };
+        let mut files = vec![];
+        for attachment in attachments {
+            match attachment {
+                Attachment::File(f) => {
+                    let mut path: PathBuf = self.dir.clone();
+                    path.push(f);
+                    if path.is_file() {
+                        let mut buf = Vec::new();
+                        let mut file = File::open(&path)?;
+                        file.read_to_end(&mut buf)?;
+                        files.push(ProvidedFile {
+                            name: path.file_name().unwrap().to_str().unwrap().to_string(),
+                            data: buf,
+                        })
+                    } else {
+                        Err(anyhow!("Provided file {} is not a file.", f.display()))?
+                    }
+                }
+                Attachment::Named { file, r#as } => {
+                    let mut path: PathBuf = self.dir.clone();
+                    path.push(file);
+                    if path.is_file() {
+                        let mut buf = Vec::new();
+                        let mut file = File::open(&path)?;
+                        file.read_to_end(&mut buf)?;
+                        files.push(ProvidedFile {
+                            name: r#as.clone(),
+                            data: buf,
+                        })
+                    } else {
+                        Err(anyhow!("Provided file {} is not a file.", file.display()))?
+                    }
+                }
+                Attachment::Folder { dir, r#as, exclude } => {
+                    let mut path: PathBuf = self.dir.clone();
+                    path.push(dir);
+                    if path.is_dir() {
+                        let filename = dir.file_name().unwrap().to_str().unwrap().to_string();
+                        let name = r#as.clone().unwrap_or(filename);
+                        let buf = Vec::new();
+                        let enc = GzEncoder::new(buf, Compression::default());
+                        let mut tar = tar::Builder::new(enc);
+                        // fixed mtimes, owners and modes, so the archive only changes when the
+                        // files in it do
+                        tar.mode(tar::HeaderMode::Deterministic);
+                        append_dir(
+                            &mut tar,
+                            &PathBuf::from(format!("./{name}")),
+                            &path,
+                            exclude.as_deref().unwrap_or_default(),
+                        )?;
+                        files.push(ProvidedFile {
+                            name: format!("{name}.tar.gz"),
+                            data: tar.into_inner()?.finish()?,
+                        });
+                    } else {
+                        Err(anyhow!("Provided path {} is not a dir", dir.display()))?;
+                    }
                 }
             }
+        }
+        Ok(files)
+    }
 
-            print!("\n");
-
-            // build_info.push();
-        }
-        Ok(build_info)
-    }
-
-    pub async fn push(&self, repo: &str, name: &str) -> Result<()> {
-        let image_name = self.container_id(name);
-        let new_tag = format!("{repo}:{image_name}");
-        DOCKER
-            .tag_image(
-                &image_name,
-                Some(bollard::image::TagImageOptions {
-                    repo: new_tag.clone(),
-                    ..Default::default()
-                }),
-            )
-            .await?;
-        let mut push = DOCKER.push_image::<String>(
-            &new_tag,
-            None,
-            Some(DockerCredentials {
-                // https://community.fly.io/t/push-to-fly-io-image-registry-via-docker-api/9132
-                // I have NO idea why the username is x and why the password is the api token,
-                // this took 2 hours to figure out and probably took a couple years off my life as well.
-                username: Some("x".to_string()),
-                password: Some(fly::FLY_API_TOKEN.clone()),
-                ..Default::default()
-            }),
-        );
-
-        while let Some(push_step) = push.next().await {
-            println!("{:#?}", push_step);
+    /// Description with `{{name.url}}` replaced by how to connect to `expose.name`
+    pub fn render_description(&self, hostname: &str) -> String {
+        let mut description = self.description.clone();
+        for (name, expose) in &self.expose {
+            let url = match expose {
+                Expose::Tcp { tcp, .. } => format!("`nc chal.{hostname} {tcp}`"),
+                Expose::Http { http, .. } => {
+                    format!("[http://{http}.{hostname}](http://{http}.{hostname})")
+                }
+            };
+            description = description.replace(&format!("{{{{{name}.url}}}}",), &url);
         }
-        Ok(())
+        description
     }
 
-    pub async fn push_all(&self, repo: &str) -> Result<()> {
-        for (name, _) in &self.containers {
-            self.push(repo, name).await?;
+    /// The flag, without the whitespace (usually a trailing newline) a flag file ends up with
+    pub fn get_flag(&self) -> Result<String> {
+        let flag = match &self.flag {
+            Flag::Raw(flag) => flag.clone(),
+            Flag::File { file } => fs::read_to_string(self.dir.join(file))?,
+        };
+        Ok(flag.trim().to_string())
+    }
+}
+
+impl Category {
+    /// category.toml of the category folder `name`, if there is one
+    pub fn load(root: &Path, name: &str) -> Result<Category> {
+        let path = root.join(name).join("category.toml");
+        if !path.is_file() {
+            return Ok(Category::default());
         }
+        toml::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| anyhow!("failed to parse {}\n{e}", path.display()))
+    }
 
-        Ok(())
+    /// Fall back to the defaults in bear.toml for anything the category doesn't set
+    pub fn inherit(mut self, config: &Config) -> Category {
+        self.points = self.points.or(config.points.clone());
+        self.tiebreak = self.tiebreak.or(config.tiebreak);
+        self
     }
+}
 
-    pub fn container_id(&self, name: &str) -> String {
-        return format!("{}-{}", self.id.replace("/", "-"), name);
+/// Folder the challenge with the path-derived id `id` is in, which category.toml is read from
+pub fn category_folder(id: &str) -> &str {
+    id.split('/').next().unwrap_or_default()
+}
+
+/// Every directory under `root` with a challenge.toml in it, sorted. Hidden directories and
+/// anything matching `ignore` (gitignore syntax, relative to `root`) are skipped, and nothing
+/// inside a challenge is searched.
+pub fn get_chall_paths(root: &Path, ignore: &[String]) -> Result<Vec<PathBuf>> {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in ignore {
+        builder.add_line(None, pattern)?;
+    }
+    let ignore = builder.build()?;
+
+    let mut challenges = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let hidden = path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+            if !entry.file_type()?.is_dir()
+                || hidden
+                || ignore
+                    .matched_path_or_any_parents(path.strip_prefix(root)?, true)
+                    .is_ignore()
+            {
+                continue;
+            }
+            if path.join("challenge.toml").is_file() {
+                challenges.push(path);
+            } else {
+                pending.push(path);
+            }
+        }
     }
+    challenges.sort();
+    Ok(challenges)
+}
 
-    pub fn get_flag(&self, root: &PathBuf) -> Result<String> {
-        match &self.flag {
-            Flag::Raw(flag) => Ok(flag.clone()),
-            Flag::File { file } => Ok(fs::read_to_string(root.join(&self.id).join(file))?),
+/// Like `tar::Builder::append_dir_all`, but skips anything in `exclude` (relative to `dir`).
+fn append_dir<W: std::io::Write>(
+    tar: &mut tar::Builder<W>,
+    name: &Path,
+    dir: &Path,
+    exclude: &[PathBuf],
+) -> Result<()> {
+    tar.append_dir(name, dir)?;
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()?;
+    paths.sort();
+    for path in paths {
+        let relative = path.strip_prefix(dir)?;
+        if exclude.iter().any(|e| e == relative) {
+            continue;
+        }
+        let exclude = exclude
+            .iter()
+            .filter_map(|e| e.strip_prefix(relative).ok().map(Path::to_path_buf))
+            .collect::<Vec<PathBuf>>();
+        if path.is_dir() {
+            append_dir(tar, &name.join(relative), &path, &exclude)?;
+        } else {
+            tar.append_path_with_name(&path, name.join(relative))?;
         }
     }
+    Ok(())
 }
 
-pub fn get_chall_paths(root: &PathBuf) -> io::Result<Vec<PathBuf>> {
-    let mut challenges = Vec::new();
-    for category_entry in fs::read_dir(root)? {
-        let category = category_entry?.path();
-
-        if category.is_dir() {
-            for chall_entry in fs::read_dir(&category)? {
-                let chall = chall_entry?.path();
-                let path = &chall.join("challenge.toml");
-                if path.exists() {
-                    challenges.push(chall);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse `value` as the right hand side of `v = ...`
+    fn parse<T: for<'de> Deserialize<'de>>(value: &str) -> Result<T, String> {
+        #[derive(Deserialize)]
+        struct Wrapper<T> {
+            v: T,
+        }
+        toml::from_str::<Wrapper<T>>(&format!("v = {value}"))
+            .map(|w| w.v)
+            .map_err(|e| e.message().to_string())
+    }
+
+    #[test]
+    fn points() {
+        assert!(matches!(parse("50"), Ok(Points::Fixed(50))));
+        assert!(matches!(
+            parse("{ min = 100, max = 500 }"),
+            Ok(Points::Range { min: 100, max: 500 })
+        ));
+        assert_eq!(parse::<Points>("-1").unwrap_err(), "points can't be -1");
+        assert_eq!(
+            parse::<Points>("{ min = 500, max = 100 }").unwrap_err(),
+            "min (500) is more than max (100)"
+        );
+        assert_eq!(
+            parse::<Points>("{ min = 100 }").unwrap_err(),
+            "missing field `max`"
+        );
+        assert!(parse::<Points>("{ min = 1, maxx = 2 }")
+            .unwrap_err()
+            .contains("unknown field `maxx`"));
+        assert!(parse::<Points>("\"500\"")
+            .unwrap_err()
+            .contains("a number of points"));
+    }
+
+    #[test]
+    fn flag() {
+        assert!(matches!(parse("\"flag{x}\""), Ok(Flag::Raw(flag)) if flag == "flag{x}"));
+        assert!(matches!(
+            parse("{ file = \"flag.txt\" }"),
+            Ok(Flag::File { file }) if file == Path::new("flag.txt")
+        ));
+        assert_eq!(parse::<Flag>("{}").unwrap_err(), "missing field `file`");
+        assert!(parse::<Flag>("{ path = \"flag.txt\" }")
+            .unwrap_err()
+            .contains("unknown field `path`"));
+    }
+
+    #[test]
+    fn attachment() {
+        assert!(matches!(
+            parse("\"dist/chall\""),
+            Ok(Attachment::File(file)) if file == Path::new("dist/chall")
+        ));
+        assert!(matches!(
+            parse("{ file = \"a\", as = \"b\" }"),
+            Ok(Attachment::Named { file, r#as }) if file == Path::new("a") && r#as == "b"
+        ));
+        assert!(matches!(
+            parse("{ dir = \"src\", exclude = [\"flag.txt\"] }"),
+            Ok(Attachment::Folder { dir, r#as: None, exclude: Some(exclude) })
+                if dir == Path::new("src") && exclude == [PathBuf::from("flag.txt")]
+        ));
+        assert_eq!(
+            parse::<Attachment>("{ file = \"a\" }").unwrap_err(),
+            "missing field `as`"
+        );
+        assert_eq!(
+            parse::<Attachment>("{ file = \"a\", dir = \"b\" }").unwrap_err(),
+            "an attachment has either `file` or `dir`, not both"
+        );
+        assert_eq!(
+            parse::<Attachment>("{ file = \"a\", as = \"b\", exclude = [] }").unwrap_err(),
+            "`exclude` only works for `dir` attachments"
+        );
+        assert_eq!(
+            parse::<Attachment>("{ as = \"b\" }").unwrap_err(),
+            "an attachment needs either `file` or `dir`"
+        );
+    }
+
+    #[test]
+    fn expose() {
+        assert!(matches!(
+            parse("{ target = 5000, tcp = 31337 }"),
+            Ok(Expose::Tcp {
+                target: 5000,
+                tcp: 31337
+            })
+        ));
+        assert!(matches!(
+            parse("{ target = 80, http = \"web\" }"),
+            Ok(Expose::Http { target: 80, http }) if http == "web"
+        ));
+        assert_eq!(
+            parse::<Expose>("{ tcp = 31337 }").unwrap_err(),
+            "missing field `target`"
+        );
+        assert_eq!(
+            parse::<Expose>("{ target = 80, tcp = 1, http = \"web\" }").unwrap_err(),
+            "an expose has either `tcp` or `http`, not both"
+        );
+        assert_eq!(
+            parse::<Expose>("{ target = 80 }").unwrap_err(),
+            "an expose needs either `tcp = <port>` or `http = \"<subdomain>\"`"
+        );
+        assert!(parse::<Expose>("{ target = 80, udp = 1 }")
+            .unwrap_err()
+            .contains("unknown field `udp`"));
+    }
+
+    #[test]
+    fn schema_is_as_strict_as_parsing() {
+        let schema = serde_json::to_value(schemars::schema_for!(Challenge)).unwrap();
+        for name in ["Flag", "Attachment", "Expose"] {
+            for variant in schema["$defs"][name]["anyOf"].as_array().unwrap() {
+                if variant["type"] == "object" {
+                    assert_eq!(variant["additionalProperties"], false, "{name}: {variant}");
                 }
             }
         }
+        let folder = &schema["$defs"]["Attachment"]["anyOf"][2]["properties"];
+        assert_eq!(folder["as"], serde_json::json!({ "type": "string" }));
+        assert_eq!(folder["exclude"]["type"], "array");
+    }
+
+    /// A challenge in `dir` providing the folder `dist` as `dist.tar.gz`
+    fn providing_dist(dir: &Path) -> Challenge {
+        Challenge::from_toml(
+            "misc/files".to_string(),
+            dir.to_path_buf(),
+            &Category::default(),
+            "name = \"Files\"\nauthor = \"bear\"\ndescription = \"\"\nflag = \"flag{x}\"\nprovide = [{ dir = \"dist\", exclude = [\"secret\"] }]\n",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn folders_archive_the_same_every_time() {
+        let files = [
+            ("b.txt", "b"),
+            ("a.txt", "a"),
+            ("sub/c.txt", "c"),
+            ("secret", "s"),
+        ];
+        let archive = |order: &[usize], mtime: u64| {
+            let dir = crate::mock::scratch_dir("provide");
+            for &i in order {
+                let (name, contents) = files[i];
+                let path = dir.join("dist").join(name);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(&path, contents).unwrap();
+                File::options()
+                    .write(true)
+                    .open(&path)
+                    .unwrap()
+                    .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime))
+                    .unwrap();
+            }
+            let mut provided = providing_dist(&dir).provided_files().unwrap();
+            assert_eq!(provided.len(), 1);
+            provided.remove(0)
+        };
+
+        let first = archive(&[0, 1, 2, 3], 1_000_000);
+        let second = archive(&[3, 2, 1, 0], 2_000_000);
+        assert_eq!(first.name, "dist.tar.gz");
+        assert_eq!(first.data, second.data);
+        assert_eq!(first.sha256(), second.sha256());
+
+        let names = tar::Archive::new(flate2::read::GzDecoder::new(&first.data[..]))
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            names,
+            [
+                "dist",
+                "dist/a.txt",
+                "dist/b.txt",
+                "dist/sub",
+                "dist/sub/c.txt"
+            ]
+        );
+    }
+
+    /// A challenge repo with `files` (path and contents) in it
+    fn repo(files: &[(&str, &str)]) -> Config {
+        let root = crate::mock::scratch_dir("repo");
+        for (path, contents) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        let mut config: Config =
+            toml::from_str("hostname = \"example.com\"\n[backend]\ntype = \"local\"\n").unwrap();
+        config.chall_root = root;
+        config
+    }
+
+    const CHALL: &str =
+        "name = \"Chall\"\nauthor = \"bear\"\ndescription = \"\"\nflag = \"flag{x}\"\n";
+
+    #[test]
+    fn discovery_recurses_and_skips_ignored_and_hidden() {
+        let mut config = repo(&[
+            ("web/baby/challenge.toml", CHALL),
+            ("pwn/heap/part1/challenge.toml", CHALL),
+            ("pwn/heap/part2/challenge.toml", CHALL),
+            // nothing inside a challenge is a challenge
+            ("pwn/heap/part2/solve/challenge.toml", CHALL),
+            ("_drafts/web/wip/challenge.toml", CHALL),
+            ("crypto/rsa/solve/challenge.toml", CHALL),
+            (".git/challenge/challenge.toml", CHALL),
+            ("web/.hidden/challenge.toml", CHALL),
+            ("README.md", ""),
+        ]);
+        config.ignore = vec!["_drafts".to_string(), "**/solve".to_string()];
+        let ids = Challenge::get_all(&config)
+            .unwrap()
+            .into_iter()
+            .map(|chall| chall.id)
+            .collect::<Vec<String>>();
+        assert_eq!(ids, ["pwn/heap/part1", "pwn/heap/part2", "web/baby"]);
+    }
+
+    #[test]
+    fn custom_ids_and_categories() {
+        let config = repo(&[
+            (
+                "web/elements/challenge.toml",
+                &format!("id = \"elements\"\ncategory = \"Misc\"\n{CHALL}"),
+            ),
+            ("web/baby/challenge.toml", CHALL),
+            // broken, but only its id is read while looking for another challenge
+            ("web/broken/challenge.toml", "id = \"broken\"\nname = 1\n"),
+        ]);
+        let dir = config.chall_root.join("web/elements");
+        assert_eq!(
+            Challenge::find_dir(&config, "elements").unwrap(),
+            Some(dir.clone())
+        );
+        assert_eq!(
+            Challenge::find_dir(&config, "web/elements").unwrap(),
+            Some(dir)
+        );
+        assert_eq!(Challenge::find_dir(&config, "web/nope").unwrap(), None);
+
+        let chall = Challenge::get(&config, "elements".to_string()).unwrap();
+        assert_eq!(chall.id, "elements");
+        assert_eq!(chall.category, "Misc");
+        let chall = Challenge::get(&config, "web/baby".to_string()).unwrap();
+        assert_eq!(chall.id, "web/baby");
+        assert_eq!(chall.category, "web");
+    }
+
+    #[test]
+    fn duplicate_ids_are_rejected() {
+        let config = repo(&[
+            ("web/a/challenge.toml", &format!("id = \"same\"\n{CHALL}")),
+            ("pwn/b/challenge.toml", &format!("id = \"same\"\n{CHALL}")),
+        ]);
+        let e = Challenge::get_all(&config).unwrap_err().to_string();
+        assert!(e.ends_with("both have the id same"), "{e}");
+    }
+
+    #[test]
+    fn category_defaults_are_inherited() {
+        let mut config = repo(&[
+            (
+                "web/category.toml",
+                "name = \"Web Exploitation\"\nauthor = \"voxal\"\npoints = 50\nlimits = { cpu = 1, mem = 256 }\n",
+            ),
+            (
+                "web/baby/challenge.toml",
+                "name = \"Baby\"\ndescription = \"\"\nflag = \"flag{x}\"\n[containers.main]\nbuild = \".\"\nlimits = { mem = 512 }\n",
+            ),
+            (
+                "web/own/challenge.toml",
+                &format!("category = \"Misc\"\npoints = {{ min = 10, max = 20 }}\ntiebreak = true\n{CHALL}"),
+            ),
+            ("pwn/heap/part1/challenge.toml", CHALL),
+            ("pwn/category.toml", "tiebreak = false\n"),
+        ]);
+        config.tiebreak = Some(true);
+        config.points = Some(Points::Fixed(1));
+
+        let baby = Challenge::get(&config, "web/baby".to_string()).unwrap();
+        assert_eq!(baby.category, "Web Exploitation");
+        assert_eq!(baby.author, "voxal");
+        assert!(matches!(baby.points, Some(Points::Fixed(50))));
+        assert_eq!(baby.tiebreak, Some(true));
+        let limits = &baby.containers["main"].limits;
+        assert_eq!((limits.cpu, limits.mem), (Some(1), Some(512)));
+
+        let own = Challenge::get(&config, "web/own".to_string()).unwrap();
+        assert_eq!(own.category, "Misc");
+        assert_eq!(own.author, "bear");
+        assert!(matches!(
+            own.points,
+            Some(Points::Range { min: 10, max: 20 })
+        ));
+
+        // nested challenges use the category.toml of their top folder
+        let part1 = Challenge::get(&config, "pwn/heap/part1".to_string()).unwrap();
+        assert_eq!(part1.category, "pwn");
+        assert_eq!(part1.tiebreak, Some(false));
+        assert!(matches!(part1.points, Some(Points::Fixed(1))));
+    }
+
+    #[test]
+    fn author_is_required_without_a_category_default() {
+        let config = repo(&[(
+            "web/baby/challenge.toml",
+            "name = \"Baby\"\ndescription = \"\"\nflag = \"flag{x}\"\n",
+        )]);
+        let e = Challenge::get(&config, "web/baby".to_string())
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("set it here or in category.toml"), "{e}");
     }
-    return Ok(challenges);
 }
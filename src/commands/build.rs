@@ -0,0 +1,649 @@
+//! Building images.
+//!
+//! The base images from `[images]` in bear.toml and every challenge container are jobs in one
+//! graph. A job depends on the jobs whose tags its dockerfile builds on (`FROM` or
+//! `COPY --from`), and only starts once all of those are built.
+
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    fs::{self, File},
+    future::Future,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    challenge::Challenge,
+    context::{self, BuildContext},
+    Config, DOCKER, LOGS_DIR,
+};
+use anyhow::{anyhow, Result};
+use bollard::body_try_stream;
+use colored::*;
+use futures::{stream::FuturesUnordered, StreamExt};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+struct Job {
+    tag: String,
+    /// Challenge the image belongs to, if it isn't a base image
+    chall: Option<String>,
+    context: BuildContext,
+    /// Base images are only built when something needs them, unless everything is being built
+    base: bool,
+}
+
+/// Tag a base image from bear.toml is built as
+pub fn image_tag(name: &str) -> String {
+    format!("bear-cds/{name}")
+}
+
+/// Images a dockerfile builds on. Build args in image names aren't expanded.
+pub fn references(context: &BuildContext) -> Vec<String> {
+    let dockerfile = fs::read_to_string(&context.dockerfile).unwrap_or_default();
+    let mut references = Vec::new();
+    for line in dockerfile.replace("\\\n", " ").lines() {
+        let mut words = line.split_whitespace();
+        let Some(instruction) = words.next() else {
+            continue;
+        };
+        if instruction.eq_ignore_ascii_case("FROM") {
+            if let Some(image) = words.find(|word| !word.starts_with("--")) {
+                references.push(image.to_string());
+            }
+        } else if instruction.eq_ignore_ascii_case("COPY") {
+            references.extend(
+                words
+                    .filter_map(|word| word.strip_prefix("--from="))
+                    .map(str::to_string),
+            );
+        }
+    }
+    references
+}
+
+/// Whether any of the references is `tag`
+pub fn builds_on(references: &[String], tag: &str) -> bool {
+    references
+        .iter()
+        .any(|reference| reference == tag || reference == &format!("{tag}:latest"))
+}
+
+/// Indices of the jobs each job depends on
+fn dependencies(jobs: &[Job]) -> Vec<Vec<usize>> {
+    jobs.iter()
+        .map(|job| {
+            let references = references(&job.context);
+            jobs.iter()
+                .enumerate()
+                .filter(|(_, other)| builds_on(&references, &other.tag))
+                .map(|(i, _)| i)
+                .collect()
+        })
+        .collect()
+}
+
+/// What came out of building one image
+pub struct BuildResult {
+    pub tag: String,
+    pub image_id: Option<String>,
+    pub digest: Option<String>,
+    pub duration: Duration,
+    /// Warnings docker printed while building
+    pub warnings: Vec<String>,
+    /// The image was already up to date, so nothing was built
+    pub skipped: bool,
+    /// Full output of the build
+    pub log: Option<PathBuf>,
+}
+
+impl BuildResult {
+    fn status(&self) -> String {
+        let mut status = if self.skipped {
+            "up to date".to_string()
+        } else {
+            format!("built in {:.1}s", self.duration.as_secs_f64())
+        };
+        if !self.warnings.is_empty() {
+            status.push_str(&format!(", {} warnings", self.warnings.len()));
+        }
+        status
+    }
+}
+
+fn is_warning(line: &str) -> bool {
+    line.starts_with("[WARNING]") || line.starts_with("WARNING") || line.starts_with("WARN")
+}
+
+async fn build(
+    job: &Job,
+    dependencies: Vec<&Job>,
+    force: bool,
+    logs: &Path,
+    progress: &ProgressBar,
+) -> Result<BuildResult> {
+    let start = Instant::now();
+    let tag = &job.tag;
+    let mut context = job.context.clone();
+    for dependency in dependencies {
+        let image = DOCKER.inspect_image(&dependency.tag).await?;
+        context
+            .bases
+            .insert(dependency.tag.clone(), image.id.unwrap_or_default());
+    }
+    // reads every file in the context, which can be a lot
+    let hash = {
+        let context = context.clone();
+        tokio::task::spawn_blocking(move || context.hash()).await??
+    };
+    if !force && context::image_hash(tag).await.as_ref() == Some(&hash) {
+        let image = DOCKER.inspect_image(tag).await?;
+        return Ok(BuildResult {
+            tag: tag.clone(),
+            image_id: image.id,
+            digest: None,
+            duration: start.elapsed(),
+            warnings: vec![],
+            skipped: true,
+            log: None,
+        });
+    }
+
+    fs::create_dir_all(logs)?;
+    let log_path = logs.join(format!("{}.log", tag.replace('/', "-")));
+    let mut log = File::create(&log_path)?;
+    let mut warnings = Vec::new();
+    // the end of the output is usually enough to tell why a build failed
+    let mut tail = VecDeque::new();
+    let fail = |error: String, tail: &VecDeque<String>| {
+        let mut message = error.trim().to_string();
+        for line in tail {
+            message.push_str(&format!("\n    {line}"));
+        }
+        message.push_str(&format!("\nfull log: {}", log_path.display()));
+        anyhow!(message)
+    };
+
+    progress.set_message("sending build context");
+    let mut build = DOCKER.build_image(
+        context.options(tag, &hash),
+        None,
+        Some(body_try_stream(context.archive())),
+    );
+    while let Some(build_step) = build.next().await {
+        let build_step = match build_step {
+            Ok(build_step) => build_step,
+            Err(e) => {
+                writeln!(log, "{e}")?;
+                return Err(fail(format!("docker error: {e}"), &tail));
+            }
+        };
+        if let Some(stream) = &build_step.stream {
+            log.write_all(stream.as_bytes())?;
+            for line in stream.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                if is_warning(line) {
+                    warnings.push(line.to_string());
+                }
+                if tail.len() == 10 {
+                    tail.pop_front();
+                }
+                tail.push_back(line.to_string());
+                progress.set_message(line.to_string());
+            }
+        }
+        if let Some(status) = &build_step.status {
+            writeln!(log, "{status}")?;
+        }
+        if let Some(error) = build_step
+            .error_detail
+            .and_then(|detail| detail.message)
+            .or(build_step.error)
+        {
+            writeln!(log, "{error}")?;
+            return Err(fail(error, &tail));
+        }
+    }
+
+    let image = DOCKER.inspect_image(tag).await?;
+    Ok(BuildResult {
+        tag: tag.clone(),
+        image_id: image.id,
+        digest: image
+            .repo_digests
+            .unwrap_or_default()
+            .into_iter()
+            .find_map(|digest| digest.split_once('@').map(|(_, d)| d.to_string())),
+        duration: start.elapsed(),
+        warnings,
+        skipped: false,
+        log: Some(log_path),
+    })
+}
+
+/// How building one image went
+enum Outcome {
+    Done(BuildResult),
+    Failed(anyhow::Error),
+    /// Never started because the image it builds on failed
+    Blocked(String),
+}
+
+/// Build every job with `build` after the jobs it depends on, at most `threads` at a time, with
+/// a spinner for every build that's running. A failed build only stops the images built on top
+/// of it.
+async fn schedule<'a, F, Fut>(jobs: &'a [Job], threads: usize, build: F) -> Vec<Outcome>
+where
+    F: Fn(&'a Job, Vec<&'a Job>, ProgressBar) -> Fut,
+    Fut: Future<Output = Result<BuildResult>> + 'a,
+{
+    let dependencies = dependencies(jobs);
+    let mut dependents = vec![vec![]; jobs.len()];
+    for (i, deps) in dependencies.iter().enumerate() {
+        for &dep in deps {
+            dependents[dep].push(i);
+        }
+    }
+    let mut waiting_on = dependencies.iter().map(Vec::len).collect::<Vec<usize>>();
+    let mut ready = (0..jobs.len())
+        .filter(|&i| waiting_on[i] == 0)
+        .collect::<VecDeque<usize>>();
+    let progress = MultiProgress::new();
+    let style = ProgressStyle::with_template("{spinner:.cyan} {prefix:.bold} {wide_msg:.dim}")
+        .expect("invalid progress template");
+    let mut running = FuturesUnordered::new();
+    let mut outcomes = (0..jobs.len())
+        .map(|_| None)
+        .collect::<Vec<Option<Outcome>>>();
+    loop {
+        while running.len() < threads.max(1) {
+            let Some(i) = ready.pop_front() else {
+                break;
+            };
+            let deps = dependencies[i].iter().map(|&dep| &jobs[dep]).collect();
+            let job = &jobs[i];
+            let bar = progress.add(
+                ProgressBar::new_spinner()
+                    .with_style(style.clone())
+                    .with_prefix(job.tag.clone()),
+            );
+            bar.enable_steady_tick(Duration::from_millis(100));
+            if progress.is_hidden() {
+                println!("building image: {}", job.tag);
+            }
+            let build = build(job, deps, bar.clone());
+            running.push(async move {
+                let result = build.await;
+                match &result {
+                    Ok(result) => bar.finish_with_message(result.status()),
+                    Err(_) => bar.abandon_with_message("failed".red().to_string()),
+                }
+                (i, result)
+            });
+        }
+        let Some((i, result)) = running.next().await else {
+            break;
+        };
+        match result {
+            Ok(result) => {
+                if progress.is_hidden() {
+                    println!("{}: {}", result.tag, result.status());
+                }
+                outcomes[i] = Some(Outcome::Done(result));
+                for &dependent in &dependents[i] {
+                    waiting_on[dependent] -= 1;
+                    if waiting_on[dependent] == 0 {
+                        ready.push_back(dependent);
+                    }
+                }
+            }
+            Err(e) => {
+                if progress.is_hidden() {
+                    println!("{}: failed", jobs[i].tag);
+                }
+                outcomes[i] = Some(Outcome::Failed(e));
+                let mut blocked = dependents[i].clone();
+                while let Some(dependent) = blocked.pop() {
+                    if outcomes[dependent].is_none() {
+                        outcomes[dependent] = Some(Outcome::Blocked(jobs[i].tag.clone()));
+                        blocked.extend(&dependents[dependent]);
+                    }
+                }
+            }
+        }
+    }
+    // anything that never got to run is waiting on itself somewhere
+    let stuck = (0..jobs.len())
+        .filter(|&i| outcomes[i].is_none())
+        .map(|i| jobs[i].tag.as_str())
+        .collect::<Vec<&str>>();
+    outcomes
+        .into_iter()
+        .map(|outcome| {
+            outcome.unwrap_or_else(|| {
+                Outcome::Failed(anyhow!("dependency cycle between {}", stuck.join(", ")))
+            })
+        })
+        .collect()
+}
+
+/// Drop the base images none of the other jobs build on, directly or through other base images.
+fn unused_bases(jobs: Vec<Job>) -> Vec<Job> {
+    let dependencies = dependencies(&jobs);
+    let mut needed = HashSet::new();
+    let mut pending = (0..jobs.len())
+        .filter(|&i| !jobs[i].base)
+        .collect::<Vec<usize>>();
+    while let Some(i) = pending.pop() {
+        if needed.insert(i) {
+            pending.extend(&dependencies[i]);
+        }
+    }
+    jobs.into_iter()
+        .enumerate()
+        .filter(|(i, _)| needed.contains(i))
+        .map(|(_, job)| job)
+        .collect()
+}
+
+pub async fn command(
+    config: Config,
+    selected: Option<Vec<String>>,
+    threads: usize,
+    force: bool,
+) -> Result<()> {
+    let challs = match &selected {
+        Some(selected) => Challenge::get_some(&config, selected.clone())?,
+        None => Challenge::get_all(&config)?,
+    };
+    match challs.len() {
+        0 => {}
+        1 => println!("Building {}", challs[0].id),
+        2 => println!("Building {} and {}", challs[0].id, challs[1].id),
+        _ => {
+            println!(
+                "Building {}, {} and {}",
+                challs[0].id,
+                challs[1].id,
+                if challs.len() > 3 {
+                    "more"
+                } else {
+                    &challs[2].id
+                },
+            )
+        }
+    }
+
+    let mut jobs = config
+        .images
+        .iter()
+        .map(|(name, image)| Job {
+            tag: image_tag(name),
+            chall: None,
+            context: image.build_context(&config.chall_root),
+            base: true,
+        })
+        .collect::<Vec<Job>>();
+    for chall in &challs {
+        let chall_dir = chall.dir.clone();
+        // sorted so builds start in the same order every time
+        let containers = chall.containers.iter().collect::<BTreeMap<_, _>>();
+        for (name, container) in containers {
+            jobs.push(Job {
+                tag: chall.container_id(name),
+                chall: Some(chall.id.clone()),
+                context: container.image.build_context(&chall_dir),
+                base: false,
+            });
+        }
+    }
+    if selected.is_some() {
+        jobs = unused_bases(jobs);
+    }
+    let logs = Path::new(LOGS_DIR);
+    let outcomes = schedule(&jobs, threads, |job, deps, bar| async move {
+        build(job, deps, force, logs, &bar).await
+    })
+    .await;
+
+    let width = jobs
+        .iter()
+        .map(|job| job.tag.len())
+        .max()
+        .unwrap_or_default();
+    let chall_width = jobs
+        .iter()
+        .filter_map(|job| job.chall.as_ref().map(String::len))
+        .max()
+        .unwrap_or_default()
+        .max("(base)".len());
+    println!();
+    let mut failed = 0;
+    for (job, outcome) in jobs.iter().zip(&outcomes) {
+        let chall = job.chall.as_deref().unwrap_or("(base)");
+        let (status, detail) = match outcome {
+            Outcome::Done(result) => {
+                let id = result
+                    .digest
+                    .as_ref()
+                    .or(result.image_id.as_ref())
+                    .map(|id| id.trim_start_matches("sha256:"))
+                    .and_then(|id| id.get(..12))
+                    .unwrap_or_default();
+                (result.status().green(), id.dimmed())
+            }
+            Outcome::Failed(_) => {
+                failed += 1;
+                ("failed".red().bold(), "".normal())
+            }
+            Outcome::Blocked(base) => {
+                failed += 1;
+                ("not built".red(), format!("{base} failed").dimmed())
+            }
+        };
+        println!(
+            "{chall:chall_width$}  {:width$}  {status} {detail}",
+            job.tag
+        );
+    }
+    for (job, outcome) in jobs.iter().zip(&outcomes) {
+        match outcome {
+            Outcome::Done(result) if !result.warnings.is_empty() => {
+                println!("\n{}", format!("{} warnings:", job.tag).yellow().bold());
+                for warning in &result.warnings {
+                    println!("  {}", warning.yellow());
+                }
+                if let Some(log) = &result.log {
+                    println!("  full log: {}", log.display());
+                }
+            }
+            Outcome::Failed(e) => {
+                eprintln!("\n{} {e:#}", format!("{} failed:", job.tag).red().bold());
+            }
+            _ => {}
+        }
+    }
+
+    if failed > 0 {
+        return Err(anyhow!("{failed} of {} images failed to build", jobs.len()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::scratch_dir;
+    use std::sync::{Arc, Mutex};
+
+    /// A job building `dockerfile`
+    fn job(tag: &str, dockerfile: &str, base: bool) -> Job {
+        let dir = scratch_dir("build");
+        fs::write(dir.join("Dockerfile"), dockerfile).unwrap();
+        Job {
+            tag: tag.to_string(),
+            chall: (!base).then(|| tag.to_string()),
+            context: BuildContext {
+                dockerfile: dir.join("Dockerfile"),
+                dir,
+                args: BTreeMap::new(),
+                target: None,
+                platform: None,
+                bases: BTreeMap::new(),
+            },
+            base,
+        }
+    }
+
+    fn result(tag: &str) -> BuildResult {
+        BuildResult {
+            tag: tag.to_string(),
+            image_id: None,
+            digest: None,
+            duration: Duration::ZERO,
+            warnings: vec![],
+            skipped: false,
+            log: None,
+        }
+    }
+
+    /// Run `schedule` with a build that fails for the tags in `failing`, returning the outcome of
+    /// every job as a string, the order builds finished in, and the most builds running at once
+    async fn run(
+        jobs: &[Job],
+        threads: usize,
+        failing: &[&str],
+    ) -> (Vec<String>, Vec<String>, usize) {
+        let finished = Arc::new(Mutex::new(Vec::new()));
+        let running = Arc::new(Mutex::new((0, 0)));
+        let outcomes = schedule(jobs, threads, |job, deps, _| {
+            let (finished, running) = (finished.clone(), running.clone());
+            let fail = failing.contains(&job.tag.as_str());
+            async move {
+                {
+                    let mut running = running.lock().unwrap();
+                    running.0 += 1;
+                    running.1 = running.1.max(running.0);
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                running.lock().unwrap().0 -= 1;
+                // everything a job builds on is done before it starts
+                let done = finished.lock().unwrap().clone();
+                assert!(deps.iter().all(|dep| done.contains(&dep.tag)));
+                finished.lock().unwrap().push(job.tag.clone());
+                match fail {
+                    true => Err(anyhow!("broken")),
+                    false => Ok(result(&job.tag)),
+                }
+            }
+        })
+        .await;
+        let outcomes = outcomes
+            .iter()
+            .map(|outcome| match outcome {
+                Outcome::Done(result) => format!("done {}", result.tag),
+                Outcome::Failed(e) => format!("failed: {e}"),
+                Outcome::Blocked(tag) => format!("blocked by {tag}"),
+            })
+            .collect();
+        let finished = finished.lock().unwrap().clone();
+        let max_running = running.lock().unwrap().1;
+        (outcomes, finished, max_running)
+    }
+
+    #[test]
+    fn references_and_dependencies() {
+        let jobs = [
+            job("bear-cds/base", "FROM ubuntu:24.04\n", true),
+            job(
+                "web-app",
+                "FROM --platform=linux/amd64 bear-cds/base:latest AS build\nCOPY \\\n  --from=bear-cds/tools /bin/x /x\n",
+                false,
+            ),
+            job("bear-cds/tools", "from bear-cds/base\n", true),
+        ];
+        assert_eq!(
+            references(&jobs[1].context),
+            ["bear-cds/base:latest", "bear-cds/tools"]
+        );
+        assert_eq!(dependencies(&jobs), [vec![], vec![0, 2], vec![0]]);
+    }
+
+    #[test]
+    fn unused_bases_are_dropped() {
+        let jobs = vec![
+            job("bear-cds/base", "FROM ubuntu\n", true),
+            job("bear-cds/tools", "FROM bear-cds/base\n", true),
+            job("bear-cds/unused", "FROM ubuntu\n", true),
+            job("pwn-chall", "FROM bear-cds/tools\n", false),
+        ];
+        let tags = unused_bases(jobs)
+            .into_iter()
+            .map(|job| job.tag)
+            .collect::<Vec<String>>();
+        assert_eq!(tags, ["bear-cds/base", "bear-cds/tools", "pwn-chall"]);
+    }
+
+    #[tokio::test]
+    async fn schedule_builds_dependencies_first() {
+        let jobs = [
+            job("web-a", "FROM bear-cds/base\n", false),
+            job("web-b", "FROM bear-cds/base\n", false),
+            job("web-c", "FROM bear-cds/base\n", false),
+            job("bear-cds/base", "FROM ubuntu\n", true),
+            job("misc", "FROM ubuntu\n", false),
+        ];
+        let (outcomes, finished, max_running) = run(&jobs, 2, &[]).await;
+        assert_eq!(
+            outcomes,
+            [
+                "done web-a",
+                "done web-b",
+                "done web-c",
+                "done bear-cds/base",
+                "done misc"
+            ]
+        );
+        assert_eq!(finished.len(), 5);
+        assert_eq!(max_running, 2);
+    }
+
+    #[tokio::test]
+    async fn schedule_blocks_what_builds_on_a_failure() {
+        let jobs = [
+            job("bear-cds/base", "FROM ubuntu\n", true),
+            job("bear-cds/tools", "FROM bear-cds/base\n", true),
+            job("pwn-chall", "FROM bear-cds/tools\n", false),
+            job("misc", "FROM ubuntu\n", false),
+        ];
+        let (outcomes, finished, _) = run(&jobs, 4, &["bear-cds/base"]).await;
+        assert_eq!(
+            outcomes,
+            [
+                "failed: broken",
+                "blocked by bear-cds/base",
+                "blocked by bear-cds/base",
+                "done misc"
+            ]
+        );
+        assert_eq!(finished.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn schedule_reports_cycles() {
+        let jobs = [
+            job("bear-cds/a", "FROM bear-cds/b\n", true),
+            job("bear-cds/b", "FROM bear-cds/a\n", true),
+            job("misc", "FROM ubuntu\n", false),
+        ];
+        let (outcomes, finished, _) = run(&jobs, 1, &[]).await;
+        assert_eq!(
+            outcomes,
+            [
+                "failed: dependency cycle between bear-cds/a, bear-cds/b",
+                "failed: dependency cycle between bear-cds/a, bear-cds/b",
+                "done misc"
+            ]
+        );
+        assert_eq!(finished, ["misc"]);
+    }
+}
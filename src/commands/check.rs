@@ -0,0 +1,504 @@
+//! Validates every challenge without deploying anything.
+//!
+//! Problems are reported with the place in challenge.toml they come from, compiler style, so the
+//! output reads fine both in a terminal and in CI logs.
+
+use std::{collections::BTreeMap, env, fs, ops::Range, path::PathBuf};
+
+use crate::{
+    backend::{self, DeployBackend},
+    challenge::{self, Attachment, Category, Challenge, Expose, Flag},
+    Config,
+};
+use anyhow::{anyhow, Result};
+use colored::*;
+use toml_edit::ImDocument;
+
+/// A challenge.toml that is being checked
+struct Source {
+    path: PathBuf,
+    text: String,
+    doc: Option<ImDocument<String>>,
+}
+
+impl Source {
+    fn load(path: PathBuf) -> Result<Source> {
+        let text = fs::read_to_string(&path)?;
+        let doc = ImDocument::parse(text.clone()).ok();
+        Ok(Source { path, text, doc })
+    }
+
+    /// Where the value at `keys` is defined, falling back to its closest parent that has a
+    /// location (implicit tables like `expose` in `[expose.web]` don't).
+    fn span(&self, keys: &[&str]) -> Option<Range<usize>> {
+        let doc = self.doc.as_ref()?;
+        let mut item = doc.get(keys.first()?)?;
+        let mut span = item.span();
+        for key in &keys[1..] {
+            item = match key.parse::<usize>() {
+                Ok(index) => item.get(index),
+                Err(_) => item.get(*key),
+            }?;
+            span = item.span().or(span);
+        }
+        span
+    }
+
+    /// 1-based line and column of a byte offset
+    fn position(&self, offset: usize) -> (usize, usize) {
+        let before = &self.text[..offset.min(self.text.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.len() - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+        (line, column)
+    }
+}
+
+/// An error pointing at `span` of the source, the way rustc prints them
+fn render(source: &Source, span: Option<Range<usize>>, message: &str) -> String {
+    let mut out = format!("{}: {}\n", "error".red().bold(), message.bold());
+    let path = env::current_dir()
+        .ok()
+        .and_then(|dir| source.path.strip_prefix(dir).ok())
+        .unwrap_or(&source.path)
+        .display();
+    let Some(span) = span else {
+        out.push_str(&format!("  {} {path}\n", "-->".blue().bold()));
+        return out;
+    };
+    let (line, column) = source.position(span.start);
+    out.push_str(&format!(
+        "  {} {path}:{line}:{column}\n",
+        "-->".blue().bold()
+    ));
+    if let Some(text) = source.text.lines().nth(line - 1) {
+        let gutter = " ".repeat(line.to_string().len());
+        let width = (span.end - span.start)
+            .min(text.len().saturating_sub(column - 1))
+            .max(1);
+        out.push_str(&format!("{gutter} {}\n", "|".blue().bold()));
+        out.push_str(&format!("{} {text}\n", format!("{line} |").blue().bold()));
+        out.push_str(&format!(
+            "{gutter} {} {}{}\n",
+            "|".blue().bold(),
+            " ".repeat(column - 1),
+            "^".repeat(width).red().bold()
+        ));
+    }
+    out
+}
+
+#[derive(Default)]
+struct Report {
+    /// Every error so far, rendered
+    errors: Vec<String>,
+}
+
+impl Report {
+    fn error(&mut self, source: &Source, span: Option<Range<usize>>, message: impl AsRef<str>) {
+        let error = render(source, span, message.as_ref());
+        eprintln!("{error}");
+        self.errors.push(error);
+    }
+
+    fn at(&mut self, source: &Source, keys: &[&str], message: impl AsRef<str>) {
+        self.error(source, source.span(keys), message);
+    }
+}
+
+/// Whether `subdomain` can be used as a single DNS label
+pub fn is_dns_label(subdomain: &str) -> bool {
+    (1..=63).contains(&subdomain.len())
+        && !subdomain.starts_with('-')
+        && !subdomain.ends_with('-')
+        && subdomain
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// Which challenge exposes each tcp port and subdomain, to catch the ones exposed twice
+#[derive(Default)]
+pub struct Exposed {
+    pub tcp: BTreeMap<u32, String>,
+    pub http: BTreeMap<String, String>,
+}
+
+impl Exposed {
+    /// Record what `chall` exposes. Returns the expose name, key and error for everything some
+    /// earlier challenge already has.
+    pub fn claim(&mut self, chall: &Challenge) -> Vec<(String, &'static str, String)> {
+        let mut taken = Vec::new();
+        for (name, expose) in &chall.expose {
+            let owner = format!("{} (expose.{name})", chall.id);
+            match expose {
+                Expose::Tcp { tcp: port, .. } => match self.tcp.get(port) {
+                    Some(other) => taken.push((
+                        name.clone(),
+                        "tcp",
+                        format!("tcp port {port} is already used by {other}"),
+                    )),
+                    None => {
+                        self.tcp.insert(*port, owner);
+                    }
+                },
+                Expose::Http {
+                    http: subdomain, ..
+                } => match self.http.get(subdomain) {
+                    Some(other) => taken.push((
+                        name.clone(),
+                        "http",
+                        format!("subdomain {subdomain} is already used by {other}"),
+                    )),
+                    None => {
+                        self.http.insert(subdomain.clone(), owner);
+                    }
+                },
+            }
+        }
+        taken
+    }
+}
+
+/// Checks that only need the challenge itself, and the backend it's deployed to
+fn check_chall(
+    config: &Config,
+    backend: &dyn DeployBackend,
+    report: &mut Report,
+    source: &Source,
+    chall: &Challenge,
+) {
+    let dir = &chall.dir;
+
+    if let Flag::File { file } = &chall.flag {
+        if !dir.join(file).is_file() {
+            report.at(
+                source,
+                &["flag", "file"],
+                format!("flag file {} doesn't exist", file.display()),
+            );
+        }
+    }
+
+    for (i, attachment) in chall.provide.iter().flatten().enumerate() {
+        let index = i.to_string();
+        let (path, is_dir) = match attachment {
+            Attachment::File(file) => (file, false),
+            Attachment::Named { file, .. } => (file, false),
+            Attachment::Folder { dir, .. } => (dir, true),
+        };
+        let full = dir.join(path);
+        if is_dir && !full.is_dir() {
+            report.at(
+                source,
+                &["provide", &index],
+                format!("provided directory {} doesn't exist", path.display()),
+            );
+        } else if !is_dir && !full.is_file() {
+            report.at(
+                source,
+                &["provide", &index],
+                format!("provided file {} doesn't exist", path.display()),
+            );
+        }
+    }
+
+    for (name, container) in &chall.containers {
+        let context = container.image.build_context(dir);
+        if !context.dir.is_dir() {
+            report.at(
+                source,
+                &["containers", name, "build"],
+                format!(
+                    "build context {} doesn't exist",
+                    context
+                        .dir
+                        .strip_prefix(dir)
+                        .unwrap_or(&context.dir)
+                        .display()
+                ),
+            );
+        } else if !context.dockerfile.is_file() {
+            report.at(
+                source,
+                &["containers", name],
+                format!(
+                    "no dockerfile at {}",
+                    context
+                        .dockerfile
+                        .strip_prefix(dir)
+                        .unwrap_or(&context.dockerfile)
+                        .display()
+                ),
+            );
+        }
+        if let (backend::Config::Fly(_), Some(mem)) = (&config.backend, container.limits.mem) {
+            if !mem.is_multiple_of(256) {
+                report.at(
+                    source,
+                    &["containers", name, "limits", "mem"],
+                    format!("mem has to be a multiple of 256 on fly, got {mem}"),
+                );
+            }
+        }
+    }
+
+    for (name, expose) in &chall.expose {
+        if !chall.containers.contains_key(name) {
+            report.at(
+                source,
+                &["expose", name],
+                format!("expose.{name} doesn't match any container"),
+            );
+        }
+        match expose {
+            Expose::Http { http, .. } if !is_dns_label(http) => report.at(
+                source,
+                &["expose", name, "http"],
+                format!("subdomain {http} isn't a valid DNS label (a-z, 0-9 and -)"),
+            ),
+            Expose::Tcp { tcp, .. } => {
+                if let Err(e) = backend.check_tcp_port(*tcp) {
+                    report.at(source, &["expose", name, "tcp"], e.to_string());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // placeholders are filled in from expose when the description is uploaded
+    let mut rest = chall.description.as_str();
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        let placeholder = &rest[start..start + end + 2];
+        rest = &rest[start + end + 2..];
+        let inner = &placeholder[2..placeholder.len() - 2];
+        let known = inner
+            .strip_suffix(".url")
+            .is_some_and(|name| chall.expose.contains_key(name));
+        if !known {
+            let span = source
+                .text
+                .find(placeholder)
+                .map(|offset| offset..offset + placeholder.len())
+                .or(source.span(&["description"]));
+            report.error(
+                source,
+                span,
+                format!("{placeholder} doesn't match anything in expose"),
+            );
+        }
+    }
+}
+
+/// Parse the category.toml of `folder`, reporting it and falling back to no defaults if it's broken
+fn check_category(config: &Config, report: &mut Report, folder: &str) -> Result<Category> {
+    let path = config.chall_root.join(folder).join("category.toml");
+    if !path.is_file() {
+        return Ok(Category::default().inherit(config));
+    }
+    let source = Source::load(path)?;
+    let category = toml::from_str(&source.text).unwrap_or_else(|e: toml::de::Error| {
+        report.error(&source, e.span(), e.message());
+        Category::default()
+    });
+    Ok(category.inherit(config))
+}
+
+pub fn command(config: Config) -> Result<()> {
+    let backend = backend::from_config(&config.backend);
+    let mut report = Report::default();
+    let mut checked = Vec::new();
+    let mut categories = BTreeMap::new();
+    let dirs = challenge::get_chall_paths(&config.chall_root, &config.ignore)?;
+    for dir in dirs {
+        let source = Source::load(dir.join("challenge.toml"))?;
+        let id = Challenge::id_from_path(&config.chall_root, &dir)?;
+        let folder = challenge::category_folder(&id).to_string();
+        if !categories.contains_key(&folder) {
+            let category = check_category(&config, &mut report, &folder)?;
+            categories.insert(folder.clone(), category);
+        }
+        match Challenge::from_toml(id, dir, &categories[&folder], &source.text) {
+            Ok(chall) => {
+                check_chall(&config, backend.as_ref(), &mut report, &source, &chall);
+                checked.push((source, chall));
+            }
+            Err(e) => report.error(&source, e.span(), e.message()),
+        }
+    }
+
+    // ids name the containers and the rCTF challenge, so they can't be shared either
+    let mut ids = BTreeMap::new();
+    for (source, chall) in &checked {
+        match ids.get(&chall.id) {
+            Some(other) => report.at(
+                source,
+                &["id"],
+                format!("id {} is already used by {other}", chall.id),
+            ),
+            None => {
+                ids.insert(chall.id.clone(), chall.dir.display().to_string());
+            }
+        }
+    }
+
+    // ports and subdomains have to be unique across the whole CTF
+    let mut exposed = Exposed::default();
+    for (source, chall) in &checked {
+        for (name, key, message) in exposed.claim(chall) {
+            report.at(source, &["expose", &name, key], message);
+        }
+    }
+
+    match report.errors.len() {
+        0 => (),
+        1 => return Err(anyhow!("found 1 problem")),
+        n => return Err(anyhow!("found {n} problems")),
+    }
+    match checked.len() {
+        1 => println!("1 challenge looks good"),
+        n => println!("{n} challenges look good"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::scratch_dir;
+
+    const TOML: &str = r#"name = "Baby"
+author = "bear"
+description = "go to {{web.url}} or {{api.url}}"
+flag = { file = "flag.txt" }
+provide = ["a.txt", "b.txt"]
+
+[containers.main]
+build = "src"
+
+[expose.web]
+target = 80
+http = "baby"
+"#;
+
+    /// challenge.toml with `text` in its own directory
+    fn source(text: &str) -> Source {
+        colored::control::set_override(false);
+        let dir = scratch_dir("check");
+        fs::write(dir.join("challenge.toml"), text).unwrap();
+        Source::load(dir.join("challenge.toml")).unwrap()
+    }
+
+    fn spanned<'a>(source: &'a Source, keys: &[&str]) -> Option<&'a str> {
+        source.span(keys).map(|span| &source.text[span])
+    }
+
+    #[test]
+    fn spans_of_keys() {
+        let source = source(TOML);
+        assert_eq!(spanned(&source, &["flag", "file"]), Some("\"flag.txt\""));
+        assert_eq!(spanned(&source, &["provide", "1"]), Some("\"b.txt\""));
+        assert_eq!(
+            spanned(&source, &["containers", "main", "build"]),
+            Some("\"src\"")
+        );
+        assert_eq!(
+            spanned(&source, &["expose", "web", "http"]),
+            Some("\"baby\"")
+        );
+        // `expose` is only implied by [expose.web], so it has no span of its own
+        assert_eq!(spanned(&source, &["expose"]), None);
+        assert_eq!(spanned(&source, &["provide", "2"]), None);
+        assert_eq!(source.position(0), (1, 1));
+        assert_eq!(source.position(TOML.find("b.txt").unwrap()), (5, 22));
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        let source = source(TOML);
+        let dir = source.path.parent().unwrap().to_path_buf();
+        fs::write(dir.join("a.txt"), "").unwrap();
+        let config: Config = toml::from_str(
+            "hostname = \"example.com\"\nchall_root = \".\"\n[backend]\ntype = \"local\"\n",
+        )
+        .unwrap();
+        let chall = Challenge::from_toml(
+            "web/baby".to_string(),
+            dir,
+            &Category::default(),
+            &source.text,
+        )
+        .unwrap();
+        let backend = backend::from_config(&config.backend);
+        let mut report = Report::default();
+        check_chall(&config, backend.as_ref(), &mut report, &source, &chall);
+
+        let path = source.path.display();
+        assert_eq!(
+            report.errors,
+            [
+                format!("error: flag file flag.txt doesn't exist\n  --> {path}:4:17\n  |\n4 | flag = {{ file = \"flag.txt\" }}\n  |                 ^^^^^^^^^^\n"),
+                format!("error: provided file b.txt doesn't exist\n  --> {path}:5:21\n  |\n5 | provide = [\"a.txt\", \"b.txt\"]\n  |                     ^^^^^^^\n"),
+                format!("error: build context src doesn't exist\n  --> {path}:8:9\n  |\n8 | build = \"src\"\n  |         ^^^^^\n"),
+                format!("error: expose.web doesn't match any container\n  --> {path}:10:1\n   |\n10 | [expose.web]\n   | ^^^^^^^^^^^^\n"),
+                format!("error: {{{{api.url}}}} doesn't match anything in expose\n  --> {path}:3:37\n  |\n3 | description = \"go to {{{{web.url}}}} or {{{{api.url}}}}\"\n  |                                     ^^^^^^^^^^^\n"),
+            ]
+        );
+    }
+
+    #[test]
+    fn tcp_ports_are_checked_against_the_backend() {
+        let source = source("name = \"Baby\"\nauthor = \"bear\"\ndescription = \"\"\nflag = \"flag{x}\"\n\n[containers.main]\nbuild = \".\"\n\n[expose.main]\ntarget = 5000\ntcp = 1337\n");
+        let dir = source.path.parent().unwrap().to_path_buf();
+        fs::write(dir.join("Dockerfile"), "").unwrap();
+        let chall = Challenge::from_toml(
+            "pwn/baby".to_string(),
+            dir,
+            &Category::default(),
+            &source.text,
+        )
+        .unwrap();
+        let config: Config = toml::from_str(
+            "hostname = \"example.com\"\nchall_root = \".\"\n[backend]\ntype = \"kubernetes\"\napi_server = \"http://localhost:8001\"\nregistry = \"registry\"\ntcp_service_type = \"NodePort\"\n",
+        )
+        .unwrap();
+        let backend = backend::from_config(&config.backend);
+        let mut report = Report::default();
+        check_chall(&config, backend.as_ref(), &mut report, &source, &chall);
+
+        let path = source.path.display();
+        assert_eq!(
+            report.errors,
+            [format!("error: tcp port 1337 can't be used with NodePort services, those only take ports 30000 to 32767\n  --> {path}:11:7\n   |\n11 | tcp = 1337\n   |       ^^^^\n")]
+        );
+    }
+
+    #[test]
+    fn example_repo_is_fine() {
+        let mut config: Config =
+            toml::from_str("hostname = \"example.com\"\n[backend]\ntype = \"fly\"\norg = \"org\"\napp_name = \"app\"\n")
+                .unwrap();
+        config.chall_root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("example_repo");
+        command(config).unwrap();
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_value() {
+        let source =
+            source("name = \"Baby\"\nauthor = \"bear\"\npoints = { min = 500, max = 100 }\n");
+        let e = Challenge::from_toml(
+            "web/baby".to_string(),
+            source.path.parent().unwrap().to_path_buf(),
+            &Category::default(),
+            &source.text,
+        )
+        .unwrap_err();
+        let rendered = render(&source, e.span(), e.message());
+        let path = source.path.display();
+        assert_eq!(
+            rendered,
+            format!("error: min (500) is more than max (100)\n  --> {path}:3:10\n  |\n3 | points = {{ min = 500, max = 100 }}\n  |          ^^^^^^^^^^^^^^^^^^^^^^^^\n")
+        );
+    }
+}
@@ -1,18 +1,101 @@
 use std::collections::HashMap;
 
 use crate::{
-    challenge::{Challenge, Expose},
-    fly, rctf, Config, DOCKER,
+    backend::{self, Machine, MachineSpec},
+    challenge::{Challenge, Container, Expose},
+    context,
+    ingress::{self, Route},
+    scoreboard,
+    state::{self, ContainerState, State},
+    Config,
 };
 use anyhow::{anyhow, Result};
-use bollard::{auth::DockerCredentials, image::BuildImageOptions};
-use futures::stream::StreamExt;
-use serde_json::json;
+
+/// What the machine of a container should look like, without the image, which is only known
+/// once it's pushed
+pub fn machine_spec(chall: &Challenge, name: &str, container: &Container) -> MachineSpec {
+    let mut ports = container.ports.clone().unwrap_or_default();
+    if let Some(Expose::Tcp { target, .. } | Expose::Http { target, .. }) = chall.expose.get(name) {
+        ports.push(*target);
+    }
+    MachineSpec {
+        ports,
+        env: container.env.clone(),
+        cpus: container.limits.cpu,
+        memory_mb: container.limits.mem,
+        ..Default::default()
+    }
+}
+
+/// What the local docker daemon knows about the image of a container.
+#[derive(Default)]
+pub struct LocalImage {
+    /// Hash of the context the image was built from, see [`context::image_hash`]
+    pub hash: Option<String>,
+    /// Digest of the local image behind the last deployed tag, see [`state::image_digest`]. Only
+    /// looked up for states from before the deployed digest was recorded.
+    pub digest: Option<String>,
+}
+
+impl LocalImage {
+    pub async fn inspect(id: &str, previous: Option<&ContainerState>) -> LocalImage {
+        LocalImage {
+            hash: context::image_hash(id).await,
+            digest: match previous {
+                Some(previous) if previous.digest.is_none() => {
+                    state::image_digest(id, &previous.image).await
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
+/// What differs between the machine a container runs on and what deploying it now would give:
+/// the local image, the machine's config, or the image the machine actually runs. Nothing
+/// means deploying it can be skipped.
+pub fn changes(
+    spec: &MachineSpec,
+    machine: &Machine,
+    previous: Option<&ContainerState>,
+    local: &LocalImage,
+) -> Vec<String> {
+    let Some(previous) = previous else {
+        return vec!["not in state".to_string()];
+    };
+    let mut changes = Vec::new();
+    if local.hash.is_none() || previous.hash != local.hash {
+        changes.push("image".to_string());
+    }
+    if previous.config != Some(spec.fingerprint()) {
+        changes.push("config".to_string());
+    }
+    let deployed = previous.digest.as_ref().or(local.digest.as_ref());
+    if previous.image != machine.image
+        || machine.digest.is_none()
+        || machine.digest.as_ref() != deployed
+    {
+        changes.push("running image".to_string());
+    }
+    changes
+}
 
 pub async fn command(config: Config, selected: Option<Vec<String>>) -> Result<()> {
-    fly::ensure_app(&config.fly)?;
-    let app_name = &config.fly.app_name;
-    let challs: Vec<Challenge> = Challenge::get_all(&config.chall_root)?;
+    let backend = backend::from_config(&config.backend);
+    // before anything is deployed, so a missing token doesn't stop a deploy halfway
+    let scoreboards = scoreboard::from_config(&config)?;
+    // only parse what is being deployed, so a broken challenge elsewhere doesn't block a hotfix
+    let challs: Vec<Challenge> = match &selected {
+        Some(selected) => Challenge::get_some(&config, selected.clone())?,
+        None => Challenge::get_all(&config)?,
+    };
+    if challs.is_empty() {
+        println!("Nothing to deploy");
+        return Ok(());
+    }
+    let _lock = State::lock(&config)?;
+    let mut state = State::load(&config)?;
+    backend.ensure().await?;
     match challs.len() {
         1 => println!("Deploying {}", challs[0].id),
         2 => println!("Deploying {} and {}", challs[0].id, challs[1].id),
@@ -29,321 +112,115 @@ pub async fn command(config: Config, selected: Option<Vec<String>>) -> Result<()
             )
         }
     }
-    let repo = &format!("registry.fly.io/{}", app_name);
-    let mut machines = fly::list_machines(app_name)?
-        .into_iter()
+    for chall in &challs {
+        for expose in chall.expose.values() {
+            if let Expose::Tcp { tcp, .. } = expose {
+                backend
+                    .check_tcp_port(*tcp)
+                    .map_err(|e| anyhow!("{}: {e}", chall.id))?;
+            }
+        }
+    }
+    let machine_list = backend.list().await?;
+    let machines = machine_list
+        .iter()
         .map(|machine| (machine.name.clone(), machine))
-        .collect::<HashMap<String, fly::MachineInfo>>();
-    println!("{:#?}", machines);
-
-    let mut http_expose: HashMap<String, String> = HashMap::new();
-    let mut tcp_expose: HashMap<u32, (String, String)> = HashMap::new();
+        .collect::<HashMap<String, &Machine>>();
+
+    // when deploying everything the routes are rebuilt from scratch, otherwise keep whatever the
+    // ingress is serving for the challenges that aren't being touched
+    let mut routes = match selected {
+        Some(_) => {
+            let mut routes = backend
+                .current_routes(&config, machines.get(ingress::NAME).copied(), &machine_list)
+                .await?;
+            for chall in &challs {
+                for name in chall.containers.keys() {
+                    routes.remove_machine(&chall.container_id(name));
+                }
+            }
+            routes
+        }
+        None => ingress::Routes::default(),
+    };
     for chall in &challs {
+        let chall_state = state.challenges.entry(chall.id.clone()).or_default();
         for (name, container) in &chall.containers {
-            let id = chall.container_id(&name);
-            let machine_id = if selected.is_none() || selected.as_ref().unwrap().contains(&chall.id)
-            {
-                chall.push(&repo, name).await?;
-                // println!(
-                //     "{:#?}",
-                //     DOCKER.inspect_image(&format!("{repo}:{id}")).await?
-                // );
-                if container.limits.mem.unwrap_or_default() % 256 != 0 {
-                    Err(anyhow!("Memory must be a multiple of 256."))?;
-                }
-                let machine_config = fly::MachineConfig {
-                    image: format!("{repo}:{id}"),
-                    guest: Some(fly::AllocatedResources {
-                        cpu_kind: "shared".to_string(),
-                        cpus: container.limits.cpu,
-                        memory_mb: container.limits.mem,
-                        kernel_args: None,
-                    }),
-                    env: container.env.clone(),
-                    ..Default::default()
-                };
-
+            let id = chall.container_id(name);
+            let mut spec = machine_spec(chall, name, container);
+            let previous = chall_state.containers.get(name);
+            let local = LocalImage::inspect(&id, previous).await;
+            let fingerprint = spec.fingerprint();
+            let unchanged = match machines.get(&id) {
+                Some(machine) => changes(&spec, machine, previous, &local).is_empty(),
+                None => false,
+            };
+            let machine = if let (true, Some(machine)) = (unchanged, machines.get(&id)) {
+                println!("{id} is unchanged, skipping");
+                (*machine).clone()
+            } else {
+                spec.image = backend.push_image(&id).await?;
                 let machine = if let Some(machine) = machines.get(&id) {
-                    fly::update_machine(app_name, &machine.id, &machine_config)?
+                    backend.update(machine, &spec).await?
                 } else {
-                    fly::create_machine(app_name, &id, &machine_config)?
+                    backend.create(&id, &spec).await?
                 };
-                machine.id
-            } else {
-                if let Some(machine) = machines.get(&id) {
-                    machine.id.clone()
-                } else {
-                    continue;
-                }
+                chall_state.containers.insert(
+                    name.clone(),
+                    ContainerState {
+                        machine_id: machine.id.clone(),
+                        digest: state::image_digest(&id, &spec.image).await,
+                        image: spec.image,
+                        hash: local.hash,
+                        config: Some(fingerprint),
+                        expose: chall.expose.get(name).cloned(),
+                    },
+                );
+                machine
             };
-            let internal_url = format!("{machine_id}.vm.{}.internal", config.fly.app_name);
             if let Some(expose) = chall.expose.get(name) {
                 match expose {
                     Expose::Tcp { target, tcp } => {
-                        tcp_expose.insert(*tcp, (id.clone(), format!("{internal_url}:{target}")));
+                        routes.tcp.insert(*tcp, Route::new(&machine, *target));
                     }
                     Expose::Http { target, http } => {
-                        http_expose.insert(http.clone(), format!("{internal_url}:{target}"));
+                        routes
+                            .http
+                            .insert(http.clone(), Route::new(&machine, *target));
                     }
                 }
             }
         }
+        state.save(&config)?;
     }
 
-    if !machines.contains_key("ingress") {
-        println!("Caddy server not found. Building and deploying.");
-        let machine = build_ingress(app_name, &repo).await?;
-        println!("Waiting on ingress to start");
-        println!("{:?}", fly::wait_for_machine(app_name, &machine.id));
-        println!("Ingress Created");
-        machines.insert("ingress".to_string(), machine);
-    }
-
-    update_ingress(
-        &config,
-        &machines.get("ingress").unwrap().id,
-        &repo,
-        http_expose,
-        tcp_expose,
-    )
-    .await?;
-
-    if let Some(rctf) = &config.rctf {
-        for chall in challs
-            .iter()
-            .filter(|c| match selected.as_ref().map(|s| s.contains(&c.id)) {
-                Some(v) => v,
-                None => true,
-            })
-            .collect::<Vec<&Challenge>>()
-        {
-            if let Some(true) = chall.hidden {
-            } else {
-                rctf::update_chall(&config, chall).await?;
+    backend
+        .ingress(&config, machines.get(ingress::NAME).copied(), &routes)
+        .await?;
+
+    for platform in &scoreboards {
+        for chall in &challs {
+            let chall_state = state.challenges.entry(chall.id.clone()).or_default();
+            let entry = chall_state
+                .scoreboards
+                .entry(platform.name().to_string())
+                .or_default();
+            let changes = platform.upsert(&config, chall, entry).await?;
+            match (changes.is_empty(), entry.id.is_empty()) {
+                (false, _) => println!(
+                    "Updated {} on {} ({})",
+                    chall.id,
+                    platform.name(),
+                    changes.join(", ")
+                ),
+                (true, false) => println!("{} is up to date on {}", chall.id, platform.name()),
+                (true, true) => (),
             }
-        }
-    }
-    return Ok(());
-}
-
-fn merge(a: &mut serde_json::Value, b: &serde_json::Value) {
-    match (a, b) {
-        (serde_json::Value::Object(a), serde_json::Value::Object(b)) => {
-            for (k, v) in b {
-                merge(a.entry(k.clone()).or_insert(serde_json::Value::Null), v);
+            if entry.id.is_empty() {
+                chall_state.scoreboards.remove(platform.name());
             }
+            state.save(&config)?;
         }
-        (a, b) => *a = b.clone(),
-    }
-}
-// TODO merge these two functions into one
-async fn build_ingress(app_name: &str, repo: &str) -> Result<fly::MachineInfo> {
-    let tag = format!("{repo}:ingress");
-    let ingress_tar = include_bytes!("../../caddy.tar.gz").to_vec();
-    let mut build = DOCKER.build_image(
-        BuildImageOptions {
-            dockerfile: "Dockerfile",
-            t: &tag,
-            rm: true,
-            ..Default::default()
-        },
-        None,
-        Some(ingress_tar.into()),
-    );
-
-    while let Some(build_step) = build.next().await {
-        if let Some(stream) = build_step?.stream {
-            println!("{stream}")
-        }
-    }
-
-    let mut push = DOCKER.push_image::<String>(
-        &tag,
-        None,
-        Some(DockerCredentials {
-            username: Some("x".to_string()),
-            password: Some(fly::FLY_API_TOKEN.clone()),
-            ..Default::default()
-        }),
-    );
-
-    while let Some(push_step) = push.next().await {
-        println!("{:#?}", push_step);
     }
-
-    let machine = fly::create_machine(
-        app_name,
-        "ingress",
-        &fly::MachineConfig {
-            image: tag,
-            services: None,
-            guest: Some(fly::AllocatedResources {
-                cpu_kind: "shared".to_string(),
-                cpus: Some(4),
-                memory_mb: Some(1024),
-                kernel_args: None,
-            }),
-            ..Default::default()
-        },
-    )?;
-
-    Ok(machine)
-}
-
-async fn update_ingress(
-    config: &Config,
-    ingress_id: &str,
-    repo: &str,
-    http_expose: HashMap<String, String>,
-    tcp_expose: HashMap<u32, (String, String)>,
-) -> Result<()> {
-    let tag = format!("{repo}:ingress");
-    let mut services = Vec::new();
-    for (port, _) in &tcp_expose {
-        services.push(fly::MachineService {
-            ports: vec![fly::MachinePort {
-                port: Some(*port),
-                ..Default::default()
-            }],
-            protocol: "tcp".to_string(),
-            internal_port: *port,
-
-            concurrency: Some(fly::MachineConcurrency {
-                soft_limit: 500,
-                hard_limit: 500,
-            }),
-        });
-    }
-
-    services.push(fly::MachineService {
-        ports: vec![fly::MachinePort {
-            port: Some(80),
-            ..Default::default()
-        }],
-        protocol: "tcp".to_string(),
-        internal_port: 80,
-        concurrency: Some(fly::MachineConcurrency {
-            soft_limit: 3000,
-            hard_limit: 3000,
-        }),
-    });
-
-    services.push(fly::MachineService {
-        ports: vec![fly::MachinePort {
-            port: Some(443),
-            ..Default::default()
-        }],
-        protocol: "tcp".to_string(),
-        internal_port: 443,
-        concurrency: Some(fly::MachineConcurrency {
-            soft_limit: 3000,
-            hard_limit: 3000,
-        }),
-    });
-
-    println!(
-        "{:#?}",
-        fly::update_machine(
-            &config.fly.app_name,
-            ingress_id,
-            &fly::MachineConfig {
-                image: tag,
-                services: Some(services.into()),
-                guest: Some(fly::AllocatedResources {
-                    cpu_kind: "shared".to_string(),
-                    cpus: Some(4),
-                    memory_mb: Some(1024),
-                    kernel_args: None,
-                }),
-                ..Default::default()
-            },
-        )?
-    );
-
-    println!("Waiting on ingress to start");
-    fly::wait_for_machine(&config.fly.app_name, &ingress_id)?;
-    println!("Ingress Updated");
-    let mut http_expose_json = Vec::with_capacity(http_expose.len());
-    for (sub, target) in http_expose {
-        http_expose_json.push(json!({
-            "match": [{
-                "host": [format!("{sub}.{}", config.hostname)],
-            }],
-            "handle": [{
-                "handler": "reverse_proxy",
-                "upstreams": [{
-                    "dial": target,
-                }]
-            }]
-        }));
-    }
-
-    http_expose_json.push(json!({
-        "handle": [{
-            "handler": "static_response",
-            "status_code": 404,
-            "body": "Not Found",
-        }]
-    }));
-
-    let mut tcp_expose_json = HashMap::with_capacity(tcp_expose.len());
-    for (port, (name, target)) in tcp_expose {
-        tcp_expose_json.insert(
-            name,
-            json!({
-                "listen": [format!("0.0.0.0:{port}")],
-                "routes": [{
-                    "handle": [
-                        {
-                            "handler": "proxy",
-                            "upstreams": [{ "dial": [target] }]
-                        }
-                    ]
-                }]
-            }),
-        );
-    }
-
-    // okay now we do caddy stuffing
-    let mut caddy = json!({
-        "apps": {
-            "layer4": {
-                "servers": tcp_expose_json,
-            },
-            "http":{
-                "servers": {
-                    "bear-cds-http": {
-                        "listen": [":80"],
-                        "routes": [{
-                            "handle": [{
-                                "handler": "subroute",
-                                "routes": http_expose_json,
-                            }],
-                            "match": [{
-                                "host": [format!("*.{}", config.hostname)],
-                            }]
-                        }],
-                    }
-                }
-            }
-        }
-    });
-    merge(&mut caddy, &config.caddy);
-    println!("{}", caddy);
-    let mut buf = Vec::new();
-    fly::execute_command(
-        &config.fly.app_name,
-        ingress_id,
-        vec![
-            "curl",
-            "localhost:2019/load",
-            "-H",
-            "Content-Type: application/json",
-            "-d",
-            &caddy.to_string(),
-        ],
-    )?
-    .read_to_end(&mut buf)?;
-    println!("{:?}", String::from_utf8(buf));
     Ok(())
 }
@@ -0,0 +1,272 @@
+use std::io::{self, Write};
+
+use crate::{
+    backend::{self, DeployBackend, Machine},
+    challenge::{Challenge, Expose},
+    ingress, print_error,
+    scoreboard::{self, RemoteChall, ScoreboardPlatform},
+    state::State,
+    Config,
+};
+use anyhow::{anyhow, Result};
+use colored::*;
+
+pub fn confirm() -> Result<bool> {
+    print!("Continue? [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Scoreboard challenges to delete, with the platform they are on
+type Remote<'a> = Vec<(&'a dyn ScoreboardPlatform, RemoteChall)>;
+
+/// Delete the given machines and scoreboard challenges, and stop routing traffic to the machines.
+async fn remove(
+    config: &Config,
+    backend: &dyn DeployBackend,
+    machines: &[Machine],
+    doomed: Vec<&Machine>,
+    remote: Remote<'_>,
+    yes: bool,
+) -> Result<()> {
+    let _lock = State::lock(config)?;
+    let mut state = State::load(config)?;
+    if doomed.is_empty() && remote.is_empty() {
+        println!("Nothing to remove.");
+        return Ok(());
+    }
+    for machine in &doomed {
+        let exposed = state
+            .challenges
+            .values()
+            .flat_map(|chall| chall.containers.values())
+            .find(|container| container.machine_id == machine.id)
+            .and_then(|container| container.expose.as_ref());
+        let serving = match exposed {
+            Some(Expose::Http { http, .. }) => format!(" (serving {http}.{})", config.hostname),
+            Some(Expose::Tcp { tcp, .. }) => format!(" (serving tcp {tcp})"),
+            None => String::new(),
+        };
+        println!("  {} machine {}{serving}", "-".red().bold(), machine.name);
+    }
+    for (platform, chall) in &remote {
+        println!(
+            "  {} {} challenge {}",
+            "-".red().bold(),
+            platform.name(),
+            chall.label
+        );
+    }
+    if !yes && !confirm()? {
+        return Err(anyhow!("Aborted"));
+    }
+
+    let ingress = machines.iter().find(|m| m.name == ingress::NAME);
+    let mut routes = backend.current_routes(config, ingress, machines).await?;
+    let before = routes.http.len() + routes.tcp.len();
+    for machine in &doomed {
+        routes.remove_machine(&machine.name);
+    }
+    if routes.http.len() + routes.tcp.len() != before {
+        backend.ingress(config, ingress, &routes).await?;
+    }
+
+    for machine in doomed {
+        backend.destroy(machine).await?;
+        state.remove_machine(&machine.id);
+        state.save(config)?;
+        println!("Destroyed {}", machine.name);
+    }
+    for (platform, remote) in remote {
+        platform.delete(&remote.state).await?;
+        for chall in state.challenges.values_mut() {
+            chall
+                .scoreboards
+                .retain(|name, s| name != platform.name() || s.id != remote.state.id);
+        }
+        state.save(config)?;
+        println!("Deleted {} from {}", remote.label, platform.name());
+    }
+    // drop challenges with nothing left deployed
+    state
+        .challenges
+        .retain(|_, chall| !chall.containers.is_empty() || !chall.scoreboards.is_empty());
+    state.save(config)?;
+    Ok(())
+}
+
+/// The machines and scoreboard challenges of `chall`, a challenge id or its path relative to the
+/// challenge root. Challenges that are gone from the repo are found by what was deployed for them.
+fn find<'a>(
+    config: &Config,
+    state: &State,
+    machines: &'a [Machine],
+    platforms: &'a [Box<dyn ScoreboardPlatform>],
+    chall: &str,
+) -> Result<(Vec<&'a Machine>, Remote<'a>)> {
+    let (id, found) = match Challenge::find_dir(config, chall)? {
+        Some(dir) => {
+            let chall = Challenge::parse(config, dir)?;
+            let found = machines
+                .iter()
+                .filter(|m| {
+                    chall
+                        .containers
+                        .keys()
+                        .any(|name| chall.container_id(name) == m.name)
+                })
+                .collect::<Vec<&Machine>>();
+            (chall.id, found)
+        }
+        None => {
+            let deployed = state
+                .challenges
+                .get(chall)
+                .map(|chall| chall.containers.values().collect::<Vec<_>>())
+                .unwrap_or_default();
+            let found = machines
+                .iter()
+                .filter(|m| deployed.iter().any(|c| c.machine_id == m.id))
+                .collect();
+            (chall.to_string(), found)
+        }
+    };
+    if found.is_empty() {
+        print_error!("No machines found for {id}");
+    }
+    let mut remote = Vec::new();
+    for platform in platforms {
+        let recorded = state
+            .challenges
+            .get(&id)
+            .and_then(|chall| chall.scoreboards.get(platform.name()))
+            .cloned();
+        if let Some(chall_state) = recorded.or(platform.default_state(&id)) {
+            remote.push((
+                platform.as_ref(),
+                RemoteChall {
+                    label: chall_state.id.clone(),
+                    state: chall_state,
+                },
+            ));
+        }
+    }
+    Ok((found, remote))
+}
+
+pub async fn destroy(config: Config, challs: Vec<String>, yes: bool) -> Result<()> {
+    let backend = backend::from_config(&config.backend);
+    let platforms = scoreboard::from_config(&config)?;
+    let machines = backend.list().await?;
+    let state = State::load(&config)?;
+    let mut doomed = Vec::new();
+    let mut remote = Vec::new();
+    for chall in challs {
+        let (found, found_remote) = find(
+            &config,
+            &state,
+            &machines,
+            &platforms,
+            chall.trim_end_matches('/'),
+        )?;
+        doomed.extend(found);
+        remote.extend(found_remote);
+    }
+    remove(&config, backend.as_ref(), &machines, doomed, remote, yes).await
+}
+
+/// Remove every machine and scoreboard challenge that no longer has a challenge in the repo.
+pub async fn prune(config: Config, yes: bool) -> Result<()> {
+    let backend = backend::from_config(&config.backend);
+    let challs = Challenge::get_all(&config)?;
+    let machines = backend.list().await?;
+    let doomed = machines
+        .iter()
+        .filter(|m| {
+            m.name != ingress::NAME
+                && !challs.iter().any(|chall| {
+                    chall
+                        .containers
+                        .keys()
+                        .any(|name| chall.container_id(name) == m.name)
+                })
+        })
+        .collect::<Vec<&Machine>>();
+    let platforms = scoreboard::from_config(&config)?;
+    let state = State::load(&config)?;
+    let mut remote = Vec::new();
+    for platform in &platforms {
+        for chall in platform.orphans(&challs, &state).await? {
+            remote.push((platform.as_ref(), chall));
+        }
+    }
+    remove(&config, backend.as_ref(), &machines, doomed, remote, yes).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mock::{scratch_dir, FakeScoreboard},
+        state::ScoreboardState,
+    };
+    use std::fs;
+
+    fn machine(id: &str, name: &str) -> Machine {
+        Machine {
+            id: id.to_string(),
+            name: name.to_string(),
+            state: "started".to_string(),
+            image: String::new(),
+            digest: None,
+            address: String::new(),
+        }
+    }
+
+    #[test]
+    fn challenges_with_their_own_id_are_found_by_path() {
+        let root = scratch_dir("destroy");
+        let dir = root.join("web/elements");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("challenge.toml"),
+            "id = \"elements\"\nname = \"Elements\"\nauthor = \"bear\"\ndescription = \"\"\nflag = \"flag{x}\"\n[containers.main]\nbuild = \".\"\n",
+        )
+        .unwrap();
+        let mut config: Config =
+            toml::from_str("hostname = \"example.com\"\n[backend]\ntype = \"local\"\n").unwrap();
+        config.chall_root = root;
+        let machines = [
+            machine("1", "elements-main"),
+            machine("2", "web-elements-main"),
+        ];
+        let platforms: Vec<Box<dyn ScoreboardPlatform>> = vec![Box::new(FakeScoreboard)];
+
+        let mut state = State::default();
+        let (found, remote) = find(&config, &state, &machines, &platforms, "web/elements").unwrap();
+        assert_eq!(
+            found.iter().map(|m| &m.name).collect::<Vec<_>>(),
+            ["elements-main"]
+        );
+        assert_eq!(remote[0].1.state.id, "fake-elements");
+
+        state
+            .challenges
+            .entry("elements".to_string())
+            .or_default()
+            .scoreboards
+            .insert(
+                "fake".to_string(),
+                ScoreboardState {
+                    id: "recorded".to_string(),
+                    files: None,
+                },
+            );
+        for chall in ["web/elements", "elements"] {
+            let (_, remote) = find(&config, &state, &machines, &platforms, chall).unwrap();
+            assert_eq!(remote[0].1.state.id, "recorded");
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use super::build;
+use crate::{
+    challenge::{Challenge, Expose},
+    context::BuildContext,
+    Config,
+};
+use anyhow::{anyhow, Result};
+use serde_json::{json, Map, Value};
+
+/// `path` made absolute and with `.` and `..` resolved, without following symlinks
+fn normalize(path: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in std::path::absolute(path)?.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    Ok(normalized)
+}
+
+/// Path of `path` relative to `base`. Absolute paths, or relative ones that go all the way up to
+/// `/`, would only work on this machine, so it's an error if the two only share the root.
+fn relative_to(path: &Path, base: &Path) -> Result<PathBuf> {
+    let (path, base) = (normalize(path)?, normalize(base)?);
+    let path_components = path.components().collect::<Vec<Component>>();
+    let base_components = base.components().collect::<Vec<Component>>();
+    let common = path_components
+        .iter()
+        .zip(&base_components)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let only_root = path_components[..common]
+        .iter()
+        .all(|c| matches!(c, Component::Prefix(_) | Component::RootDir));
+    if only_root {
+        return Err(anyhow!(
+            "{} is outside of {}, export to a directory in the challenge repo so paths can be relative",
+            path.display(),
+            base.display()
+        ));
+    }
+    let mut relative = PathBuf::from(".");
+    for _ in common..base_components.len() {
+        relative.push("..");
+    }
+    for component in &path_components[common..] {
+        relative.push(component);
+    }
+    Ok(relative)
+}
+
+/// Whether `key` can be written without quotes
+fn plain_key(key: &str) -> bool {
+    key.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-./".contains(c))
+}
+
+/// `value` as YAML. Strings are always quoted (JSON strings are valid YAML) so nothing like
+/// `80:80` or `yes` gets read as something else, and arrays are written inline.
+fn yaml(value: &Value, indent: usize, out: &mut String) {
+    let Value::Object(map) = value else {
+        out.push_str(&value.to_string());
+        out.push('\n');
+        return;
+    };
+    for (key, value) in map {
+        out.push_str(&" ".repeat(indent));
+        match plain_key(key) {
+            true => out.push_str(key),
+            false => out.push_str(&Value::from(key.as_str()).to_string()),
+        }
+        out.push(':');
+        match value {
+            Value::Object(inner) if !inner.is_empty() => {
+                out.push('\n');
+                yaml(value, indent + 2, out);
+            }
+            _ => {
+                out.push(' ');
+                out.push_str(&value.to_string());
+                out.push('\n');
+            }
+        }
+    }
+}
+
+fn base_service(name: &str) -> String {
+    format!("bear-cds-{name}")
+}
+
+/// The `build` section of a service. Base images are passed in as additional contexts of the
+/// services that use them, so compose builds them first.
+fn build_section(config: &Config, build: &BuildContext, output: &Path) -> Result<Value> {
+    let mut section = json!({
+        "context": relative_to(&build.dir, output)?,
+        "dockerfile": match build.dockerfile.strip_prefix(&build.dir) {
+            Ok(dockerfile) => dockerfile.to_path_buf(),
+            Err(_) => relative_to(&build.dockerfile, &build.dir)?,
+        },
+    });
+    if !build.args.is_empty() {
+        section["args"] = json!(build.args);
+    }
+    if let Some(target) = &build.target {
+        section["target"] = json!(target);
+    }
+    let references = build::references(build);
+    let bases = config
+        .images
+        .keys()
+        .filter(|name| build::builds_on(&references, &build::image_tag(name)))
+        .map(|name| {
+            (
+                build::image_tag(name),
+                json!(format!("service:{}", base_service(name))),
+            )
+        })
+        .collect::<Map<String, Value>>();
+    if !bases.is_empty() {
+        section["additional_contexts"] = Value::Object(bases);
+    }
+    Ok(section)
+}
+
+/// The docker-compose.yml and Caddyfile for `challs`, with paths relative to `output`.
+fn render(config: &Config, challs: &[Challenge], output: &Path) -> Result<(String, String)> {
+    let mut services = Map::new();
+    let mut caddyfile = String::new();
+    // base images only need building, so they're scaled down to nothing
+    for (name, image) in &config.images {
+        let build = image.build_context(&config.chall_root);
+        services.insert(
+            base_service(name),
+            json!({
+                "build": build_section(config, &build, output)?,
+                "image": build::image_tag(name),
+                "scale": 0,
+            }),
+        );
+    }
+    for chall in challs {
+        for (name, container) in &chall.containers {
+            let id = chall.container_id(name);
+            let build = container.image.build_context(&chall.dir);
+            let mut service = json!({
+                "build": build_section(config, &build, output)?,
+                "image": id,
+                "restart": "unless-stopped",
+            });
+            if let Some(platform) = &build.platform {
+                service["platform"] = json!(platform);
+            }
+            if let Some(env) = &container.env {
+                service["environment"] = json!(env);
+            }
+            if let Some(mem) = container.limits.mem {
+                service["mem_limit"] = json!(format!("{mem}m"));
+            }
+            if let Some(cpu) = container.limits.cpu {
+                service["cpus"] = json!(cpu);
+            }
+            match chall.expose.get(name) {
+                Some(Expose::Tcp { target, tcp }) => {
+                    service["ports"] = json!([format!("{tcp}:{target}")]);
+                }
+                Some(Expose::Http { target, http }) => {
+                    caddyfile.push_str(&format!(
+                        "{http}.{} {{\n\treverse_proxy {id}:{target}\n}}\n\n",
+                        config.hostname
+                    ));
+                }
+                None => (),
+            }
+            services.insert(id, service);
+        }
+    }
+
+    services.insert(
+        "caddy".to_string(),
+        json!({
+            "image": "caddy:2",
+            "restart": "unless-stopped",
+            "ports": ["80:80", "443:443"],
+            "volumes": ["./Caddyfile:/etc/caddy/Caddyfile:ro"],
+        }),
+    );
+
+    let mut compose = String::new();
+    yaml(
+        &json!({ "services": Value::Object(services) }),
+        0,
+        &mut compose,
+    );
+    Ok((compose, caddyfile))
+}
+
+/// Write a docker-compose.yml and Caddyfile that run every challenge without bear-cds.
+pub fn compose(config: Config, output: Option<PathBuf>) -> Result<()> {
+    let output = output.unwrap_or(config.chall_root.clone());
+    let challs = Challenge::get_all(&config)?;
+    fs::create_dir_all(&output)?;
+    let (compose, caddyfile) = render(&config, &challs, &output)?;
+    fs::write(output.join("docker-compose.yml"), compose)?;
+    fs::write(output.join("Caddyfile"), caddyfile)?;
+    println!(
+        "Exported {} challenges to {}",
+        challs.len(),
+        output.join("docker-compose.yml").display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_relative_to_the_output() {
+        assert_eq!(
+            relative_to(Path::new("/repo/web/chall"), Path::new("/repo/out/compose")).unwrap(),
+            PathBuf::from("./../../web/chall")
+        );
+        assert_eq!(
+            relative_to(Path::new("/repo/./web/../web/chall"), Path::new("/repo")).unwrap(),
+            PathBuf::from("./web/chall")
+        );
+        assert!(relative_to(Path::new("/repo/web/chall"), Path::new("/tmp/out")).is_err());
+    }
+
+    #[test]
+    fn example_repo_compose() {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("example_repo");
+        let mut config: Config =
+            toml::from_str("hostname = \"example.com\"\n[backend]\ntype = \"local\"\n").unwrap();
+        config.chall_root = root.clone();
+        let challs = Challenge::get_all(&config).unwrap();
+        let (compose, caddyfile) = render(&config, &challs, &root).unwrap();
+        assert_eq!(
+            compose,
+            r#"services:
+  caddy:
+    image: "caddy:2"
+    ports: ["80:80","443:443"]
+    restart: "unless-stopped"
+    volumes: ["./Caddyfile:/etc/caddy/Caddyfile:ro"]
+  web-elements-main:
+    build:
+      context: "./web/elements"
+      dockerfile: "Dockerfile"
+    cpus: 1
+    image: "web-elements-main"
+    mem_limit: "256m"
+    restart: "unless-stopped"
+"#
+        );
+        assert_eq!(
+            caddyfile,
+            "silly-goose.example.com {\n\treverse_proxy web-elements-main:3000\n}\n\n"
+        );
+    }
+}
@@ -0,0 +1,351 @@
+//! Scaffolding for new CTF repos and challenges.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{
+    challenge::{self, Challenge},
+    commands::check::{is_dns_label, Exposed},
+    Config, InitBackend, Template,
+};
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+
+const CATEGORIES: &[&str] = &["web", "pwn", "crypto", "rev", "misc"];
+
+/// First port handed out to tcp challenges
+const FIRST_PORT: u32 = 31337;
+
+fn bear_toml(backend: &InitBackend, state_dir: Option<&Path>) -> String {
+    let backend = match backend {
+        InitBackend::Local => "type = \"local\"\n",
+        InitBackend::Fly => "type = \"fly\"\norg = \"your-fly-org\"\napp_name = \"your-app-name\"\n",
+        InitBackend::Kubernetes => {
+            "type = \"kubernetes\"\napi_server = \"http://localhost:8001\"\nregistry = \"ghcr.io/your-org/your-ctf\"\n"
+        }
+    };
+    let state_dir = match state_dir {
+        Some(dir) => format!(
+            "# deploy state and its lock, shared by everyone who deploys\nstate_dir = {}\n",
+            toml::Value::from(dir.to_string_lossy().as_ref())
+        ),
+        None => String::new(),
+    };
+    format!(
+        "hostname = \"chall.example.com\"\n{state_dir}\n[backend]\n{backend}\n[rctf]\nurl = \"https://ctf.example.com\"\n"
+    )
+}
+
+fn env_example(backend: &InitBackend) -> String {
+    let mut env = String::from("RCTF_ADMIN_TOKEN=\n");
+    match backend {
+        InitBackend::Local => (),
+        InitBackend::Fly => {
+            env.push_str("FLY_API_HOSTNAME=\"https://api.machines.dev\"\nFLY_API_TOKEN=\n")
+        }
+        InitBackend::Kubernetes => {
+            env.push_str("KUBE_API_TOKEN=\nREGISTRY_USERNAME=\nREGISTRY_PASSWORD=\n")
+        }
+    }
+    env
+}
+
+/// Create `bear.toml`, `.env.example` and a directory per category in `dir`.
+pub fn init(dir: PathBuf, backend: InitBackend, state_dir: Option<PathBuf>) -> Result<()> {
+    let config = dir.join("bear.toml");
+    if config.exists() {
+        return Err(anyhow!("{} already exists", config.display()));
+    }
+    fs::create_dir_all(&dir)?;
+    fs::write(&config, bear_toml(&backend, state_dir.as_deref()))?;
+
+    let env = dir.join(".env.example");
+    if !env.exists() {
+        fs::write(env, env_example(&backend))?;
+    }
+
+    // .env has the secrets and .bear has this checkout's build logs, neither belongs in git
+    let gitignore = dir.join(".gitignore");
+    let mut ignored = fs::read_to_string(&gitignore).unwrap_or_default();
+    for line in [".env", ".bear/"] {
+        if !ignored.lines().any(|l| l.trim() == line) {
+            if !ignored.is_empty() && !ignored.ends_with('\n') {
+                ignored.push('\n');
+            }
+            ignored.push_str(line);
+            ignored.push('\n');
+        }
+    }
+    fs::write(gitignore, ignored)?;
+
+    for category in CATEGORIES {
+        let category = dir.join(category);
+        fs::create_dir_all(&category)?;
+        fs::write(category.join(".gitkeep"), "")?;
+    }
+
+    println!("Created {}", config.display());
+    println!("Copy .env.example to .env, fill in the tokens, then add a challenge with `bear new web/my-challenge`");
+    Ok(())
+}
+
+/// Files every template is made of, besides challenge.toml and flag.txt
+fn template_files(template: &Template) -> &'static [(&'static str, &'static str)] {
+    match template {
+        Template::Web => &[
+            ("Dockerfile", include_str!("../../templates/web/Dockerfile")),
+            ("app.py", include_str!("../../templates/web/app.py")),
+            ("solve.py", include_str!("../../templates/web/solve.py")),
+        ],
+        Template::Pwn => &[
+            ("Dockerfile", include_str!("../../templates/pwn/Dockerfile")),
+            ("chal.c", include_str!("../../templates/pwn/chal.c")),
+            ("solve.py", include_str!("../../templates/pwn/solve.py")),
+        ],
+        Template::Crypto => &[
+            (
+                "Dockerfile",
+                include_str!("../../templates/crypto/Dockerfile"),
+            ),
+            ("chall.py", include_str!("../../templates/crypto/chall.py")),
+            ("solve.py", include_str!("../../templates/crypto/solve.py")),
+        ],
+        Template::Rev => &[
+            ("Dockerfile", include_str!("../../templates/rev/Dockerfile")),
+            ("chal.c", include_str!("../../templates/rev/chal.c")),
+            ("solve.py", include_str!("../../templates/rev/solve.py")),
+        ],
+    }
+}
+
+fn challenge_toml(template: &Template, name: &str, author: &str, port: u32) -> String {
+    let (provide, target, expose) = match template {
+        Template::Web => ("", 3000, format!("http = \"{name}\"")),
+        Template::Pwn => (
+            "provide = [\"chal.c\", \"Dockerfile\"]\n",
+            5000,
+            format!("tcp = {port}"),
+        ),
+        Template::Crypto => ("provide = [\"chall.py\"]\n", 5000, format!("tcp = {port}")),
+        Template::Rev => (
+            "# provide the compiled binary once it's built\n# provide = [\"chal\"]\n",
+            5000,
+            format!("tcp = {port}"),
+        ),
+    };
+    format!(
+        r#"name = {}
+author = {}
+description = """
+TODO
+
+{{{{main.url}}}}
+"""
+flag = {{ file = "flag.txt" }}
+{provide}
+[containers.main]
+build = "."
+limits = {{ cpu = 1, mem = 256 }}
+ports = [{target}]
+
+[expose.main]
+target = {target}
+{expose}
+"#,
+        toml::Value::from(name),
+        toml::Value::from(author),
+    )
+}
+
+/// `git config user.name`, falling back to the login name
+fn default_author() -> String {
+    process::Command::new("git")
+        .args(["config", "user.name"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .or_else(|| env::var("USER").ok())
+        .unwrap_or("anonymous".to_string())
+}
+
+/// What the challenges that are already there expose
+fn exposed(config: &Config) -> Result<Exposed> {
+    let mut exposed = Exposed::default();
+    for dir in challenge::get_chall_paths(&config.chall_root, &config.ignore)? {
+        // a broken challenge shouldn't stop a new one from being made
+        if let Ok(chall) = Challenge::parse(config, dir) {
+            exposed.claim(&chall);
+        }
+    }
+    Ok(exposed)
+}
+
+/// Create the challenge `id` (`category/name`, or deeper like `pwn/heap/part1`) from a template.
+pub fn new(
+    config: Config,
+    id: String,
+    template: Option<Template>,
+    author: Option<String>,
+) -> Result<()> {
+    let parts = id.split('/').collect::<Vec<&str>>();
+    if parts.len() < 2
+        || parts
+            .iter()
+            .any(|part| part.is_empty() || part.starts_with('.'))
+    {
+        return Err(anyhow!(
+            "challenges are named category/name (or category/more/name), got {id}"
+        ));
+    }
+    let (category, name) = (parts[0], parts[parts.len() - 1]);
+    let template = match template {
+        Some(template) => template,
+        None => Template::from_str(category, true).map_err(|_| {
+            anyhow!("there is no template for {category}, pick one with --template")
+        })?,
+    };
+    if let Template::Web = template {
+        if !is_dns_label(name) {
+            return Err(anyhow!(
+                "{name} is used as the subdomain of the challenge, so it can only have a-z, 0-9 and -"
+            ));
+        }
+    }
+    let dir = config.chall_root.join(&id);
+    if dir.exists() {
+        return Err(anyhow!("{} already exists", dir.display()));
+    }
+    // nothing inside a challenge is searched for challenges
+    if let Some(parent) = dir
+        .ancestors()
+        .skip(1)
+        .take_while(|parent| *parent != config.chall_root)
+        .find(|parent| parent.join("challenge.toml").is_file())
+    {
+        return Err(anyhow!(
+            "{} is a challenge, a challenge can't be inside it",
+            parent.display()
+        ));
+    }
+
+    let mut exposed = exposed(&config)?;
+    let port = (FIRST_PORT..)
+        .find(|port| !exposed.tcp.contains_key(port))
+        .expect("ran out of ports");
+    let author = author.unwrap_or_else(default_author);
+    // the outermost directory that's about to be created, to clean up if the challenge is no good
+    let created = dir
+        .ancestors()
+        .take_while(|dir| !dir.exists())
+        .last()
+        .unwrap_or(&dir)
+        .to_path_buf();
+    fs::create_dir_all(&dir)?;
+    fs::write(
+        dir.join("challenge.toml"),
+        challenge_toml(&template, name, &author, port),
+    )?;
+    fs::write(dir.join("flag.txt"), "flag{placeholder}\n")?;
+    for (file, contents) in template_files(&template) {
+        fs::write(dir.join(file), contents)?;
+    }
+
+    // the same checks `bear check` does for exposes, so the challenge can be deployed right away
+    let checked = Challenge::parse(&config, dir.clone()).and_then(|chall| {
+        match exposed.claim(&chall).into_iter().next() {
+            Some((name, key, message)) => Err(anyhow!("expose.{name}.{key}: {message}")),
+            None => Ok(()),
+        }
+    });
+    if checked.is_err() {
+        fs::remove_dir_all(&created)?;
+    }
+    checked?;
+    println!("Created {} in {}", id, dir.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{challenge::Expose, mock::scratch_dir};
+
+    /// The config `init` wrote to `dir`
+    fn config(dir: &Path) -> Config {
+        let mut config =
+            Config::parse(&fs::read_to_string(dir.join("bear.toml")).unwrap()).unwrap();
+        config.chall_root = dir.to_path_buf();
+        config
+    }
+
+    fn new_chall(dir: &Path, id: &str, template: Template) -> Result<()> {
+        new(
+            config(dir),
+            id.to_string(),
+            Some(template),
+            Some("bear".to_string()),
+        )
+    }
+
+    #[test]
+    fn new_challenges_parse() {
+        let dir = scratch_dir("init");
+        init(dir.clone(), InitBackend::Local, None).unwrap();
+        new_chall(&dir, "web/baby", Template::Web).unwrap();
+        new_chall(&dir, "pwn/heap/part1", Template::Pwn).unwrap();
+        new_chall(&dir, "pwn/heap/part2", Template::Pwn).unwrap();
+
+        let config = config(&dir);
+        let web = Challenge::parse(&config, dir.join("web/baby")).unwrap();
+        assert_eq!(web.author, "bear");
+        assert!(matches!(&web.expose["main"], Expose::Http { http, .. } if http == "baby"));
+        let ports = ["part1", "part2"].map(|name| {
+            let chall = Challenge::parse(&config, dir.join("pwn/heap").join(name)).unwrap();
+            assert_eq!(chall.id, format!("pwn/heap/{name}"));
+            match chall.expose["main"] {
+                Expose::Tcp { tcp, .. } => tcp,
+                _ => panic!("pwn challenges expose tcp"),
+            }
+        });
+        assert_eq!(ports, [FIRST_PORT, FIRST_PORT + 1]);
+    }
+
+    #[test]
+    fn state_dir_is_only_set_when_asked_for() {
+        let dir = scratch_dir("init");
+        init(dir.clone(), InitBackend::Local, None).unwrap();
+        assert_eq!(config(&dir).state_dir, Path::new(".bear"));
+
+        let dir = scratch_dir("init");
+        let shared = PathBuf::from("/mnt/shared/bear-state");
+        init(dir.clone(), InitBackend::Fly, Some(shared.clone())).unwrap();
+        assert_eq!(config(&dir).state_dir, shared);
+    }
+
+    #[test]
+    fn new_rejects_bad_and_taken_subdomains() {
+        let dir = scratch_dir("init");
+        init(dir.clone(), InitBackend::Local, None).unwrap();
+        new_chall(&dir, "web/baby", Template::Web).unwrap();
+        assert!(new_chall(&dir, "web/Not_A_Label", Template::Web)
+            .unwrap_err()
+            .to_string()
+            .contains("a-z, 0-9 and -"));
+        assert_eq!(
+            new_chall(&dir, "misc/hard/baby", Template::Web)
+                .unwrap_err()
+                .to_string(),
+            "expose.main.http: subdomain baby is already used by web/baby (expose.main)"
+        );
+        // misc/ came with init and stays, misc/hard was made for the challenge and goes with it
+        assert!(dir.join("misc").exists());
+        assert!(!dir.join("misc/hard").exists());
+        assert!(new_chall(&dir, "web/baby/inner", Template::Web).is_err());
+    }
+}
@@ -1,12 +1,65 @@
-use anyhow::Context; // Import the Context trait
-use crate::{
-    Config,
-    rctf,
-};
-// Command to fetch the leaderboard and save it to a file, using the rctf module
-pub async fn command(config: Config) -> Result<(), anyhow::Error> {
-    // use the rctf fetch_leaderboard function to get the leaderboard
-    rctf::fetch_leaderboard(&config).await
-        .context("Failed to fetch leaderboard")?; // Add .context() method to the Result
+use crate::{scoreboard, Config};
+use anyhow::{anyhow, Context};
+use std::fs::File;
+
+/// The scoreboard called `name`, or the first one
+fn pick(config: &Config, name: Option<&str>) -> Result<scoreboard::Config, anyhow::Error> {
+    let scoreboards = scoreboard::all(config);
+    match name {
+        Some(name) => scoreboards.into_iter().find(|s| s.name() == name),
+        None => scoreboards.into_iter().next(),
+    }
+    .ok_or_else(|| match name {
+        Some(name) => anyhow!("there is no scoreboard called {name} in bear.toml"),
+        None => anyhow!("there is no scoreboard in bear.toml"),
+    })
+}
+
+/// Save the standings of the chosen scoreboard, or the first one, to ctftime.json
+pub async fn command(config: Config, name: Option<String>) -> Result<(), anyhow::Error> {
+    // only the chosen one is connected to, so the others' tokens don't need to be set
+    let platform = pick(&config, name.as_deref())?.platform()?;
+    let data = platform
+        .scoreboard()
+        .await
+        .context("Failed to fetch leaderboard")?;
+
+    let mut file = File::create("./ctftime.json")?;
+    serde_json::to_writer(&mut file, &data)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_chosen_scoreboard_needs_a_token() {
+        let config: Config = toml::from_str(
+            r#"
+hostname = "example.com"
+chall_root = "."
+[backend]
+type = "local"
+[rctf]
+url = "https://rctf.example.com"
+token_env = "BEAR_TEST_UNSET_RCTF_TOKEN"
+[ctfd]
+url = "https://ctfd.example.com"
+token_env = "BEAR_TEST_UNSET_CTFD_TOKEN"
+[[scoreboards]]
+type = "json"
+name = "main"
+"#,
+        )
+        .unwrap();
+        assert!(scoreboard::from_config(&config).is_err());
+        let main = pick(&config, Some("main")).unwrap();
+        assert_eq!(main.platform().unwrap().name(), "main");
+        assert_eq!(pick(&config, None).unwrap().name(), "rctf");
+        assert_eq!(
+            pick(&config, Some("nope")).err().unwrap().to_string(),
+            "there is no scoreboard called nope in bear.toml"
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{challenge::Challenge, fly, Config};
+use crate::{backend, challenge::Challenge, Config};
 use anyhow::Result;
 use colored::*;
 use std::collections::HashMap;
@@ -9,18 +9,20 @@ enum MachineState {
     Started,
 }
 
-pub fn command(config: Config) -> Result<()> {
-    let challs = Challenge::get_all(&config.chall_root)?;
-    let machines = fly::list_machines(&config.fly.app_name)?
+pub async fn command(config: Config) -> Result<()> {
+    let challs = Challenge::get_all(&config)?;
+    let machines = backend::from_config(&config.backend)
+        .list()
+        .await?
         .into_iter()
         .map(|machine| (machine.name, machine.state))
         .collect::<HashMap<String, String>>();
     for chall in challs {
         let deploy_status = chall
             .containers
-            .iter()
-            .map(|(name, _)| {
-                let id = chall.container_id(&name);
+            .keys()
+            .map(|name| {
+                let id = chall.container_id(name);
                 let state = match machines.get(&id) {
                     Some(state) => {
                         if state == "started" {
@@ -46,5 +48,5 @@ pub fn command(config: Config) -> Result<()> {
             .collect::<String>();
         println!("{} - {}", chall.id, deploy_status);
     }
-    return Ok(());
+    Ok(())
 }
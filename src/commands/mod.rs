@@ -1,3 +1,11 @@
+pub mod build;
+pub mod check;
 pub mod deploy;
-pub mod list;
+pub mod destroy;
+pub mod export;
+pub mod init;
 pub mod leaderboard;
+pub mod list;
+pub mod plan;
+pub mod rctf;
+pub mod schema;
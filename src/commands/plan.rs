@@ -0,0 +1,470 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    backend::{self, DeployBackend, Machine},
+    challenge::{Challenge, Expose},
+    commands::deploy::{self, LocalImage},
+    ingress::{self, Routes},
+    scoreboard::{self, rctf::Rctf, ScoreboardPlatform},
+    state::State,
+    Config,
+};
+use anyhow::Result;
+use colored::*;
+
+#[derive(Debug, PartialEq)]
+enum Change {
+    Create(String),
+    Update(String),
+    Unchanged(String),
+    Delete(String),
+}
+
+#[derive(Default)]
+struct Summary {
+    create: usize,
+    update: usize,
+    delete: usize,
+}
+
+impl Summary {
+    fn print(&mut self, title: &str, changes: Vec<Change>) {
+        println!("{}", title.bold());
+        let mut unchanged = 0;
+        for change in changes {
+            match change {
+                Change::Create(what) => {
+                    self.create += 1;
+                    println!("  {} {what}", "+".green().bold());
+                }
+                Change::Update(what) => {
+                    self.update += 1;
+                    println!("  {} {what}", "~".yellow().bold());
+                }
+                Change::Unchanged(_) => unchanged += 1,
+                Change::Delete(what) => {
+                    self.delete += 1;
+                    println!("  {} {what}", "-".red().bold());
+                }
+            }
+        }
+        if unchanged > 0 {
+            println!("  {unchanged} unchanged");
+        }
+    }
+}
+
+/// Flatten routes into `route -> machine:port` so they can be compared without caring about
+/// machine addresses, which change whenever a machine is recreated.
+fn flatten(config: &Config, routes: &Routes) -> BTreeMap<String, String> {
+    let http = routes.http.iter().map(|(sub, route)| {
+        (
+            format!("http {sub}.{}", config.hostname),
+            format!("{}:{}", route.machine, route.port),
+        )
+    });
+    let tcp = routes.tcp.iter().map(|(port, route)| {
+        (
+            format!("tcp {port}"),
+            format!("{}:{}", route.machine, route.port),
+        )
+    });
+    http.chain(tcp).collect()
+}
+
+/// What deploying `challs` would do to the machines on `backend` and the routes to them.
+/// Machines and routes that aren't in the repo are only deleted when deploying `everything`.
+async fn backend_changes(
+    config: &Config,
+    backend: &dyn DeployBackend,
+    challs: &[Challenge],
+    state: &State,
+    images: &HashMap<String, LocalImage>,
+    everything: bool,
+) -> Result<(Vec<Change>, Vec<Change>)> {
+    let machine_list = backend.list().await?;
+    let machines = machine_list
+        .iter()
+        .map(|machine| (machine.name.clone(), machine))
+        .collect::<HashMap<String, &Machine>>();
+    let current = backend
+        .current_routes(config, machines.get(ingress::NAME).copied(), &machine_list)
+        .await?;
+
+    let mut machine_changes = Vec::new();
+    // same as deploy, routes of challenges that aren't selected are left alone
+    let mut desired = match everything {
+        true => Routes::default(),
+        false => current.clone(),
+    };
+    // anything that wasn't inspected counts as not built
+    let unknown = LocalImage::default();
+    for chall in challs {
+        let chall_state = state.challenges.get(&chall.id);
+        for (name, container) in &chall.containers {
+            let id = chall.container_id(name);
+            match machines.get(&id) {
+                Some(machine) => {
+                    let spec = deploy::machine_spec(chall, name, container);
+                    let previous = chall_state.and_then(|s| s.containers.get(name));
+                    let local = images.get(&id).unwrap_or(&unknown);
+                    let changes = deploy::changes(&spec, machine, previous, local);
+                    machine_changes.push(match changes.is_empty() {
+                        true => Change::Unchanged(id.clone()),
+                        false => Change::Update(format!("{id} ({})", changes.join(", "))),
+                    });
+                }
+                None => machine_changes.push(Change::Create(id.clone())),
+            }
+            desired.remove_machine(&id);
+            // the address is not known until the machine exists, and not needed to compare
+            let route = |port: &u32| ingress::Route {
+                machine: id.clone(),
+                address: String::new(),
+                port: *port,
+            };
+            match chall.expose.get(name) {
+                Some(Expose::Tcp { target, tcp }) => {
+                    desired.tcp.insert(*tcp, route(target));
+                }
+                Some(Expose::Http { target, http }) => {
+                    desired.http.insert(http.clone(), route(target));
+                }
+                None => (),
+            }
+        }
+    }
+    if everything {
+        for machine in &machine_list {
+            let in_repo = challs.iter().any(|chall| {
+                chall
+                    .containers
+                    .keys()
+                    .any(|name| chall.container_id(name) == machine.name)
+            });
+            if !in_repo && machine.name != ingress::NAME {
+                machine_changes.push(Change::Delete(format!(
+                    "{} (not in repo, run `prune` to remove)",
+                    machine.name
+                )));
+            }
+        }
+    }
+
+    let mut route_changes = Vec::new();
+    let current = flatten(config, &current);
+    let desired = flatten(config, &desired);
+    for (route, target) in &desired {
+        route_changes.push(match current.get(route) {
+            Some(current) if current == target => Change::Unchanged(route.clone()),
+            Some(current) => Change::Update(format!("{route} -> {target} (was {current})")),
+            None => Change::Create(format!("{route} -> {target}")),
+        });
+    }
+    for (route, target) in &current {
+        if !desired.contains_key(route) {
+            route_changes.push(Change::Delete(format!("{route} -> {target}")));
+        }
+    }
+    Ok((machine_changes, route_changes))
+}
+
+/// What deploying `challs` would do on `rctf`. Orphans are only listed when deploying
+/// `everything`.
+async fn rctf_changes(
+    config: &Config,
+    rctf: &Rctf,
+    challs: &[Challenge],
+    state: &State,
+    everything: bool,
+) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    let existing = rctf.list_challs().await?;
+    for chall in challs.iter().filter(|c| c.hidden != Some(true)) {
+        let id = scoreboard::rctf::chall_id(&chall.id);
+        changes.push(match existing.iter().find(|c| c.id == id) {
+            Some(current) => {
+                let diff = scoreboard::rctf::diff(
+                    current,
+                    &rctf
+                        .local_chall(config, chall, &chall.provided_files()?)
+                        .await?,
+                );
+                match diff.is_empty() {
+                    true => Change::Unchanged(id),
+                    false => Change::Update(format!("{id} ({})", diff.join(", "))),
+                }
+            }
+            None => Change::Create(id),
+        });
+    }
+    if everything {
+        for orphan in rctf.orphans(challs, state).await? {
+            changes.push(Change::Delete(format!(
+                "{} (run `prune` to remove)",
+                orphan.label
+            )));
+        }
+    }
+    Ok(changes)
+}
+
+/// Print what `deploy` would change, without changing anything.
+pub async fn command(config: Config, selected: Option<Vec<String>>) -> Result<()> {
+    let backend = backend::from_config(&config.backend);
+    let challs: Vec<Challenge> = match &selected {
+        Some(selected) => Challenge::get_some(&config, selected.clone())?,
+        None => Challenge::get_all(&config)?,
+    };
+    let state = State::load(&config)?;
+    let mut images = HashMap::new();
+    for chall in &challs {
+        let chall_state = state.challenges.get(&chall.id);
+        for name in chall.containers.keys() {
+            let id = chall.container_id(name);
+            let previous = chall_state.and_then(|s| s.containers.get(name));
+            let local = LocalImage::inspect(&id, previous).await;
+            images.insert(id, local);
+        }
+    }
+    let everything = selected.is_none();
+    let mut summary = Summary::default();
+
+    let (machines, routes) = backend_changes(
+        &config,
+        backend.as_ref(),
+        &challs,
+        &state,
+        &images,
+        everything,
+    )
+    .await?;
+    summary.print("Machines:", machines);
+    summary.print("Routes:", routes);
+    // only rCTF can say what it has without changing anything, the other scoreboards are
+    // compared as they are deployed
+    for rctf in scoreboard::rctf::configs(&config) {
+        let rctf = Rctf::new(&rctf)?;
+        let changes = rctf_changes(&config, &rctf, &challs, &state, everything).await?;
+        summary.print(&format!("{}:", rctf.name()), changes);
+    }
+
+    println!(
+        "\nPlan: {} to create, {} to update, {} to delete.",
+        summary.create.to_string().green().bold(),
+        summary.update.to_string().yellow().bold(),
+        summary.delete.to_string().red().bold(),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        challenge::Category,
+        mock::{FakeBackend, Server},
+        state::{ChallengeState, ContainerState},
+    };
+    use serde_json::json;
+
+    fn config() -> Config {
+        toml::from_str(
+            "hostname = \"example.com\"\nchall_root = \".\"\n[backend]\ntype = \"local\"\n",
+        )
+        .unwrap()
+    }
+
+    fn chall(id: &str, extra: &str) -> Challenge {
+        let toml = format!(
+            "name = \"{id}\"\nauthor = \"bear\"\ndescription = \"hi\"\nflag = \"flag{{x}}\"\n{extra}"
+        );
+        let dir = crate::mock::scratch_dir("plan");
+        Challenge::from_toml(id.to_string(), dir, &Category::default(), &toml).unwrap()
+    }
+
+    fn http(id: &str, subdomain: &str) -> Challenge {
+        chall(
+            id,
+            &format!("[containers.main]\nbuild = \".\"\n[expose.main]\ntarget = 3000\nhttp = \"{subdomain}\"\n"),
+        )
+    }
+
+    fn route(machine: &str, port: u32) -> ingress::Route {
+        ingress::Route {
+            machine: machine.to_string(),
+            address: format!("{machine}.internal"),
+            port,
+        }
+    }
+
+    /// What deploying `chall` left in the state, built from `hash`
+    fn deployed(chall: &Challenge, hash: &str, digest: Option<&str>) -> ChallengeState {
+        let spec = deploy::machine_spec(chall, "main", &chall.containers["main"]);
+        let id = chall.container_id("main");
+        ChallengeState {
+            containers: [(
+                "main".to_string(),
+                ContainerState {
+                    machine_id: format!("{id}-id"),
+                    image: format!("registry/{id}:1"),
+                    hash: Some(hash.to_string()),
+                    config: Some(spec.fingerprint()),
+                    digest: digest.map(str::to_string),
+                    expose: chall.expose.get("main").cloned(),
+                },
+            )]
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    fn local(hash: &str, digest: &str) -> LocalImage {
+        LocalImage {
+            hash: Some(hash.to_string()),
+            digest: Some(digest.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn backend_changes_are_classified() {
+        let challs = [
+            http("web/new", "new"),
+            http("web/same", "same"),
+            chall(
+                "pwn/changed",
+                "[containers.main]\nbuild = \".\"\n[expose.main]\ntarget = 1337\ntcp = 1337\n",
+            ),
+        ];
+        let mut state = State::default();
+        // web/same is from before digests were recorded, so the local one is compared, and
+        // pwn/changed runs something other than what was deployed
+        state
+            .challenges
+            .insert("web/same".to_string(), deployed(&challs[1], "same", None));
+        state.challenges.insert(
+            "pwn/changed".to_string(),
+            deployed(&challs[2], "old", Some("deployed")),
+        );
+        let backend = FakeBackend {
+            machines: vec![
+                FakeBackend::machine("web-same-main", "registry/web-same-main:1", Some("same")),
+                FakeBackend::machine(
+                    "pwn-changed-main",
+                    "registry/pwn-changed-main:1",
+                    Some("old"),
+                ),
+                FakeBackend::machine("web-gone-main", "registry/web-gone-main:1", None),
+                FakeBackend::machine(ingress::NAME, "caddy", None),
+            ],
+            routes: Routes {
+                http: [
+                    ("same".to_string(), route("web-same-main", 3000)),
+                    ("gone".to_string(), route("web-gone-main", 80)),
+                ]
+                .into(),
+                tcp: [(1337, route("pwn-changed-main", 1000))].into(),
+            },
+        };
+        let images = [
+            ("web-same-main".to_string(), local("same", "same")),
+            ("pwn-changed-main".to_string(), local("new", "new")),
+        ]
+        .into();
+
+        let (machines, routes) =
+            backend_changes(&config(), &backend, &challs, &state, &images, true)
+                .await
+                .unwrap();
+        assert_eq!(
+            machines,
+            [
+                Change::Create("web-new-main".to_string()),
+                Change::Unchanged("web-same-main".to_string()),
+                Change::Update("pwn-changed-main (image, running image)".to_string()),
+                Change::Delete("web-gone-main (not in repo, run `prune` to remove)".to_string()),
+            ]
+        );
+        assert_eq!(
+            routes,
+            [
+                Change::Create("http new.example.com -> web-new-main:3000".to_string()),
+                Change::Unchanged("http same.example.com".to_string()),
+                Change::Update(
+                    "tcp 1337 -> pwn-changed-main:1337 (was pwn-changed-main:1000)".to_string()
+                ),
+                Change::Delete("http gone.example.com -> web-gone-main:80".to_string()),
+            ]
+        );
+
+        // deploying some challenges leaves everything else alone
+        let (machines, routes) =
+            backend_changes(&config(), &backend, &challs[..1], &state, &images, false)
+                .await
+                .unwrap();
+        assert_eq!(machines, [Change::Create("web-new-main".to_string())]);
+        assert_eq!(
+            routes
+                .iter()
+                .filter(|c| !matches!(c, Change::Unchanged(_)))
+                .collect::<Vec<_>>(),
+            [&Change::Create(
+                "http new.example.com -> web-new-main:3000".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn rctf_changes_are_classified() {
+        let server = Server::start(|request| match request.path.as_str() {
+            "/api/v1/admin/challs" => {
+                let chall = |id: &str, name: &str| {
+                    json!({
+                        "id": id,
+                        "name": name,
+                        "description": "hi",
+                        "category": "web",
+                        "author": "bear",
+                        "flag": "flag{x}",
+                        "points": { "min": 100, "max": 500 },
+                        "files": [],
+                        "tiebreakEligible": true,
+                    })
+                };
+                (
+                    200,
+                    json!({ "data": [
+                        chall("bcds-web-same", "web/same"),
+                        chall("bcds-web-changed", "Old"),
+                        chall("bcds-web-gone", "Gone"),
+                    ] }),
+                )
+            }
+            _ => (404, json!({})),
+        });
+        let challs = [
+            chall("web/new", ""),
+            chall("web/same", ""),
+            chall("web/changed", ""),
+            chall("web/secret", "hidden = true"),
+        ];
+        let changes = rctf_changes(
+            &config(),
+            &Rctf::mock(&server),
+            &challs,
+            &State::default(),
+            true,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            changes,
+            [
+                Change::Create("bcds-web-new".to_string()),
+                Change::Unchanged("bcds-web-same".to_string()),
+                Change::Update("bcds-web-changed (name: Old -> web/changed)".to_string()),
+                Change::Delete("bcds-web-gone \"Gone\" (run `prune` to remove)".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,378 @@
+//! Keeping rCTF in sync with the repo, and importing what's already on rCTF into it.
+
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    challenge::Challenge,
+    commands::destroy::confirm,
+    scoreboard::{
+        self,
+        rctf::{self, Rctf, RctfChall},
+        ScoreboardPlatform,
+    },
+    state::{ScoreboardState, State},
+    Config,
+};
+use anyhow::{anyhow, Result};
+use colored::*;
+use serde::Serialize;
+
+/// The rCTF scoreboards in bear.toml, or only the one called `name`
+fn scoreboards(config: &Config, name: Option<&str>) -> Result<Vec<rctf::Config>> {
+    let configs = rctf::configs(config)
+        .into_iter()
+        .filter(|rctf| name.is_none_or(|name| rctf.name() == name))
+        .collect::<Vec<rctf::Config>>();
+    match (configs.is_empty(), name) {
+        (false, _) => Ok(configs),
+        (true, Some(name)) => Err(anyhow!(
+            "there is no rCTF scoreboard called {name} in bear.toml"
+        )),
+        (true, None) => Err(anyhow!("there is no rCTF scoreboard in bear.toml")),
+    }
+}
+
+/// Update every challenge on the rCTF scoreboards that differs from the repo. With `prune`, also
+/// delete the orphans (challenges bear-cds made that are gone from the repo or hidden there),
+/// which are only reported otherwise.
+pub async fn sync(
+    config: Config,
+    scoreboard: Option<String>,
+    selected: Option<Vec<String>>,
+    dry_run: bool,
+    prune: bool,
+    yes: bool,
+) -> Result<()> {
+    // orphans need every challenge to tell, so they're only looked for when syncing all of them
+    if prune && selected.is_some() {
+        return Err(anyhow!("--prune only works when syncing every challenge"));
+    }
+    let rctfs = scoreboards(&config, scoreboard.as_deref())?
+        .iter()
+        .map(Rctf::new)
+        .collect::<Result<Vec<Rctf>>>()?;
+    let challs: Vec<Challenge> = match &selected {
+        Some(selected) => Challenge::get_some(&config, selected.clone())?,
+        None => Challenge::get_all(&config)?,
+    };
+    let _lock = match dry_run {
+        true => None,
+        false => Some(State::lock(&config)?),
+    };
+    let mut state = State::load(&config)?;
+    for rctf in &rctfs {
+        println!("{}", format!("{}:", rctf.name()).bold());
+        sync_one(&config, rctf, &challs, &mut state, dry_run).await?;
+        if selected.is_none() {
+            prune_one(&config, rctf, &challs, &mut state, dry_run, prune, yes).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Update the challenges on `rctf` that differ from `challs`
+async fn sync_one(
+    config: &Config,
+    rctf: &Rctf,
+    challs: &[Challenge],
+    state: &mut State,
+    dry_run: bool,
+) -> Result<()> {
+    let existing = rctf.list_challs().await?;
+    let mut unchanged = 0;
+    for chall in challs.iter().filter(|c| c.hidden != Some(true)) {
+        let id = rctf::chall_id(&chall.id);
+        let current = existing.iter().find(|c| c.id == id);
+        let changes = match dry_run {
+            true => {
+                let desired = rctf
+                    .local_chall(config, chall, &chall.provided_files()?)
+                    .await?;
+                match current {
+                    Some(current) => rctf::diff(current, &desired),
+                    None => vec!["created".to_string()],
+                }
+            }
+            false => rctf.update_chall(config, chall, current).await?,
+        };
+        if changes.is_empty() {
+            unchanged += 1;
+            continue;
+        }
+        let symbol = match current {
+            Some(_) => "~".yellow().bold(),
+            None => "+".green().bold(),
+        };
+        println!("  {symbol} {id} ({})", chall.id);
+        if current.is_some() {
+            for change in changes {
+                println!("      {change}");
+            }
+        }
+        if !dry_run {
+            state
+                .challenges
+                .entry(chall.id.clone())
+                .or_default()
+                .scoreboards
+                .insert(rctf.name().to_string(), ScoreboardState { id, files: None });
+            state.save(config)?;
+        }
+    }
+    println!("{unchanged} challenges already up to date");
+    Ok(())
+}
+
+/// List the orphans on `rctf`, and delete them if `prune` is set
+async fn prune_one(
+    config: &Config,
+    rctf: &Rctf,
+    challs: &[Challenge],
+    state: &mut State,
+    dry_run: bool,
+    prune: bool,
+    yes: bool,
+) -> Result<()> {
+    let orphaned = rctf.orphans(challs, state).await?;
+    if !prune {
+        for orphan in &orphaned {
+            println!(
+                "  {} {} isn't in the repo or is hidden there, --prune deletes it",
+                "!".yellow().bold(),
+                orphan.label
+            );
+        }
+        return Ok(());
+    }
+    if orphaned.is_empty() {
+        return Ok(());
+    }
+    for orphan in &orphaned {
+        println!("  {} {}", "-".red().bold(), orphan.label);
+    }
+    if dry_run || (!yes && !confirm()?) {
+        return Ok(());
+    }
+    for orphan in orphaned {
+        rctf.delete(&orphan.state).await?;
+        for chall_state in state.challenges.values_mut() {
+            chall_state
+                .scoreboards
+                .retain(|name, s| name != rctf.name() || s.id != orphan.state.id);
+        }
+        state.save(config)?;
+        println!("Deleted {} from {}", orphan.state.id, rctf.name());
+    }
+    Ok(())
+}
+
+/// challenge.toml of a challenge pulled from rCTF
+#[derive(Serialize)]
+struct Pulled {
+    name: String,
+    author: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    category: Option<String>,
+    description: String,
+    flag: String,
+    points: toml::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tiebreak: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    provide: Vec<String>,
+}
+
+/// Lowercase with anything but letters and digits turned into dashes, for directory names
+fn slug(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-")
+}
+
+/// Where a challenge pulled from rCTF goes relative to the challenge root, and its
+/// challenge.toml. Fails if a file name would point outside of that directory.
+fn pulled(chall: &RctfChall) -> Result<(PathBuf, String)> {
+    // names come from the server, don't let them point outside the challenge folder
+    if let Some(file) = chall
+        .files
+        .iter()
+        .find(|f| !scoreboard::is_plain_file_name(&f.name))
+    {
+        return Err(anyhow!(
+            "{} has a file called {:?}, which isn't a plain file name",
+            chall.id,
+            file.name
+        ));
+    }
+    let folder = slug(&chall.category);
+    let points = match chall.points.min == chall.points.max {
+        true => toml::Value::from(chall.points.min),
+        false => toml::Value::try_from(&chall.points)?,
+    };
+    let toml = toml::to_string(&Pulled {
+        name: chall.name.clone(),
+        // challenge.toml needs an author, rCTF doesn't
+        author: match chall.author.is_empty() {
+            true => "unknown".to_string(),
+            false => chall.author.clone(),
+        },
+        category: (folder != chall.category).then(|| chall.category.clone()),
+        description: chall.description.clone(),
+        flag: chall.flag.clone(),
+        points,
+        tiebreak: (!chall.tiebreak_eligible).then_some(false),
+        provide: chall.files.iter().map(|f| f.name.clone()).collect(),
+    })?;
+    Ok((Path::new(&folder).join(slug(&chall.name)), toml))
+}
+
+/// Write challenges that are on rCTF but weren't made by bear-cds into the repo, files and all.
+/// Pass rCTF ids to only pull those. Pulls from the first rCTF scoreboard unless `scoreboard`
+/// names another one.
+pub async fn pull(
+    config: Config,
+    scoreboard: Option<String>,
+    ids: Vec<String>,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    let rctf_config = scoreboards(&config, scoreboard.as_deref())?.remove(0);
+    let output = output.unwrap_or(config.chall_root.clone());
+    let existing = Rctf::new(&rctf_config)?.list_challs().await?;
+    let selected = existing.iter().filter(|c| match ids.is_empty() {
+        true => !c.id.starts_with("bcds-"),
+        false => ids.contains(&c.id),
+    });
+
+    let mut count = 0;
+    for chall in selected {
+        let (dir, toml) = pulled(chall)?;
+        let dir = output.join(dir);
+        if dir.exists() {
+            println!("{} already exists, skipping {}", dir.display(), chall.id);
+            continue;
+        }
+        fs::create_dir_all(&dir)?;
+        for file in &chall.files {
+            let url = match file.url.starts_with('/') {
+                true => format!("{}{}", rctf_config.url, file.url),
+                false => file.url.clone(),
+            };
+            let name = file.name.clone();
+            let source = rctf_config.name();
+            let data = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+                let mut data = Vec::new();
+                ureq::get(&url)
+                    .call()
+                    .map_err(|err| anyhow!("Download of {name} failed ({source}): {err}"))?
+                    .into_reader()
+                    .read_to_end(&mut data)?;
+                Ok(data)
+            })
+            .await??;
+            fs::write(dir.join(&file.name), data)?;
+        }
+        fs::write(dir.join("challenge.toml"), toml)?;
+        println!("Pulled {} into {}", chall.id, dir.display());
+        count += 1;
+    }
+    if count > 0 {
+        println!(
+            "Pulled {count} challenges. Deploying them creates new bcds- challenges on rCTF, the originals are left alone."
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scoreboard::rctf::{RctfPoints, RctfUploadedFile};
+
+    fn rctf_chall() -> RctfChall {
+        RctfChall {
+            id: "made-by-hand".to_string(),
+            name: "Baby's First RSA!".to_string(),
+            description: "hi".to_string(),
+            category: "Crypto".to_string(),
+            author: String::new(),
+            flag: "flag{x}".to_string(),
+            points: RctfPoints { min: 100, max: 500 },
+            files: vec![RctfUploadedFile {
+                name: "chall.py".to_string(),
+                url: "/uploads/chall.py".to_string(),
+            }],
+            tiebreak_eligible: false,
+        }
+    }
+
+    #[test]
+    fn slugs() {
+        assert_eq!(slug("Baby's First RSA!"), "baby-s-first-rsa");
+        assert_eq!(slug("  web  "), "web");
+        assert_eq!(slug("pwn/heap"), "pwn-heap");
+    }
+
+    #[test]
+    fn pulled_challenge_toml() {
+        let (dir, toml) = pulled(&rctf_chall()).unwrap();
+        assert_eq!(dir, Path::new("crypto/baby-s-first-rsa"));
+        assert_eq!(
+            toml,
+            "name = \"Baby's First RSA!\"\nauthor = \"unknown\"\ncategory = \"Crypto\"\ndescription = \"hi\"\nflag = \"flag{x}\"\ntiebreak = false\nprovide = [\"chall.py\"]\n\n[points]\nmax = 500\nmin = 100\n"
+        );
+
+        let mut chall = rctf_chall();
+        chall.category = "crypto".to_string();
+        chall.author = "bear".to_string();
+        chall.points = RctfPoints { min: 50, max: 50 };
+        chall.tiebreak_eligible = true;
+        chall.files.clear();
+        let (_, toml) = pulled(&chall).unwrap();
+        assert_eq!(
+            toml,
+            "name = \"Baby's First RSA!\"\nauthor = \"bear\"\ndescription = \"hi\"\nflag = \"flag{x}\"\npoints = 50\n"
+        );
+        // and it reads back as a challenge
+        toml::from_str::<Challenge>(&toml).unwrap();
+    }
+
+    #[test]
+    fn pulled_files_must_be_plain_names() {
+        for name in ["../x", "a/b", "/etc/passwd", ".."] {
+            let mut chall = rctf_chall();
+            chall.files[0].name = name.to_string();
+            assert!(pulled(&chall)
+                .unwrap_err()
+                .to_string()
+                .contains("isn't a plain file name"));
+        }
+    }
+
+    #[tokio::test]
+    async fn prune_with_selected_challenges_fails_first() {
+        // no scoreboard, no challenges: anything past the flag check would fail differently
+        let config: Config =
+            toml::from_str("hostname = \"example.com\"\nchall_root = \"/nonexistent\"\n[backend]\ntype = \"local\"\n")
+                .unwrap();
+        let e = sync(
+            config,
+            None,
+            Some(vec!["web/baby".to_string()]),
+            false,
+            true,
+            true,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "--prune only works when syncing every challenge"
+        );
+    }
+}
@@ -0,0 +1,16 @@
+use crate::{
+    challenge::{Category, Challenge},
+    Config, SchemaFile,
+};
+use anyhow::Result;
+use schemars::schema_for;
+
+pub fn command(file: SchemaFile) -> Result<()> {
+    let schema = match file {
+        SchemaFile::Challenge => schema_for!(Challenge),
+        SchemaFile::Config => schema_for!(Config),
+        SchemaFile::Category => schema_for!(Category),
+    };
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}
@@ -0,0 +1,366 @@
+//! Docker build contexts.
+//!
+//! Walks a build directory the way docker would, skipping anything matched by `.dockerignore`,
+//! so the context can be hashed to tell whether an image needs rebuilding at all, and streamed
+//! to the docker daemon as a tar without ever holding the whole thing in memory.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::DOCKER;
+use anyhow::{anyhow, Result};
+use bollard::query_parameters::{BuildImageOptions, BuildImageOptionsBuilder};
+use bytes::Bytes;
+use futures::{stream, Stream};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+
+/// Image label the context hash is stored under
+pub const HASH_LABEL: &str = "bear-cds.hash";
+
+/// Turn a `.dockerignore` into a gitignore matcher. Dockerignore patterns are always relative to
+/// the root of the context, so every pattern gets anchored.
+fn dockerignore(dir: &Path) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Ok(file) = fs::read_to_string(dir.join(".dockerignore")) {
+        for line in file.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negate, pattern) = match line.strip_prefix('!') {
+                Some(pattern) => ("!", pattern.trim()),
+                None => ("", line),
+            };
+            let pattern = pattern.trim_start_matches("./").trim_start_matches('/');
+            builder.add_line(None, &format!("{negate}/{pattern}"))?;
+        }
+    }
+    Ok(builder.build()?)
+}
+
+/// Name the dockerfile is sent under when it lives outside of the context
+const INJECTED_DOCKERFILE: &str = ".bear-cds.Dockerfile";
+
+/// Everything that goes into building an image.
+#[derive(Debug, Clone)]
+pub struct BuildContext {
+    /// Directory sent to docker as the build context
+    pub dir: PathBuf,
+    /// Dockerfile to build, doesn't have to be inside `dir`
+    pub dockerfile: PathBuf,
+    pub args: BTreeMap<String, String>,
+    pub target: Option<String>,
+    pub platform: Option<String>,
+    /// Local images the dockerfile builds on, tag -> image id. Part of the hash so rebuilding
+    /// a base image rebuilds everything on top of it.
+    pub bases: BTreeMap<String, String>,
+}
+
+impl BuildContext {
+    /// Path of the dockerfile inside the context, if it's in there at all
+    fn dockerfile_in_context(&self) -> Option<PathBuf> {
+        let dir = self.dir.canonicalize().ok()?;
+        let dockerfile = self.dockerfile.canonicalize().ok()?;
+        dockerfile.strip_prefix(dir).ok().map(Path::to_path_buf)
+    }
+
+    /// Path docker should read the dockerfile from, relative to the root of the archive.
+    pub fn dockerfile_name(&self) -> PathBuf {
+        self.dockerfile_in_context()
+            .unwrap_or_else(|| PathBuf::from(INJECTED_DOCKERFILE))
+    }
+
+    /// Every file, directory and symlink in the context, relative to `dir` and sorted so the
+    /// order is stable. A dockerfile from outside the context isn't included.
+    pub fn files(&self) -> Result<Vec<PathBuf>> {
+        let dir = &self.dir;
+        if !dir.is_dir() {
+            return Err(anyhow!(
+                "Failed to read {}. Make sure it exists and is a directory.",
+                dir.display()
+            ));
+        }
+        if !self.dockerfile.is_file() {
+            return Err(anyhow!("No dockerfile at {}", self.dockerfile.display()));
+        }
+        let dockerfile = self.dockerfile_name();
+        let ignore = dockerignore(dir)?;
+        let mut files = Vec::new();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(current) = pending.pop() {
+            for entry in fs::read_dir(&current)? {
+                let entry = entry?;
+                let path = entry.path();
+                let relative = path.strip_prefix(dir)?.to_path_buf();
+                // symlinks are sent as symlinks like docker does, so they're never descended into
+                let is_dir = entry.file_type()?.is_dir();
+                // docker always sends these, even when they're ignored
+                let always = relative == dockerfile || relative == Path::new(".dockerignore");
+                if !always
+                    && ignore
+                        .matched_path_or_any_parents(&relative, is_dir)
+                        .is_ignore()
+                {
+                    continue;
+                }
+                if is_dir {
+                    pending.push(path);
+                }
+                files.push(relative);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Hash of everything that ends up in the build: paths, permissions and contents of the
+    /// context, the dockerfile, and the build settings.
+    pub fn hash(&self) -> Result<String> {
+        let mut hasher = Sha256::new();
+        for relative in self.files()? {
+            hash_entry(&mut hasher, &self.dir.join(&relative), &relative)?;
+        }
+        if self.dockerfile_in_context().is_none() {
+            hash_entry(
+                &mut hasher,
+                &self.dockerfile,
+                Path::new(INJECTED_DOCKERFILE),
+            )?;
+        }
+        for (key, value) in &self.args {
+            hasher.update(format!("arg {key}={value}\0"));
+        }
+        if let Some(target) = &self.target {
+            hasher.update(format!("target {target}\0"));
+        }
+        if let Some(platform) = &self.platform {
+            hasher.update(format!("platform {platform}\0"));
+        }
+        for (tag, id) in &self.bases {
+            hasher.update(format!("base {tag}={id}\0"));
+        }
+        Ok(hex::encode(hasher.finalize()))
+    }
+
+    /// Options to build the context as `tag`, labelled with its hash.
+    pub fn options(&self, tag: &str, hash: &str) -> BuildImageOptions {
+        let mut options = BuildImageOptionsBuilder::new()
+            .dockerfile(&self.dockerfile_name().to_string_lossy())
+            .t(tag)
+            .rm(true)
+            .buildargs(&self.args.clone().into_iter().collect::<HashMap<_, _>>())
+            .labels(&HashMap::from([(HASH_LABEL, hash)]));
+        if let Some(target) = &self.target {
+            options = options.target(target);
+        }
+        if let Some(platform) = &self.platform {
+            options = options.platform(platform);
+        }
+        options.build()
+    }
+
+    /// Stream the build context as a tar. The archive is written on a blocking thread and only
+    /// a few chunks are buffered at a time, so large contexts don't have to fit in memory.
+    pub fn archive(&self) -> impl Stream<Item = io::Result<Bytes>> + Send + 'static {
+        let (tx, rx) = mpsc::channel(8);
+        let context = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let out = BufWriter::with_capacity(64 * 1024, ChannelWriter { tx: tx.clone() });
+            if let Err(e) = context.write_tar(out) {
+                let _ = tx.blocking_send(Err(e));
+            }
+        });
+        stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|chunk| (chunk, rx))
+        })
+    }
+
+    fn write_tar(&self, out: impl Write) -> io::Result<()> {
+        let files = self.files().map_err(io::Error::other)?;
+        let mut tar = tar::Builder::new(out);
+        // docker sends symlinks as symlinks, not whatever they point at
+        tar.follow_symlinks(false);
+        for relative in files {
+            let path = self.dir.join(&relative);
+            if fs::symlink_metadata(&path)?.is_dir() {
+                tar.append_dir(&relative, &path)?;
+            } else {
+                tar.append_path_with_name(&path, &relative)?;
+            }
+        }
+        if self.dockerfile_in_context().is_none() {
+            tar.follow_symlinks(true);
+            tar.append_path_with_name(&self.dockerfile, INJECTED_DOCKERFILE)?;
+        }
+        tar.into_inner()?.flush()
+    }
+}
+
+fn hash_entry(hasher: &mut Sha256, path: &Path, relative: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    hasher.update(relative.to_string_lossy().as_bytes());
+    hasher.update([0]);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        hasher.update(metadata.permissions().mode().to_le_bytes());
+    }
+    if metadata.is_symlink() {
+        hasher.update(fs::read_link(path)?.to_string_lossy().as_bytes());
+    } else if metadata.is_file() {
+        io::copy(&mut fs::File::open(path)?, hasher)?;
+    }
+    hasher.update([0]);
+    Ok(())
+}
+
+/// Sends everything written to it down a channel, one chunk per flush.
+struct ChannelWriter {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "build context was dropped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Context hash of a local image, if it was built by bear-cds
+pub async fn image_hash(image: &str) -> Option<String> {
+    DOCKER
+        .inspect_image(image)
+        .await
+        .ok()?
+        .config?
+        .labels?
+        .remove(HASH_LABEL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::scratch_dir;
+
+    /// A context with the given files (created empty) and `.dockerignore`
+    fn context(files: &[&str], dockerignore: &str) -> BuildContext {
+        let dir = scratch_dir("context");
+        for file in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        fs::write(dir.join(".dockerignore"), dockerignore).unwrap();
+        BuildContext {
+            dockerfile: dir.join("Dockerfile"),
+            dir,
+            args: BTreeMap::new(),
+            target: None,
+            platform: None,
+            bases: BTreeMap::new(),
+        }
+    }
+
+    fn files(context: &BuildContext) -> Vec<String> {
+        context
+            .files()
+            .unwrap()
+            .iter()
+            .map(|f| f.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn negation_brings_files_back() {
+        let context = context(
+            &["Dockerfile", "a.log", "keep.log", "src/b.log"],
+            "*.log\n!keep.log\n",
+        );
+        // patterns are anchored to the root, so src/b.log isn't matched by *.log
+        assert_eq!(
+            files(&context),
+            [
+                ".dockerignore",
+                "Dockerfile",
+                "keep.log",
+                "src",
+                "src/b.log"
+            ]
+        );
+    }
+
+    #[test]
+    fn directory_patterns_skip_everything_inside() {
+        let context = context(
+            &[
+                "Dockerfile",
+                "node_modules/x/index.js",
+                "solve/solve.py",
+                "src/solve/keep.py",
+            ],
+            "# comments are skipped\nnode_modules\n./solve/\n",
+        );
+        assert_eq!(
+            files(&context),
+            [
+                ".dockerignore",
+                "Dockerfile",
+                "src",
+                "src/solve",
+                "src/solve/keep.py",
+            ]
+        );
+    }
+
+    #[test]
+    fn dockerfile_is_always_included() {
+        let context = context(&["Dockerfile", "flag.txt"], "*\n");
+        assert_eq!(files(&context), [".dockerignore", "Dockerfile"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_not_followed() {
+        let context = context(&["Dockerfile", "real/file"], "");
+        std::os::unix::fs::symlink("real", context.dir.join("link")).unwrap();
+        std::os::unix::fs::symlink(".", context.dir.join("real/loop")).unwrap();
+        assert_eq!(
+            files(&context),
+            [
+                ".dockerignore",
+                "Dockerfile",
+                "link",
+                "real",
+                "real/file",
+                "real/loop"
+            ]
+        );
+        let mut tar = Vec::new();
+        context.write_tar(&mut tar).unwrap();
+        let mut archive = tar::Archive::new(tar.as_slice());
+        let link = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .find(|entry| entry.path().unwrap() == Path::new("link"))
+            .unwrap();
+        assert!(link.header().entry_type().is_symlink());
+        assert_eq!(link.link_name().unwrap().unwrap(), Path::new("real"));
+
+        let hash = context.hash().unwrap();
+        fs::write(context.dir.join("real/file"), "changed").unwrap();
+        assert_ne!(context.hash().unwrap(), hash);
+    }
+}
@@ -0,0 +1,333 @@
+//! The caddy ingress that sits in front of every challenge.
+//!
+//! The ingress is just another machine on the backend, running the image in `caddy.tar.gz`.
+//! Routes are pushed to it by curling the caddy admin api from inside the machine.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::Write,
+    path::Path,
+};
+
+use crate::{
+    backend::{DeployBackend, Machine, MachineSpec, Service},
+    Config, DOCKER, LOGS_DIR,
+};
+use anyhow::{anyhow, Result};
+use bollard::{body_full, query_parameters::BuildImageOptionsBuilder};
+use bytes::Bytes;
+use futures::StreamExt;
+use serde_json::json;
+
+/// Name of the ingress machine
+pub const NAME: &str = "ingress";
+/// Local tag of the ingress image
+const IMAGE: &str = "bear-cds-ingress";
+
+#[derive(Debug, Default, Clone)]
+pub struct Routes {
+    /// subdomain -> upstream
+    pub http: HashMap<String, Route>,
+    /// public port -> upstream
+    pub tcp: HashMap<u32, Route>,
+}
+
+impl Routes {
+    /// Stop routing anything to the given machine
+    pub fn remove_machine(&mut self, machine: &str) {
+        self.http.retain(|_, route| route.machine != machine);
+        self.tcp.retain(|_, route| route.machine != machine);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Route {
+    /// Name of the machine traffic is sent to
+    pub machine: String,
+    pub address: String,
+    pub port: u32,
+}
+
+impl Route {
+    pub fn new(machine: &Machine, port: u32) -> Route {
+        Route {
+            machine: machine.name.clone(),
+            address: machine.address.clone(),
+            port,
+        }
+    }
+
+    pub fn dial(&self) -> String {
+        format!("{}:{}", self.address, self.port)
+    }
+}
+
+fn merge(a: &mut serde_json::Value, b: &serde_json::Value) {
+    match (a, b) {
+        (serde_json::Value::Object(a), serde_json::Value::Object(b)) => {
+            for (k, v) in b {
+                merge(a.entry(k.clone()).or_insert(serde_json::Value::Null), v);
+            }
+        }
+        (a, b) => *a = b.clone(),
+    }
+}
+
+/// Build the caddy image and push it to the backend. The build output goes to `ingress.log` in
+/// the logs folder.
+pub async fn build<B: DeployBackend + ?Sized>(backend: &B) -> Result<String> {
+    let ingress_tar = Bytes::from_static(include_bytes!("../caddy.tar.gz"));
+    let mut build = DOCKER.build_image(
+        BuildImageOptionsBuilder::new()
+            .dockerfile("Dockerfile")
+            .t(IMAGE)
+            .rm(true)
+            .build(),
+        None,
+        Some(body_full(ingress_tar)),
+    );
+
+    let logs = Path::new(LOGS_DIR);
+    fs::create_dir_all(logs)?;
+    let log_path = logs.join("ingress.log");
+    let mut log = File::create(&log_path)?;
+    while let Some(build_step) = build.next().await {
+        let build_step = build_step.map_err(|e| anyhow!("building the ingress failed: {e}"))?;
+        if let Some(stream) = &build_step.stream {
+            log.write_all(stream.as_bytes())?;
+        }
+        if let Some(error) = build_step
+            .error_detail
+            .and_then(|detail| detail.message)
+            .or(build_step.error)
+        {
+            writeln!(log, "{error}")?;
+            return Err(anyhow!(
+                "building the ingress failed: {}\nfull log: {}",
+                error.trim(),
+                log_path.display()
+            ));
+        }
+    }
+
+    backend.push_image(IMAGE).await
+}
+
+pub fn spec(image: String, routes: &Routes) -> MachineSpec {
+    let mut services = routes
+        .tcp
+        .keys()
+        .map(|port| Service {
+            port: *port,
+            concurrency: 500,
+        })
+        .collect::<Vec<Service>>();
+    services.push(Service {
+        port: 80,
+        concurrency: 3000,
+    });
+    services.push(Service {
+        port: 443,
+        concurrency: 3000,
+    });
+
+    MachineSpec {
+        image,
+        services,
+        cpus: Some(4),
+        memory_mb: Some(1024),
+        ..Default::default()
+    }
+}
+
+/// Generate the caddy json config for the given routes, with the `[caddy]` section of bear.toml
+/// merged on top.
+pub fn caddy_config(config: &Config, routes: &Routes) -> serde_json::Value {
+    let mut http_expose_json = Vec::with_capacity(routes.http.len());
+    for (sub, route) in &routes.http {
+        http_expose_json.push(json!({
+            "match": [{
+                "host": [format!("{sub}.{}", config.hostname)],
+            }],
+            "handle": [{
+                "handler": "reverse_proxy",
+                "upstreams": [{
+                    "dial": route.dial(),
+                }]
+            }]
+        }));
+    }
+
+    http_expose_json.push(json!({
+        "handle": [{
+            "handler": "static_response",
+            "status_code": 404,
+            "body": "Not Found",
+        }]
+    }));
+
+    let mut tcp_expose_json = HashMap::with_capacity(routes.tcp.len());
+    for (port, route) in &routes.tcp {
+        tcp_expose_json.insert(
+            route.machine.clone(),
+            json!({
+                "listen": [format!("0.0.0.0:{port}")],
+                "routes": [{
+                    "handle": [
+                        {
+                            "handler": "proxy",
+                            "upstreams": [{ "dial": [route.dial()] }]
+                        }
+                    ]
+                }]
+            }),
+        );
+    }
+
+    let mut caddy = json!({
+        "apps": {
+            "layer4": {
+                "servers": tcp_expose_json,
+            },
+            "http":{
+                "servers": {
+                    "bear-cds-http": {
+                        "listen": [":80"],
+                        "routes": [{
+                            "handle": [{
+                                "handler": "subroute",
+                                "routes": http_expose_json,
+                            }],
+                            "match": [{
+                                "host": [format!("*.{}", config.hostname)],
+                            }]
+                        }],
+                    }
+                }
+            }
+        }
+    });
+    merge(&mut caddy, &config.caddy);
+    caddy
+}
+
+/// Read the routes caddy is currently serving back out of its config. `machines` is used to
+/// figure out which machine an upstream address belongs to.
+pub async fn current_routes<B: DeployBackend + ?Sized>(
+    config: &Config,
+    backend: &B,
+    ingress: &Machine,
+    machines: &[Machine],
+) -> Result<Routes> {
+    let output = backend
+        .exec(ingress, vec!["curl", "-s", "localhost:2019/config/"])
+        .await?;
+    let caddy: serde_json::Value =
+        serde_json::from_slice(&output).map_err(|e| anyhow!("failed to read caddy config: {e}"))?;
+
+    let route = |machine: Option<&str>, dial: &str| -> Option<Route> {
+        let (address, port) = dial.rsplit_once(':')?;
+        let machine = machine
+            .map(str::to_string)
+            .or(machines
+                .iter()
+                .find(|m| m.address == address)
+                .map(|m| m.name.clone()))
+            .unwrap_or(address.to_string());
+        Some(Route {
+            machine,
+            address: address.to_string(),
+            port: port.parse().ok()?,
+        })
+    };
+
+    let mut routes = Routes::default();
+    let http =
+        &caddy["apps"]["http"]["servers"]["bear-cds-http"]["routes"][0]["handle"][0]["routes"];
+    for entry in http.as_array().into_iter().flatten() {
+        let host = entry["match"][0]["host"][0].as_str();
+        let dial = entry["handle"][0]["upstreams"][0]["dial"].as_str();
+        if let (Some(host), Some(dial)) = (host, dial) {
+            let sub = host
+                .strip_suffix(&format!(".{}", config.hostname))
+                .unwrap_or(host);
+            if let Some(route) = route(None, dial) {
+                routes.http.insert(sub.to_string(), route);
+            }
+        }
+    }
+
+    let tcp = &caddy["apps"]["layer4"]["servers"];
+    for (name, server) in tcp.as_object().into_iter().flatten() {
+        let port = server["listen"][0]
+            .as_str()
+            .and_then(|listen| listen.rsplit_once(':'))
+            .and_then(|(_, port)| port.parse::<u32>().ok());
+        let dial = server["routes"][0]["handle"][0]["upstreams"][0]["dial"][0].as_str();
+        if let (Some(port), Some(dial)) = (port, dial) {
+            if let Some(route) = route(Some(name), dial) {
+                routes.tcp.insert(port, route);
+            }
+        }
+    }
+    Ok(routes)
+}
+
+/// Create or update the ingress machine so it exposes every tcp port, then load the routes
+/// into caddy.
+pub async fn update<B: DeployBackend + ?Sized>(
+    config: &Config,
+    backend: &B,
+    existing: Option<&Machine>,
+    routes: &Routes,
+) -> Result<Machine> {
+    let machine = match existing {
+        Some(machine) => {
+            let spec = spec(machine.image.clone(), routes);
+            backend.update(machine, &spec).await?
+        }
+        None => {
+            println!("Caddy server not found. Building and deploying.");
+            let image = build(backend).await?;
+            backend.create(NAME, &spec(image, routes)).await?
+        }
+    };
+
+    println!("Waiting on ingress to start");
+    backend.wait(&machine).await?;
+    println!("Ingress Updated");
+
+    let caddy = caddy_config(config, routes);
+    // caddy answers errors with a json body, so print the status after it to tell them apart
+    let output = backend
+        .exec(
+            &machine,
+            vec![
+                "curl",
+                "-sS",
+                "-w",
+                "\n%{http_code}",
+                "localhost:2019/load",
+                "-H",
+                "Content-Type: application/json",
+                "-d",
+                &caddy.to_string(),
+            ],
+        )
+        .await
+        .map_err(|e| anyhow!("failed to load the caddy config: {e}"))?;
+    let output = String::from_utf8(output).map_err(|e| anyhow!("invalid caddy response: {e}"))?;
+    let (body, status) = output.trim_end().rsplit_once('\n').unwrap_or(("", &output));
+    if status.trim() != "200" {
+        return Err(anyhow!(
+            "caddy rejected the config ({}): {}",
+            status.trim(),
+            body.trim()
+        ));
+    }
+    if !body.trim().is_empty() {
+        println!("{}", body.trim());
+    }
+    Ok(machine)
+}
@@ -1,63 +1,32 @@
 //! A highly opinionated challenge deployment system by Les Amateurs!
-//! 
-//! Currently, it deploys challenges to fly.io and manages them. It is designed to be used with the rCTF platform. We may add support for different deployment targets in the future (e.g. AWS, GCP, etc).
-//! 
-//! Under the hood, we heavily take advantage of the fly api, and also use docker to build and run challenges. Caddy is also used to serve the challenges. (Caddy is hosted as a machine on fly.io and is used as a reverse proxy to the challenges.)
-//! 
-//! The tool is designed to be used with a specific directory structure. The root directory should contain a `bear.toml` file with the following structure:
-//! 
-//! ```
-//! [fly]
-//! org = "your-fly-org-name"
-//! app_name = "your-app-name"
-//! 
-//! [rctf]
-//! url = "https://rctf.your-ctf.com"
-//! ```
-//! 
-//! The credentials are stored inside a `.env` file in the root directory. The `.env` file should contain the following:
-//! 
-//! ```
-//! RCTF_ADMIN_TOKEN="your-rctf-api-token"
-//! FLY_API_HOSTNAME="your-fly-api-hostname"
-//! FLY_API_TOKEN="your-fly-api-token"
-//! ```
-//! 
-//! Check out the example_repo for a sample directory structure. Create a folder for each challenge category and create a folder inside of that for each challenge.
-//! 
-//! ```tree
-//! ├── bear.toml
-//! ├── crypto
-//! │   ├── aesy
-//! │   │   ├── challenge.toml
-//! │   │   └── flag.txt
-//! │   ├── rsa
-//! │   │    ├── challenge.toml
-//! │   │    └── ...
-//! ├── another-category-here
-//! │   └──  ...
-//! ├── .env
-//! ```
-//! 
+//!
+//! Challenges are built with docker and run on a deployment backend behind a caddy reverse proxy.
+//! Backends are pluggable through the `DeployBackend` trait and scoreboards through the
+//! `ScoreboardPlatform` trait, so support for other deployment targets (e.g. AWS, GCP, etc) and
+//! scoreboards can be added without touching the rest of the tool.
+//!
+//! See readme.md for how to set up a challenge repo and what goes in bear.toml.
 
 use anyhow::Result;
 use bollard::Docker;
 
-use challenge::Challenge;
-use clap::{Parser, Subcommand};
-use dotenvy;
-use futures::StreamExt;
+use clap::{Parser, Subcommand, ValueEnum};
+use colored::Colorize;
 use lazy_static::lazy_static;
+use schemars::JsonSchema;
 use serde::Deserialize;
 use serde_json::json;
-use std::{fs, path::PathBuf, process::exit};
-use temp_dir::TempDir;
-use toml;
+use std::{collections::BTreeMap, fs, path::PathBuf, process::exit};
 
+mod backend;
 mod challenge;
 mod commands;
-mod fly;
-mod rctf;
+mod context;
+mod ingress;
+#[cfg(test)]
+mod mock;
+mod scoreboard;
+mod state;
 
 lazy_static! {
     pub static ref DOCKER: Docker =
@@ -73,21 +42,70 @@ macro_rules! print_error {
     });
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 /// Configuration struct for the application.
 pub struct Config {
-    /// Configuration for fly.io
-    pub fly: fly::Config,
+    /// Configuration for the deployment backend
+    pub backend: backend::Config,
     /// Configuration for rCTF
-    pub rctf: Option<rctf::Config>,
+    pub rctf: Option<scoreboard::rctf::Config>,
+    /// Configuration for CTFd
+    pub ctfd: Option<scoreboard::ctfd::Config>,
+    #[serde(default)]
+    /// More scoreboards to deploy challenges to, e.g. a mirror or a static JSON export
+    pub scoreboards: Vec<scoreboard::Config>,
     #[serde(default = "default_chall_root")]
+    #[schemars(extend("default" = "."))]
     /// Root directory for challenges (defaults to current directory)
     pub chall_root: PathBuf,
+    #[serde(default)]
+    /// Directories under the challenge root that aren't searched for challenges, in gitignore syntax (e.g. `_drafts`)
+    pub ignore: Vec<String>,
     /// Hostname for the caddy machine
     pub hostname: String,
     #[serde(default = "default_caddy")]
     /// Caddy configuration
     pub caddy: serde_json::Value,
+    #[serde(default = "default_state_dir")]
+    #[schemars(extend("default" = ".bear"))]
+    /// Where the deployment state and its lock are kept (defaults to .bear). Point it at a
+    /// directory everyone who deploys can reach (e.g. a shared mount) to share the state
+    pub state_dir: PathBuf,
+    #[serde(default)]
+    /// Base images shared between challenges, built before anything that uses them
+    pub images: BTreeMap<String, challenge::BuildConfig>,
+    /// Default points for challenges, unless their category.toml or challenge.toml says otherwise
+    pub points: Option<challenge::Points>,
+    /// Default for whether challenges count for breaking ties
+    pub tiebreak: Option<bool>,
+}
+
+impl Config {
+    /// Parse bear.toml. Configs from before `[backend]` existed have a `[fly]` table instead,
+    /// which is read as `[backend]` with `type = "fly"`.
+    pub fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        let error = match toml::from_str(text) {
+            Ok(config) => return Ok(config),
+            Err(e) => e,
+        };
+        let Ok(mut table) = text.parse::<toml::Table>() else {
+            return Err(error);
+        };
+        if table.contains_key("backend") {
+            return Err(error);
+        }
+        let Some(toml::Value::Table(mut fly)) = table.remove("fly") else {
+            return Err(error);
+        };
+        fly.insert("type".to_string(), "fly".into());
+        table.insert("backend".to_string(), fly.into());
+        let config = table.try_into()?;
+        eprintln!(
+            "{} [fly] in bear.toml is now [backend] with type = \"fly\", please rename it",
+            "WARNING:".yellow().bold()
+        );
+        Ok(config)
+    }
 }
 
 fn default_chall_root() -> PathBuf {
@@ -99,6 +117,13 @@ fn default_caddy() -> serde_json::Value {
     json!({})
 }
 
+fn default_state_dir() -> PathBuf {
+    PathBuf::from(".bear")
+}
+
+/// Build logs, kept per checkout
+pub const LOGS_DIR: &str = ".bear/logs";
+
 #[derive(Parser)]
 #[command(version, about = "---les amateurs challenge deployment system---", long_about = None)]
 struct Args {
@@ -119,29 +144,194 @@ pub enum Commands {
 
     /// Build all challenges
     Build {
-        /// Max number of challenges to build in parellel
+        /// Max number of images to build in parallel
         #[arg(long, default_value = "4")]
         threads: usize,
+        /// Rebuild images even if their build context hasn't changed
+        #[arg(long)]
+        force: bool,
         #[arg()]
         /// List of challenges to build
         challs: Option<Vec<String>>,
     },
 
-    /// Deploy all challenges to fly.io
+    /// Check every challenge for mistakes without deploying anything
+    Check,
+
+    /// Deploy all challenges to the configured backend
     Deploy {
+        /// Only show what would change, same as `plan`
+        #[arg(long)]
+        dry_run: bool,
         #[arg()]
         /// List of challenges to deploy
         challs: Option<Vec<String>>,
     },
 
+    /// Show what deploy would create, update and delete
+    Plan {
+        #[arg()]
+        /// List of challenges to plan
+        challs: Option<Vec<String>>,
+    },
+
+    /// Destroy the machines, routes and rCTF entries of challenges
+    Destroy {
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+        #[arg(required = true)]
+        /// List of challenges to destroy
+        challs: Vec<String>,
+    },
+
+    /// Destroy machines and rCTF challenges that are no longer in the repo
+    Prune {
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Sync challenges with rCTF, or import challenges from it
+    Rctf {
+        #[command(subcommand)]
+        command: RctfCommand,
+    },
+
     /// Fetch the leaderboard and save it to ctftime.json
-    Leaderboard,
+    Leaderboard {
+        /// Scoreboard to fetch it from, defaults to the first one in bear.toml
+        #[arg(long)]
+        scoreboard: Option<String>,
+    },
+
+    /// Print the JSON Schema of challenge.toml or bear.toml, for editor completion and validation
+    Schema {
+        #[arg(value_enum)]
+        file: SchemaFile,
+    },
+
+    /// Create bear.toml, .env.example and a directory per category
+    Init {
+        /// Backend bear.toml is set up for
+        #[arg(long, value_enum, default_value = "local")]
+        backend: InitBackend,
+        /// Keep the deploy state in this directory instead of .bear, e.g. a mount everyone who
+        /// deploys shares
+        #[arg(long)]
+        state_dir: Option<PathBuf>,
+        /// Where to create the repo
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+    },
+
+    /// Create a new challenge from a template
+    New {
+        /// Template to start from (defaults to the category if there is a template for it)
+        #[arg(short, long, value_enum)]
+        template: Option<Template>,
+        /// Author of the challenge (defaults to `git config user.name`)
+        #[arg(long)]
+        author: Option<String>,
+        /// Id of the challenge, e.g. web/my-challenge or pwn/heap/part1
+        id: String,
+    },
+
+    /// Export the challenge repo so it can be hosted without bear-cds
+    Export {
+        #[command(subcommand)]
+        format: ExportFormat,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+/// Formats the challenge repo can be exported to
+pub enum ExportFormat {
+    /// Write a docker-compose.yml and Caddyfile for every challenge
+    Compose {
+        /// Directory to write to (defaults to the challenge root)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+/// Ways to keep rCTF and the repo in sync
+pub enum RctfCommand {
+    /// Update the challenges on rCTF that differ from the repo
+    Sync {
+        /// Only show what would change
+        #[arg(long)]
+        dry_run: bool,
+        /// Also delete challenges bear-cds made that are no longer in the repo
+        #[arg(long)]
+        prune: bool,
+        /// Don't ask for confirmation before deleting
+        #[arg(short, long)]
+        yes: bool,
+        /// rCTF scoreboard to sync, defaults to every one in bear.toml
+        #[arg(long)]
+        scoreboard: Option<String>,
+        #[arg()]
+        /// List of challenges to sync
+        challs: Option<Vec<String>>,
+    },
+    /// Import challenges from rCTF into challenge.toml files
+    Pull {
+        /// Directory to write to (defaults to the challenge root)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// rCTF scoreboard to pull from, defaults to the first one in bear.toml
+        #[arg(long)]
+        scoreboard: Option<String>,
+        #[arg()]
+        /// rCTF ids of the challenges to pull (defaults to every one not made by bear-cds)
+        ids: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+/// Files there is a schema for
+pub enum SchemaFile {
+    /// challenge.toml
+    Challenge,
+    /// bear.toml
+    Config,
+    /// category.toml
+    Category,
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+/// Backends `bear init` can set up
+pub enum InitBackend {
+    Local,
+    Fly,
+    Kubernetes,
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+/// Challenge templates for `bear new`
+pub enum Template {
+    Web,
+    Pwn,
+    Crypto,
+    Rev,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
     let _ = dotenvy::dotenv();
+    // these don't need a bear.toml
+    match args.command {
+        Commands::Schema { file } => return commands::schema::command(file),
+        Commands::Init {
+            backend,
+            state_dir,
+            dir,
+        } => return commands::init::init(dir, backend, state_dir),
+        _ => (),
+    }
     let config_file = match fs::read_to_string(args.config) {
         Ok(f) => f,
         Err(_) => {
@@ -153,54 +343,59 @@ async fn main() -> Result<()> {
         }
     };
 
-    let config: Config = match toml::from_str(&config_file) {
+    let config = match Config::parse(&config_file) {
         Ok(c) => c,
-        Err(_) => {
-            print_error!("{}", "Failed to parse bear.toml");
+        Err(e) => {
+            print_error!("Failed to parse bear.toml");
+            eprintln!("{e}");
             exit(1);
         }
     };
 
     match args.command {
-        Commands::List => commands::list::command(config)?,
-        Commands::Build { threads, challs } => {
-            let tmp_dir = TempDir::new().unwrap();
-            let challs = if let Some(challs) = challs {
-                Challenge::get_some(&config.chall_root, challs)?
-            } else {
-                Challenge::get_all(&config.chall_root)?
-            };
-            match challs.len() {
-                1 => println!("Building {}", challs[0].id),
-                2 => println!("Building {} and {}", challs[0].id, challs[1].id),
-                _ => {
-                    println!(
-                        "Building {}, {} and {}",
-                        challs[0].id,
-                        challs[1].id,
-                        if challs.len() > 3 {
-                            "more"
-                        } else {
-                            &challs[2].id
-                        },
-                    )
-                }
-            }
-
-            futures::stream::iter(
-                challs
-                    .into_iter()
-                    .map(|c| c.build(&config.chall_root, &tmp_dir)),
-            )
-            .buffer_unordered(threads)
-            .collect::<Vec<Result<Vec<_>>>>()
-            .await
-            .into_iter()
-            .collect::<Result<Vec<Vec<_>>>>()?;
-            ()
+        Commands::List => commands::list::command(config).await?,
+        Commands::Build {
+            threads,
+            force,
+            challs,
+        } => commands::build::command(config, challs, threads, force).await?,
+        Commands::Check => commands::check::command(config)?,
+        Commands::Deploy {
+            dry_run: true,
+            challs,
+        }
+        | Commands::Plan { challs } => commands::plan::command(config, challs).await?,
+        Commands::Deploy { challs, .. } => commands::deploy::command(config, challs).await?,
+        Commands::Destroy { yes, challs } => {
+            commands::destroy::destroy(config, challs, yes).await?
+        }
+        Commands::Prune { yes } => commands::destroy::prune(config, yes).await?,
+        Commands::Schema { .. } | Commands::Init { .. } => unreachable!(),
+        Commands::New {
+            template,
+            author,
+            id,
+        } => commands::init::new(config, id, template, author)?,
+        Commands::Rctf { command } => match command {
+            RctfCommand::Sync {
+                dry_run,
+                prune,
+                yes,
+                scoreboard,
+                challs,
+            } => commands::rctf::sync(config, scoreboard, challs, dry_run, prune, yes).await?,
+            RctfCommand::Pull {
+                output,
+                scoreboard,
+                ids,
+            } => commands::rctf::pull(config, scoreboard, ids, output).await?,
+        },
+        Commands::Leaderboard { scoreboard } => {
+            commands::leaderboard::command(config, scoreboard).await?
         }
-        Commands::Deploy { challs } => commands::deploy::command(config, challs).await?,
-        Commands::Leaderboard => commands::leaderboard::command(config).await?,
+        Commands::Export { format } => match format {
+            ExportFormat::Compose { output } => commands::export::compose(config, output)?,
+        },
     }
 
     Ok(())
@@ -0,0 +1,258 @@
+//! Helpers for tests: a tiny HTTP server to point API clients at, scratch directories, and a
+//! backend and scoreboard that don't talk to anything.
+
+use crate::{
+    backend::{DeployBackend, Machine, MachineSpec},
+    challenge::{Challenge, ProvidedFile},
+    ingress::Routes,
+    scoreboard::ScoreboardPlatform,
+    state::ScoreboardState,
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// A request the server got
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    /// Path and query, e.g. `/api/v1/challenges?view=admin`
+    pub path: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or(Value::Null)
+    }
+
+    /// Path split on `/`, without the query
+    pub fn segments(&self) -> Vec<&str> {
+        self.path
+            .split('?')
+            .next()
+            .unwrap()
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect()
+    }
+}
+
+/// Answers every request with whatever `handler` returns, as JSON. Runs until the test ends.
+pub struct Server {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Server {
+    pub fn start(handler: impl FnMut(&Request) -> (u16, Value) + Send + 'static) -> Server {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        let handler = Mutex::new(handler);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let Some(request) = read_request(&mut stream) else {
+                    continue;
+                };
+                let (status, body) = (handler.lock().unwrap())(&request);
+                seen.lock().unwrap().push(request);
+                let body = body.to_string();
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {status} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+            }
+        });
+        Server { url, requests }
+    }
+
+    /// Everything requested so far, oldest first
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Requests other than GETs, as `METHOD /path`
+    pub fn changes(&self) -> Vec<String> {
+        self.requests()
+            .iter()
+            .filter(|r| r.method != "GET")
+            .map(|r| format!("{} {}", r.method, r.path))
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.requests.lock().unwrap().clear();
+    }
+}
+
+fn read_request(stream: &mut impl Read) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (key, value) = line.split_once(':')?;
+        headers.push((key.trim().to_string(), value.trim().to_string()));
+    }
+    let mut request = Request {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+    let length = request
+        .header("Content-Length")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(0);
+    request.body = vec![0; length];
+    reader.read_exact(&mut request.body).ok()?;
+    Some(request)
+}
+
+/// A fresh, empty directory under the system temp dir
+pub fn scratch_dir(name: &str) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "bear-cds-{name}-{}-{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A scoreboard that names challenges `fake-<id>` and changes nothing
+pub struct FakeScoreboard;
+
+#[async_trait]
+impl ScoreboardPlatform for FakeScoreboard {
+    fn name(&self) -> &str {
+        "fake"
+    }
+
+    async fn upsert(
+        &self,
+        _config: &crate::Config,
+        chall: &Challenge,
+        state: &mut ScoreboardState,
+    ) -> Result<Vec<String>> {
+        state.id = format!("fake-{}", chall.id);
+        Ok(vec![])
+    }
+
+    async fn delete(&self, _state: &ScoreboardState) -> Result<()> {
+        Ok(())
+    }
+
+    async fn upload_files(&self, _id: &str, files: Vec<ProvidedFile>) -> Result<Vec<String>> {
+        Ok(files.into_iter().map(|f| f.name).collect())
+    }
+
+    async fn scoreboard(&self) -> Result<Value> {
+        Ok(Value::Null)
+    }
+
+    fn default_state(&self, chall_id: &str) -> Option<ScoreboardState> {
+        Some(ScoreboardState {
+            id: format!("fake-{chall_id}"),
+            files: None,
+        })
+    }
+}
+
+/// A backend with a fixed set of machines and routes, that fails anything that would change them
+#[derive(Default)]
+pub struct FakeBackend {
+    pub machines: Vec<Machine>,
+    pub routes: Routes,
+}
+
+impl FakeBackend {
+    /// A running machine called `name` on `image`
+    pub fn machine(name: &str, image: &str, digest: Option<&str>) -> Machine {
+        Machine {
+            id: format!("{name}-id"),
+            name: name.to_string(),
+            state: "started".to_string(),
+            image: image.to_string(),
+            digest: digest.map(str::to_string),
+            address: format!("{name}.internal"),
+        }
+    }
+}
+
+#[async_trait]
+impl DeployBackend for FakeBackend {
+    async fn ensure(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<Machine>> {
+        Ok(self.machines.clone())
+    }
+
+    async fn create(&self, name: &str, _spec: &MachineSpec) -> Result<Machine> {
+        Err(anyhow!("fake backend can't create {name}"))
+    }
+
+    async fn update(&self, machine: &Machine, _spec: &MachineSpec) -> Result<Machine> {
+        Err(anyhow!("fake backend can't update {}", machine.name))
+    }
+
+    async fn destroy(&self, machine: &Machine) -> Result<()> {
+        Err(anyhow!("fake backend can't destroy {}", machine.name))
+    }
+
+    async fn wait(&self, _machine: &Machine) -> Result<()> {
+        Ok(())
+    }
+
+    async fn exec(&self, machine: &Machine, _command: Vec<&str>) -> Result<Vec<u8>> {
+        Err(anyhow!("fake backend can't exec in {}", machine.name))
+    }
+
+    async fn push_image(&self, image: &str) -> Result<String> {
+        Err(anyhow!("fake backend can't push {image}"))
+    }
+
+    async fn current_routes(
+        &self,
+        _config: &crate::Config,
+        _existing: Option<&Machine>,
+        _machines: &[Machine],
+    ) -> Result<Routes> {
+        Ok(self.routes.clone())
+    }
+}
@@ -0,0 +1,770 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::env;
+
+use super::ScoreboardPlatform;
+use crate::{
+    challenge::{Challenge, Points, ProvidedFile},
+    state::ScoreboardState,
+};
+
+#[derive(Deserialize, Clone, JsonSchema)]
+#[schemars(rename = "CtfdConfig")]
+pub struct Config {
+    pub url: String,
+    /// Solves it takes for a challenge with a point range to drop to its minimum
+    #[serde(default = "default_decay")]
+    pub decay: u32,
+    /// What the scoreboard is called in output and in the state (defaults to ctfd)
+    pub name: Option<String>,
+    #[serde(default = "default_token_env")]
+    #[schemars(extend("default" = "CTFD_ADMIN_TOKEN"))]
+    /// Environment variable the admin token is read from
+    pub token_env: String,
+}
+
+impl Config {
+    /// What the scoreboard is called in output and in the state
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or("ctfd".to_string())
+    }
+}
+
+fn default_decay() -> u32 {
+    50
+}
+
+fn default_token_env() -> String {
+    "CTFD_ADMIN_TOKEN".to_string()
+}
+
+/// A CTFd instance, talked to through its API with an admin token
+pub struct Ctfd {
+    name: String,
+    url: String,
+    auth: String,
+    decay: u32,
+}
+
+#[derive(Deserialize)]
+struct Data<T> {
+    data: T,
+}
+
+/// Anything CTFd lists under a challenge (flags, tags, hints, files), only the id is needed to
+/// delete it
+#[derive(Deserialize)]
+struct Item {
+    id: u64,
+    #[serde(flatten)]
+    fields: serde_json::Map<String, Value>,
+}
+
+impl Item {
+    fn field(&self, name: &str) -> Value {
+        self.fields.get(name).cloned().unwrap_or(Value::Null)
+    }
+}
+
+fn error(name: &str, action: &str, err: ureq::Error) -> anyhow::Error {
+    anyhow!(
+        "{action} failed ({name}): {:?}",
+        err.into_response().map(|resp| resp.into_string())
+    )
+}
+
+/// Tag bear-cds puts on every challenge it deploys to CTFd, so it can tell its own challenges
+/// from ones that were made by hand
+fn marker(chall: &Challenge) -> String {
+    format!("bear-cds:{}", chall.id)
+}
+
+/// Hash of the names and contents of `files`
+fn files_hash(files: &[ProvidedFile]) -> String {
+    let mut hasher = Sha256::new();
+    for file in files {
+        hasher.update(file.name.as_bytes());
+        hasher.update([0]);
+        hasher.update(file.sha256());
+    }
+    hex::encode(hasher.finalize())
+}
+
+impl Ctfd {
+    pub fn new(config: &Config) -> Result<Ctfd> {
+        let token =
+            env::var(&config.token_env).map_err(|_| anyhow!("${} not found", config.token_env))?;
+        Ok(Ctfd {
+            name: config.name(),
+            url: config.url.clone(),
+            auth: format!("Token {token}"),
+            decay: config.decay,
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/api/v1/{path}", self.url)
+    }
+
+    /// Send the request with an optional body and return the response json, or `None` on a
+    /// 404. ureq blocks, so this runs on a blocking thread.
+    async fn call(
+        &self,
+        action: String,
+        request: ureq::Request,
+        body: Option<Vec<u8>>,
+    ) -> Result<Option<Value>> {
+        let name = self.name.clone();
+        tokio::task::spawn_blocking(move || {
+            let response = match body {
+                Some(body) => request.send_bytes(&body),
+                None => request.call(),
+            };
+            match response {
+                Ok(response) => Ok(Some(response.into_json()?)),
+                Err(ureq::Error::Status(404, _)) => Ok(None),
+                Err(err) => Err(error(&name, &action, err)),
+            }
+        })
+        .await?
+    }
+
+    /// `data` of a response
+    fn data<T: DeserializeOwned>(&self, action: &str, response: Option<Value>) -> Result<T> {
+        let response =
+            response.ok_or_else(|| anyhow!("{action} failed ({}): not found", self.name))?;
+        Ok(serde_json::from_value::<Data<T>>(response)?.data)
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let action = format!("GET {path}");
+        let request = ureq::get(&self.url(path)).set("Authorization", &self.auth);
+        let response = self.call(action.clone(), request, None).await?;
+        self.data(&action, response)
+    }
+
+    async fn send<T: DeserializeOwned>(&self, method: &str, path: &str, body: Value) -> Result<T> {
+        let action = format!("{method} {path}");
+        let request = ureq::request(method, &self.url(path))
+            .set("Authorization", &self.auth)
+            .set("Content-Type", "application/json");
+        let response = self
+            .call(action.clone(), request, Some(body.to_string().into_bytes()))
+            .await?;
+        self.data(&action, response)
+    }
+
+    async fn remove(&self, path: &str) -> Result<()> {
+        let request = ureq::delete(&self.url(path)).set("Authorization", &self.auth);
+        self.call(format!("DELETE {path}"), request, None).await?;
+        Ok(())
+    }
+
+    /// Challenge fields as CTFd takes them. A point range becomes a dynamic challenge, whose
+    /// `value` is left out since CTFd lowers it as the challenge gets solved.
+    fn fields(&self, config: &crate::Config, chall: &Challenge) -> Value {
+        let mut fields = json!({
+            "name": chall.name,
+            "category": chall.category,
+            "description": chall.render_description(&config.hostname),
+            "state": if chall.hidden == Some(true) { "hidden" } else { "visible" },
+        });
+        match chall.points.clone().unwrap_or_default() {
+            Points::Fixed(points) => {
+                fields["type"] = json!("standard");
+                fields["value"] = json!(points);
+            }
+            Points::Range { min, max } => {
+                fields["type"] = json!("dynamic");
+                fields["initial"] = json!(max);
+                fields["minimum"] = json!(min);
+                fields["decay"] = json!(self.decay);
+                fields["function"] = json!("logarithmic");
+            }
+        }
+        fields
+    }
+
+    /// Replace whatever CTFd has under `/challenges/{id}/{kind}` with `desired` if the `compare`
+    /// fields differ. Returns whether anything changed.
+    async fn replace(
+        &self,
+        id: u64,
+        kind: &str,
+        compare: &[&str],
+        desired: Vec<Value>,
+    ) -> Result<bool> {
+        let current: Vec<Item> = self.get(&format!("challenges/{id}/{kind}")).await?;
+        let mut before = current
+            .iter()
+            .map(|item| {
+                compare
+                    .iter()
+                    .map(|f| item.field(f))
+                    .collect::<Vec<Value>>()
+            })
+            .collect::<Vec<_>>();
+        let mut after = desired
+            .iter()
+            .map(|item| {
+                compare
+                    .iter()
+                    .map(|f| item[f].clone())
+                    .collect::<Vec<Value>>()
+            })
+            .collect::<Vec<_>>();
+        before.sort_by_key(|fields| fields.iter().map(Value::to_string).collect::<String>());
+        after.sort_by_key(|fields| fields.iter().map(Value::to_string).collect::<String>());
+        if before == after {
+            return Ok(false);
+        }
+        for item in current {
+            self.remove(&format!("{kind}/{}", item.id)).await?;
+        }
+        for mut item in desired {
+            item["challenge"] = json!(id);
+            self.send::<Value>("POST", kind, item).await?;
+        }
+        Ok(true)
+    }
+
+    async fn upload_file(&self, id: &str, file: &ProvidedFile) -> Result<String> {
+        let boundary = format!("bear-cds-{}", &file.sha256()[..24]);
+        let mut body = Vec::new();
+        for (name, value) in [("challenge", id), ("type", "challenge")] {
+            body.extend(
+                format!(
+                    "--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+                )
+                .as_bytes(),
+            );
+        }
+        body.extend(
+            format!(
+                "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+                file.name.replace('"', "")
+            )
+            .as_bytes(),
+        );
+        body.extend(&file.data);
+        body.extend(format!("\r\n--{boundary}--\r\n").as_bytes());
+        #[derive(Deserialize)]
+        struct Uploaded {
+            location: String,
+        }
+        let action = format!("Upload of {}", file.name);
+        let request = ureq::post(&self.url("files"))
+            .set("Authorization", &self.auth)
+            .set(
+                "Content-Type",
+                &format!("multipart/form-data; boundary={boundary}"),
+            );
+        let response = self.call(action.clone(), request, Some(body)).await?;
+        let uploaded: Vec<Uploaded> = self.data(&action, response)?;
+        Ok(uploaded
+            .into_iter()
+            .next()
+            .map(|f| f.location)
+            .unwrap_or_default())
+    }
+
+    /// Find the challenge on CTFd, by the id bear-cds created it with or else by its marker tag.
+    /// A challenge with the same name and category but without the marker was made by someone
+    /// else and isn't taken over unless it's imported on purpose.
+    async fn find(&self, chall: &Challenge, state: &ScoreboardState) -> Result<Option<u64>> {
+        #[derive(Deserialize)]
+        struct Listed {
+            id: u64,
+            name: String,
+            category: String,
+        }
+        let listed: Vec<Listed> = self.get("challenges?view=admin").await?;
+        if let Some(id) = state
+            .id
+            .parse::<u64>()
+            .ok()
+            .filter(|id| listed.iter().any(|c| c.id == *id))
+        {
+            return Ok(Some(id));
+        }
+        let marker = marker(chall);
+        let tags: Vec<Item> = self.get("tags").await?;
+        if let Some(id) = tags
+            .iter()
+            .find(|tag| tag.field("value") == marker.as_str())
+            .and_then(|tag| tag.field("challenge_id").as_u64())
+        {
+            return Ok(Some(id));
+        }
+        match listed
+            .iter()
+            .find(|c| c.name == chall.name && c.category == chall.category)
+        {
+            Some(other) => Err(anyhow!(
+                "{} already has a challenge called {} in {} (id {}) that bear-cds didn't create. \
+                 To import it, add the tag `{marker}` to it on {}, otherwise rename one of them",
+                self.name,
+                chall.name,
+                chall.category,
+                other.id,
+                self.name
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl ScoreboardPlatform for Ctfd {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Create or update `chall` on CTFd along with its flag, hints, tags and files. Hidden
+    /// challenges are kept on CTFd, just hidden from players.
+    async fn upsert(
+        &self,
+        config: &crate::Config,
+        chall: &Challenge,
+        state: &mut ScoreboardState,
+    ) -> Result<Vec<String>> {
+        let mut fields = self.fields(config, chall);
+        let mut changes = Vec::new();
+        let id = match self.find(chall, state).await? {
+            Some(id) => {
+                let current: Value = self.get(&format!("challenges/{id}")).await?;
+                // fields the server doesn't return (e.g. `function` on older CTFd) can't be
+                // compared, they're still sent when something else changed
+                let changed = fields
+                    .as_object()
+                    .unwrap()
+                    .iter()
+                    .filter(|(key, value)| {
+                        current
+                            .get(key.as_str())
+                            .is_some_and(|current| current != *value)
+                    })
+                    .map(|(key, _)| key.clone())
+                    .collect::<Vec<String>>();
+                if !changed.is_empty() {
+                    self.send::<Value>("PATCH", &format!("challenges/{id}"), fields)
+                        .await?;
+                    changes.extend(changed);
+                }
+                id
+            }
+            None => {
+                #[derive(Deserialize)]
+                struct Created {
+                    id: u64,
+                }
+                changes.push("created".to_string());
+                state.files = None;
+                // creating any challenge takes a value, a dynamic one starts at its initial value
+                if fields.get("value").is_none() {
+                    fields["value"] = fields["initial"].clone();
+                }
+                self.send::<Created>("POST", "challenges", fields).await?.id
+            }
+        };
+        state.id = id.to_string();
+
+        let flag = json!({ "content": chall.get_flag()?, "type": "static", "data": "" });
+        if self.replace(id, "flags", &["content"], vec![flag]).await? {
+            changes.push("flag".to_string());
+        }
+        let hints = chall
+            .hints
+            .iter()
+            .map(|hint| json!({ "content": hint.content, "cost": hint.cost }))
+            .collect();
+        if self
+            .replace(id, "hints", &["content", "cost"], hints)
+            .await?
+        {
+            changes.push("hints".to_string());
+        }
+        let tags = chall
+            .tags
+            .iter()
+            .chain([&marker(chall)])
+            .map(|tag| json!({ "value": tag }))
+            .collect();
+        if self.replace(id, "tags", &["value"], tags).await? {
+            changes.push("tags".to_string());
+        }
+
+        // CTFd doesn't say what's in the files it has, so compare against what was uploaded last
+        let files = chall.provided_files()?;
+        let hash = files_hash(&files);
+        let current: Vec<Item> = self.get(&format!("challenges/{id}/files")).await?;
+        if state.files.as_ref() != Some(&hash) || current.len() != files.len() {
+            for file in current {
+                self.remove(&format!("files/{}", file.id)).await?;
+            }
+            self.upload_files(&state.id, files).await?;
+            state.files = Some(hash);
+            changes.push("files".to_string());
+        }
+        Ok(changes)
+    }
+
+    async fn delete(&self, state: &ScoreboardState) -> Result<()> {
+        self.remove(&format!("challenges/{}", state.id)).await
+    }
+
+    async fn upload_files(&self, id: &str, files: Vec<ProvidedFile>) -> Result<Vec<String>> {
+        let mut locations = Vec::with_capacity(files.len());
+        for file in &files {
+            locations.push(self.upload_file(id, file).await?);
+        }
+        Ok(locations)
+    }
+
+    async fn scoreboard(&self) -> Result<Value> {
+        #[derive(Deserialize)]
+        struct Standing {
+            pos: u64,
+            name: String,
+            score: i64,
+        }
+        let standings: Vec<Standing> = self.get("scoreboard").await?;
+        Ok(json!({
+            "standings": standings
+                .into_iter()
+                .map(|s| json!({ "pos": s.pos, "team": s.name, "score": s.score }))
+                .collect::<Vec<Value>>(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{challenge::Category, mock::Server};
+    use std::{
+        collections::BTreeMap,
+        fs,
+        sync::{Arc, Mutex},
+    };
+
+    /// What the mock CTFd has: challenges by id, and flags, hints, tags and files as
+    /// `(kind, item)`
+    #[derive(Default)]
+    struct Board {
+        challenges: BTreeMap<u64, Value>,
+        items: Vec<(String, Value)>,
+        next: u64,
+    }
+
+    impl Board {
+        fn id(&mut self) -> u64 {
+            self.next += 1;
+            self.next
+        }
+    }
+
+    /// The form field `name` of a multipart body
+    fn form_field(body: &str, name: &str) -> String {
+        let start = body.find(&format!("name=\"{name}\"\r\n\r\n")).unwrap();
+        let value = &body[start..].split_once("\r\n\r\n").unwrap().1;
+        value.split_once("\r\n").unwrap().0.to_string()
+    }
+
+    fn ctfd() -> (Server, Ctfd, Arc<Mutex<Board>>) {
+        let board = Arc::new(Mutex::new(Board::default()));
+        let shared = board.clone();
+        let server = Server::start(move |req| {
+            let mut board = shared.lock().unwrap();
+            let segments = req.segments();
+            let data = |data| (200, json!({ "success": true, "data": data }));
+            match (req.method.as_str(), &segments[2..]) {
+                ("GET", ["challenges"]) => {
+                    data(json!(board.challenges.values().collect::<Vec<_>>()))
+                }
+                ("GET", ["challenges", id]) => match board.challenges.get(&id.parse().unwrap()) {
+                    Some(chall) => data(chall.clone()),
+                    None => (404, json!({})),
+                },
+                ("GET", ["challenges", id, kind]) => data(json!(board
+                    .items
+                    .iter()
+                    .filter(
+                        |(k, item)| k == kind && item["challenge_id"] == id.parse::<u64>().unwrap()
+                    )
+                    .map(|(_, item)| item)
+                    .collect::<Vec<_>>())),
+                ("POST", ["challenges"]) => {
+                    let id = board.id();
+                    let mut chall = req.json();
+                    chall["id"] = json!(id);
+                    board.challenges.insert(id, chall.clone());
+                    data(chall)
+                }
+                ("PATCH", ["challenges", id]) => {
+                    let chall = board.challenges.get_mut(&id.parse().unwrap()).unwrap();
+                    for (key, value) in req.json().as_object().unwrap() {
+                        chall[key] = value.clone();
+                    }
+                    data(chall.clone())
+                }
+                ("DELETE", ["challenges", id]) => {
+                    board.challenges.remove(&id.parse().unwrap());
+                    data(json!({}))
+                }
+                ("GET", [kind]) => data(json!(board
+                    .items
+                    .iter()
+                    .filter(|(k, _)| k == kind)
+                    .map(|(_, item)| item)
+                    .collect::<Vec<_>>())),
+                ("POST", ["files"]) => {
+                    let body = String::from_utf8_lossy(&req.body);
+                    let name = body.split("filename=\"").nth(1).unwrap();
+                    let name = name.split('"').next().unwrap();
+                    let item = json!({
+                        "id": board.id(),
+                        "challenge_id": form_field(&body, "challenge").parse::<u64>().unwrap(),
+                        "location": format!("0123abcd/{name}"),
+                    });
+                    board.items.push(("files".to_string(), item.clone()));
+                    data(json!([item]))
+                }
+                ("POST", [kind]) => {
+                    let mut item = req.json();
+                    item["id"] = json!(board.id());
+                    item["challenge_id"] = item["challenge"].clone();
+                    board.items.push((kind.to_string(), item.clone()));
+                    data(item)
+                }
+                ("DELETE", [kind, id]) => {
+                    board
+                        .items
+                        .retain(|(k, item)| k != kind || item["id"] != id.parse::<u64>().unwrap());
+                    data(json!({}))
+                }
+                _ => (404, json!({})),
+            }
+        });
+        let ctfd = Ctfd {
+            name: "ctfd".to_string(),
+            url: server.url.clone(),
+            auth: "Token test".to_string(),
+            decay: 50,
+        };
+        (server, ctfd, board)
+    }
+
+    fn config() -> crate::Config {
+        toml::from_str(
+            "hostname = \"example.com\"\nchall_root = \".\"\n[backend]\ntype = \"local\"\n",
+        )
+        .unwrap()
+    }
+
+    /// A challenge in its own directory, with `attachment.txt` provided
+    fn chall(extra: &str) -> Challenge {
+        let dir = crate::mock::scratch_dir("ctfd");
+        fs::write(dir.join("attachment.txt"), "some bytes\n").unwrap();
+        let toml = format!(
+            "name = \"Baby\"\nauthor = \"bear\"\ndescription = \"hi\"\nprovide = [\"attachment.txt\"]\n{extra}"
+        );
+        Challenge::from_toml("web/baby".to_string(), dir, &Category::default(), &toml).unwrap()
+    }
+
+    const CHALL: &str = "flag = \"flag{one}\"\npoints = { min = 100, max = 500 }\nhints = [{ content = \"look\", cost = 10 }]\ntags = [\"easy\"]\n";
+
+    #[tokio::test]
+    async fn creates_challenge_with_everything() {
+        let (server, ctfd, board) = ctfd();
+        let mut state = ScoreboardState::default();
+        let changes = ctfd
+            .upsert(&config(), &chall(CHALL), &mut state)
+            .await
+            .unwrap();
+        assert_eq!(changes, ["created", "flag", "hints", "tags", "files"]);
+        assert_eq!(state.id, "1");
+        assert!(state.files.is_some());
+
+        let created = server
+            .requests()
+            .into_iter()
+            .find(|r| r.method == "POST" && r.path == "/api/v1/challenges")
+            .unwrap()
+            .json();
+        assert_eq!(created["type"], "dynamic");
+        assert_eq!(created["value"], 500);
+        assert_eq!(created["initial"], 500);
+        assert_eq!(created["minimum"], 100);
+        assert_eq!(created["decay"], 50);
+        assert_eq!(created["category"], "web");
+
+        let board = board.lock().unwrap();
+        let kinds = board
+            .items
+            .iter()
+            .map(|(k, _)| k.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, ["flags", "hints", "tags", "tags", "files"]);
+        assert_eq!(board.items[0].1["content"], "flag{one}");
+        assert_eq!(board.items[3].1["value"], "bear-cds:web/baby");
+        assert_eq!(board.items[4].1["location"], "0123abcd/attachment.txt");
+    }
+
+    #[tokio::test]
+    async fn update_without_changes_sends_nothing() {
+        let (server, ctfd, board) = ctfd();
+        let mut state = ScoreboardState::default();
+        let chall = chall(CHALL);
+        ctfd.upsert(&config(), &chall, &mut state).await.unwrap();
+        {
+            // solves lower the value, and older CTFd doesn't have `function`
+            let mut board = board.lock().unwrap();
+            let current = board.challenges.get_mut(&1).unwrap();
+            current["value"] = json!(321);
+            current.as_object_mut().unwrap().remove("function");
+        }
+        server.clear();
+
+        let changes = ctfd.upsert(&config(), &chall, &mut state).await.unwrap();
+        assert!(changes.is_empty(), "{changes:?}");
+        assert!(server.changes().is_empty(), "{:?}", server.changes());
+        assert_eq!(state.id, "1");
+    }
+
+    #[tokio::test]
+    async fn replaces_flag_hints_and_tags() {
+        let (server, ctfd, board) = ctfd();
+        let mut state = ScoreboardState::default();
+        ctfd.upsert(&config(), &chall(CHALL), &mut state)
+            .await
+            .unwrap();
+        server.clear();
+
+        let changed = "flag = \"flag{two}\"\npoints = { min = 100, max = 500 }\nhints = [{ content = \"look harder\", cost = 10 }]\ntags = [\"hard\"]\n";
+        // same file contents, so the files are left alone
+        let chall = chall(changed);
+        let changes = ctfd.upsert(&config(), &chall, &mut state).await.unwrap();
+        assert_eq!(changes, ["flag", "hints", "tags"]);
+        assert_eq!(
+            server.changes(),
+            [
+                "DELETE /api/v1/flags/2",
+                "POST /api/v1/flags",
+                "DELETE /api/v1/hints/3",
+                "POST /api/v1/hints",
+                "DELETE /api/v1/tags/4",
+                "DELETE /api/v1/tags/5",
+                "POST /api/v1/tags",
+                "POST /api/v1/tags",
+            ]
+        );
+
+        let board = board.lock().unwrap();
+        let item = |kind: &str| {
+            board
+                .items
+                .iter()
+                .filter(|(k, _)| k == kind)
+                .map(|(_, item)| item.clone())
+                .collect::<Vec<Value>>()
+        };
+        assert_eq!(item("flags").len(), 1);
+        assert_eq!(item("flags")[0]["content"], "flag{two}");
+        assert_eq!(item("flags")[0]["challenge_id"], 1);
+        assert_eq!(item("hints")[0]["content"], "look harder");
+        assert_eq!(item("tags")[0]["value"], "hard");
+        assert_eq!(item("tags")[1]["value"], "bear-cds:web/baby");
+    }
+
+    #[tokio::test]
+    async fn only_adopts_challenges_with_the_marker() {
+        let (server, ctfd, board) = ctfd();
+        board.lock().unwrap().challenges.insert(
+            9,
+            json!({ "id": 9, "name": "Baby", "category": "web", "state": "visible" }),
+        );
+        let chall = chall(CHALL);
+        let mut state = ScoreboardState::default();
+        let error = ctfd
+            .upsert(&config(), &chall, &mut state)
+            .await
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("add the tag `bear-cds:web/baby`"),
+            "{error}"
+        );
+        assert!(server.changes().is_empty(), "{:?}", server.changes());
+
+        board.lock().unwrap().items.push((
+            "tags".to_string(),
+            json!({ "id": 10, "challenge_id": 9, "value": "bear-cds:web/baby" }),
+        ));
+        let changes = ctfd.upsert(&config(), &chall, &mut state).await.unwrap();
+        assert!(!changes.contains(&"created".to_string()), "{changes:?}");
+        assert_eq!(state.id, "9");
+    }
+
+    #[tokio::test]
+    async fn uploads_files_as_multipart() {
+        let (server, ctfd, _) = ctfd();
+        let data = b"\x00\x01binary".to_vec();
+        let file = ProvidedFile {
+            name: "dist\".tar.gz".to_string(),
+            data: data.clone(),
+        };
+        let locations = ctfd.upload_files("7", vec![file]).await.unwrap();
+        assert_eq!(locations, ["0123abcd/dist.tar.gz"]);
+
+        let request = server.requests().pop().unwrap();
+        assert_eq!(request.path, "/api/v1/files");
+        assert_eq!(request.header("Authorization"), Some("Token test"));
+        let content_type = request.header("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/form-data; boundary=")
+            .unwrap();
+        let body = String::from_utf8_lossy(&request.body);
+        assert!(body.starts_with(&format!("--{boundary}\r\n")));
+        assert!(body.ends_with(&format!("\r\n--{boundary}--\r\n")));
+        assert_eq!(form_field(&body, "challenge"), "7");
+        assert_eq!(form_field(&body, "type"), "challenge");
+        assert!(request.body.windows(data.len()).any(|w| w == data));
+        assert!(body.contains(
+            "name=\"file\"; filename=\"dist.tar.gz\"\r\nContent-Type: application/octet-stream\r\n\r\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn files_are_only_sent_again_when_they_change() {
+        let (server, ctfd, _board) = ctfd();
+        let mut state = ScoreboardState::default();
+        let chall = chall("flag = \"flag{x}\"\n");
+        ctfd.upsert(&config(), &chall, &mut state).await.unwrap();
+        let hash = state.files.clone();
+
+        // touched but not changed, so the hash in the state still matches
+        let path = chall.dir.join("attachment.txt");
+        fs::write(&path, "some bytes\n").unwrap();
+        server.clear();
+        let changes = ctfd.upsert(&config(), &chall, &mut state).await.unwrap();
+        assert!(changes.is_empty(), "{changes:?}");
+        assert!(server.changes().is_empty(), "{:?}", server.changes());
+        assert_eq!(state.files, hash);
+
+        fs::write(&path, "other bytes\n").unwrap();
+        server.clear();
+        let changes = ctfd.upsert(&config(), &chall, &mut state).await.unwrap();
+        assert_eq!(changes, ["files"]);
+        assert_eq!(
+            server.changes(),
+            ["DELETE /api/v1/files/4", "POST /api/v1/files"]
+        );
+        assert_ne!(state.files, hash);
+    }
+}
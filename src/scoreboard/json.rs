@@ -0,0 +1,305 @@
+//! A scoreboard that is just files: `challenges.json` with every visible challenge, and their
+//! attachments next to it under `files/`. Good for a static mirror or for feeding another tool.
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use super::{is_plain_file_name, ScoreboardPlatform};
+use crate::{
+    challenge::{Challenge, ProvidedFile},
+    state::ScoreboardState,
+};
+
+#[derive(Deserialize, Clone, JsonSchema)]
+#[schemars(rename = "JsonConfig")]
+pub struct Config {
+    #[serde(default = "default_path")]
+    #[schemars(extend("default" = "scoreboard"))]
+    /// Directory the export is written to
+    pub path: PathBuf,
+    #[serde(default)]
+    /// Include flags, for mirrors that check them themselves
+    pub flags: bool,
+    /// What the scoreboard is called in output and in the state (defaults to json)
+    pub name: Option<String>,
+}
+
+impl Config {
+    /// What the scoreboard is called in output and in the state
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or("json".to_string())
+    }
+}
+
+fn default_path() -> PathBuf {
+    PathBuf::from("scoreboard")
+}
+
+pub struct Json {
+    name: String,
+    path: PathBuf,
+    flags: bool,
+}
+
+impl Json {
+    pub fn new(config: &Config) -> Json {
+        Json {
+            name: config.name(),
+            path: config.path.clone(),
+            flags: config.flags,
+        }
+    }
+
+    fn challs_path(&self) -> PathBuf {
+        self.path.join("challenges.json")
+    }
+
+    /// challenge id -> challenge, as it is in challenges.json
+    fn load(&self) -> Result<BTreeMap<String, Value>> {
+        match fs::read_to_string(self.challs_path()) {
+            Ok(data) => serde_json::from_str(&data)
+                .map_err(|e| anyhow!("failed to parse {}: {e}", self.challs_path().display())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, challs: &BTreeMap<String, Value>) -> Result<()> {
+        fs::create_dir_all(&self.path)?;
+        fs::write(self.challs_path(), serde_json::to_string_pretty(challs)?)?;
+        Ok(())
+    }
+}
+
+fn write_if_changed(path: &Path, data: &[u8]) -> Result<()> {
+    if fs::read(path).ok().as_deref() != Some(data) {
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, data)?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl ScoreboardPlatform for Json {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Hidden challenges are left out of the export, and taken out if they were in it
+    async fn upsert(
+        &self,
+        config: &crate::Config,
+        chall: &Challenge,
+        state: &mut ScoreboardState,
+    ) -> Result<Vec<String>> {
+        let mut challs = self.load()?;
+        if chall.hidden == Some(true) {
+            state.id.clear();
+            return Ok(match challs.remove(&chall.id) {
+                Some(_) => {
+                    self.save(&challs)?;
+                    vec!["hidden".to_string()]
+                }
+                None => vec![],
+            });
+        }
+
+        let files = chall.provided_files()?;
+        let names = files
+            .iter()
+            .map(|f| f.name.clone())
+            .collect::<Vec<String>>();
+        let urls = self.upload_files(&chall.id, files).await?;
+        let points = chall.points.clone().unwrap_or_default();
+        let mut desired = json!({
+            "id": chall.id,
+            "name": chall.name,
+            "category": chall.category,
+            "author": chall.author,
+            "description": chall.render_description(&config.hostname),
+            "points": { "min": points.min(), "max": points.max() },
+            "tiebreak": chall.tiebreak.unwrap_or(true),
+            "hints": chall.hints.iter().map(|h| json!({ "content": h.content, "cost": h.cost })).collect::<Vec<Value>>(),
+            "tags": chall.tags,
+            "files": names.into_iter().zip(urls).map(|(name, url)| json!({ "name": name, "url": url })).collect::<Vec<Value>>(),
+        });
+        if self.flags {
+            desired["flag"] = json!(chall.get_flag()?);
+        }
+
+        let changes = match challs.get(&chall.id) {
+            Some(current) => desired
+                .as_object()
+                .unwrap()
+                .iter()
+                .filter(|(key, value)| current.get(key.as_str()) != Some(value))
+                .map(|(key, _)| key.clone())
+                .collect(),
+            None => vec!["created".to_string()],
+        };
+        if !changes.is_empty() {
+            challs.insert(chall.id.clone(), desired);
+            self.save(&challs)?;
+        }
+        state.id = chall.id.clone();
+        Ok(changes)
+    }
+
+    /// Files are left behind, other challenges may share them
+    async fn delete(&self, state: &ScoreboardState) -> Result<()> {
+        let mut challs = self.load()?;
+        if challs.remove(&state.id).is_some() {
+            self.save(&challs)?;
+        }
+        Ok(())
+    }
+
+    /// Files are stored by hash, so the same file is only written once
+    async fn upload_files(&self, _id: &str, files: Vec<ProvidedFile>) -> Result<Vec<String>> {
+        files
+            .into_iter()
+            .map(|file| {
+                if !is_plain_file_name(&file.name) {
+                    return Err(anyhow!(
+                        "{:?} isn't a plain file name, so it can't be exported",
+                        file.name
+                    ));
+                }
+                let hash = file.sha256();
+                let url = format!("files/{}/{}", &hash[..16], file.name);
+                write_if_changed(&self.path.join(&url), &file.data)?;
+                Ok(url)
+            })
+            .collect()
+    }
+
+    async fn scoreboard(&self) -> Result<Value> {
+        Err(anyhow!(
+            "{} is a static export, it has no scoreboard",
+            self.name
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{challenge::Category, mock::scratch_dir};
+
+    fn config() -> crate::Config {
+        toml::from_str(
+            "hostname = \"example.com\"\nchall_root = \".\"\n[backend]\ntype = \"local\"\n",
+        )
+        .unwrap()
+    }
+
+    fn json(flags: bool) -> Json {
+        Json {
+            name: "json".to_string(),
+            path: scratch_dir("json"),
+            flags,
+        }
+    }
+
+    /// A challenge in its own directory, with `attachment.txt` provided
+    fn chall(extra: &str) -> Challenge {
+        let dir = scratch_dir("json-chall");
+        fs::write(dir.join("attachment.txt"), "some bytes\n").unwrap();
+        let toml = format!(
+            "name = \"Baby\"\nauthor = \"bear\"\ndescription = \"hi\"\nflag = \"flag{{x}}\"\nprovide = [\"attachment.txt\"]\n{extra}"
+        );
+        Challenge::from_toml("web/baby".to_string(), dir, &Category::default(), &toml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn exports_and_updates_challenges() {
+        let json = json(false);
+        let mut state = ScoreboardState::default();
+        let changes = json
+            .upsert(&config(), &chall(""), &mut state)
+            .await
+            .unwrap();
+        assert_eq!(changes, ["created"]);
+        assert_eq!(state.id, "web/baby");
+
+        let exported = &json.load().unwrap()["web/baby"];
+        assert_eq!(exported["name"], "Baby");
+        assert_eq!(exported["points"], json!({ "min": 100, "max": 500 }));
+        assert_eq!(exported.get("flag"), None);
+        let url = exported["files"][0]["url"].as_str().unwrap();
+        assert!(url.starts_with("files/") && url.ends_with("/attachment.txt"));
+        assert_eq!(fs::read(json.path.join(url)).unwrap(), b"some bytes\n");
+
+        let changes = json
+            .upsert(&config(), &chall(""), &mut state)
+            .await
+            .unwrap();
+        assert!(changes.is_empty());
+        let changes = json
+            .upsert(&config(), &chall("points = 50"), &mut state)
+            .await
+            .unwrap();
+        assert_eq!(changes, ["points"]);
+
+        json.delete(&state).await.unwrap();
+        assert!(json.load().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn flags_are_only_exported_when_asked_for() {
+        let json = json(true);
+        let mut state = ScoreboardState::default();
+        json.upsert(&config(), &chall(""), &mut state)
+            .await
+            .unwrap();
+        assert_eq!(json.load().unwrap()["web/baby"]["flag"], "flag{x}");
+    }
+
+    #[tokio::test]
+    async fn hidden_challenges_are_taken_out() {
+        let json = json(false);
+        let mut state = ScoreboardState::default();
+        json.upsert(&config(), &chall(""), &mut state)
+            .await
+            .unwrap();
+        let changes = json
+            .upsert(&config(), &chall("hidden = true"), &mut state)
+            .await
+            .unwrap();
+        assert_eq!(changes, ["hidden"]);
+        assert!(state.id.is_empty());
+        assert!(json.load().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn files_are_written_once_and_only_inside_the_export() {
+        let json = json(false);
+        let file = |name: &str| ProvidedFile {
+            name: name.to_string(),
+            data: b"same".to_vec(),
+        };
+        let urls = json
+            .upload_files("web/baby", vec![file("a.txt"), file("a.txt")])
+            .await
+            .unwrap();
+        assert_eq!(urls[0], urls[1]);
+
+        for name in ["../../x", "dir/x", "/tmp/x", ".."] {
+            let e = json
+                .upload_files("web/baby", vec![file(name)])
+                .await
+                .unwrap_err();
+            assert!(e.to_string().contains("isn't a plain file name"), "{name}");
+        }
+        assert!(!json.path.join("files/x").exists());
+    }
+}
@@ -0,0 +1,134 @@
+//! Scoreboard platforms.
+//!
+//! Challenges end up on one or more scoreboards once they are deployed. Everything that talks to
+//! a scoreboard goes through the [`ScoreboardPlatform`] trait, so deploys can target rCTF, CTFd, a
+//! static JSON export or several of them at once (e.g. the main board and a mirror).
+
+use crate::{
+    challenge::{Challenge, ProvidedFile},
+    state::{ScoreboardState, State},
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{ffi::OsStr, path::Path};
+
+pub mod ctfd;
+pub mod json;
+pub mod rctf;
+
+#[derive(Deserialize, Clone, JsonSchema)]
+#[serde(tag = "type", rename_all = "lowercase")]
+/// An entry in `[[scoreboards]]` in bear.toml, `type` selects the platform.
+#[schemars(rename = "Scoreboard")]
+pub enum Config {
+    Rctf(rctf::Config),
+    Ctfd(ctfd::Config),
+    Json(json::Config),
+}
+
+/// A challenge on a platform, as far as deleting it goes.
+pub struct RemoteChall {
+    /// Shown when asking whether to delete it
+    pub label: String,
+    pub state: ScoreboardState,
+}
+
+#[async_trait]
+pub trait ScoreboardPlatform: Send + Sync {
+    /// What the platform is called in output and in the state.
+    fn name(&self) -> &str;
+    /// Create `chall` or bring it up to date. `state` is what was recorded for it last time and is
+    /// updated in place, it's left with an empty id if the challenge doesn't exist on the
+    /// platform afterwards. Returns what changed, which is nothing if it already was up to date.
+    async fn upsert(
+        &self,
+        config: &crate::Config,
+        chall: &Challenge,
+        state: &mut ScoreboardState,
+    ) -> Result<Vec<String>>;
+    /// Delete the challenge recorded in `state`, challenges that are already gone are fine.
+    async fn delete(&self, state: &ScoreboardState) -> Result<()>;
+    /// Make `files` available for the challenge with the platform's id `id`. Returns where each
+    /// of them ended up.
+    async fn upload_files(&self, id: &str, files: Vec<ProvidedFile>) -> Result<Vec<String>>;
+    /// Current standings, in the format CTFtime imports.
+    async fn scoreboard(&self) -> Result<Value>;
+    /// Where the challenge with id `chall_id` lives when nothing was recorded about it, for
+    /// platforms that name challenges after their id.
+    fn default_state(&self, _chall_id: &str) -> Option<ScoreboardState> {
+        None
+    }
+    /// Challenges bear-cds put on the platform that shouldn't be there for `challs` anymore,
+    /// which `prune` deletes. By default that's whatever the state remembers for challenges that
+    /// are gone from the repo, platforms that can't hide challenges also count hidden ones.
+    async fn orphans(&self, challs: &[Challenge], state: &State) -> Result<Vec<RemoteChall>> {
+        Ok(state
+            .challenges
+            .iter()
+            .filter(|(id, _)| !challs.iter().any(|chall| &chall.id == *id))
+            .filter_map(|(id, chall_state)| {
+                let state = chall_state.scoreboards.get(self.name())?;
+                Some(RemoteChall {
+                    label: id.clone(),
+                    state: state.clone(),
+                })
+            })
+            .collect())
+    }
+}
+
+/// Whether `name` is just a file name, so it can't point outside the directory it's written to.
+/// Names of files can come from a server or from challenge.toml, so they're checked before use.
+pub fn is_plain_file_name(name: &str) -> bool {
+    Path::new(name).file_name() == Some(OsStr::new(name))
+}
+
+/// Every scoreboard in bear.toml, `[rctf]` and `[ctfd]` first and then `[[scoreboards]]`.
+pub fn all(config: &crate::Config) -> Vec<Config> {
+    config
+        .rctf
+        .iter()
+        .cloned()
+        .map(Config::Rctf)
+        .chain(config.ctfd.iter().cloned().map(Config::Ctfd))
+        .chain(config.scoreboards.iter().cloned())
+        .collect()
+}
+
+impl Config {
+    /// What the scoreboard is called in output and in the state
+    pub fn name(&self) -> String {
+        match self {
+            Config::Rctf(rctf) => rctf.name(),
+            Config::Ctfd(ctfd) => ctfd.name(),
+            Config::Json(json) => json.name(),
+        }
+    }
+
+    /// The platform to talk to, fails if its token isn't set
+    pub fn platform(&self) -> Result<Box<dyn ScoreboardPlatform>> {
+        Ok(match self {
+            Config::Rctf(rctf) => Box::new(rctf::Rctf::new(rctf)?),
+            Config::Ctfd(ctfd) => Box::new(ctfd::Ctfd::new(ctfd)?),
+            Config::Json(json) => Box::new(json::Json::new(json)),
+        })
+    }
+}
+
+pub fn from_config(config: &crate::Config) -> Result<Vec<Box<dyn ScoreboardPlatform>>> {
+    let mut platforms: Vec<Box<dyn ScoreboardPlatform>> = Vec::new();
+    for scoreboard in all(config) {
+        let platform = scoreboard.platform()?;
+        if platforms.iter().any(|p| p.name() == platform.name()) {
+            return Err(anyhow!(
+                "there are two scoreboards called {}, give one of them a different `name`",
+                platform.name()
+            ));
+        }
+        platforms.push(platform);
+    }
+    Ok(platforms)
+}
@@ -0,0 +1,666 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::Engine;
+use colored::Colorize;
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{env, fmt};
+
+use super::{RemoteChall, ScoreboardPlatform};
+use crate::{
+    challenge::{Challenge, ProvidedFile},
+    state::{ScoreboardState, State},
+};
+
+#[derive(Deserialize, Clone, JsonSchema)]
+#[schemars(rename = "RctfConfig")]
+pub struct Config {
+    pub url: String,
+    /// What the scoreboard is called in output and in the state (defaults to rctf)
+    pub name: Option<String>,
+    #[serde(default = "default_token_env")]
+    #[schemars(extend("default" = "RCTF_ADMIN_TOKEN"))]
+    /// Environment variable the admin token is read from
+    pub token_env: String,
+}
+
+fn default_token_env() -> String {
+    "RCTF_ADMIN_TOKEN".to_string()
+}
+
+impl Config {
+    /// What the scoreboard is called in output and in the state
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or("rctf".to_string())
+    }
+}
+
+/// Every rCTF scoreboard in bear.toml, `[rctf]` first and then the ones in `[[scoreboards]]`
+pub fn configs(config: &crate::Config) -> Vec<Config> {
+    super::all(config)
+        .into_iter()
+        .filter_map(|scoreboard| match scoreboard {
+            super::Config::Rctf(rctf) => Some(rctf),
+            _ => None,
+        })
+        .collect()
+}
+
+/// An rCTF instance, talked to through its admin API
+pub struct Rctf {
+    name: String,
+    url: String,
+    auth: String,
+}
+
+/// A challenge as the rCTF admin API has it
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RctfChall {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub category: String,
+    pub author: String,
+    pub flag: String,
+    pub points: RctfPoints,
+    pub files: Vec<RctfUploadedFile>,
+    pub tiebreak_eligible: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RctfPoints {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl fmt::Display for RctfPoints {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.min == self.max {
+            true => write!(f, "{}", self.min),
+            false => write!(f, "{}-{}", self.min, self.max),
+        }
+    }
+}
+
+/// Id of the challenge on rCTF
+pub fn chall_id(id: &str) -> String {
+    format!("bcds-{}", id.replace('/', "-"))
+}
+
+impl Rctf {
+    pub fn new(config: &Config) -> Result<Rctf> {
+        let token =
+            env::var(&config.token_env).map_err(|_| anyhow!("${} not found", config.token_env))?;
+        Ok(Rctf {
+            name: config.name(),
+            url: config.url.clone(),
+            auth: format!("Bearer {token}"),
+        })
+    }
+
+    /// Send a request to `path` with an optional json body and return the response json, or
+    /// `None` on a 404. ureq blocks, so this runs on a blocking thread.
+    async fn call(
+        &self,
+        action: &str,
+        method: &str,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<Option<serde_json::Value>> {
+        let request =
+            ureq::request(method, &format!("{}{path}", self.url)).set("Authorization", &self.auth);
+        let error = format!("{action} failed ({})", self.name);
+        tokio::task::spawn_blocking(move || {
+            let response = match body {
+                Some(body) => request.send_json(body),
+                None => request.call(),
+            };
+            match response {
+                Ok(response) => Ok(Some(response.into_json()?)),
+                Err(ureq::Error::Status(404, _)) => Ok(None),
+                Err(err) => Err(anyhow!(
+                    "{error}: {:?}",
+                    err.into_response().map(|resp| resp.into_string())
+                )),
+            }
+        })
+        .await?
+    }
+
+    /// `data` of the response, which has to be there
+    async fn data<T: DeserializeOwned>(
+        &self,
+        action: &str,
+        method: &str,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<T> {
+        let response = self
+            .call(action, method, path, body)
+            .await?
+            .ok_or_else(|| anyhow!("{action} failed ({}): not found", self.name))?;
+        Ok(serde_json::from_value::<Response<T>>(response)?.data)
+    }
+
+    pub async fn list_challs(&self) -> Result<Vec<RctfChall>> {
+        self.data("List challenges", "GET", "/api/v1/admin/challs", None)
+            .await
+    }
+
+    pub async fn get_chall(&self, id: &str) -> Result<Option<RctfChall>> {
+        let path = format!("/api/v1/admin/challs/{id}");
+        match self.call("Get challenge", "GET", &path, None).await? {
+            Some(response) => Ok(Some(
+                serde_json::from_value::<Response<RctfChall>>(response)?.data,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn delete_chall(&self, id: &str) -> Result<()> {
+        let path = format!("/api/v1/admin/challs/{id}");
+        self.call("Delete challenge", "DELETE", &path, None).await?;
+        Ok(())
+    }
+
+    /// What `chall` should look like on rCTF, with `files` from [`Challenge::provided_files`].
+    /// Files rCTF already has are matched by hash, the rest are left without a url, nothing is
+    /// uploaded.
+    pub async fn local_chall(
+        &self,
+        config: &crate::Config,
+        chall: &Challenge,
+        files: &[ProvidedFile],
+    ) -> Result<RctfChall> {
+        let uploaded = self.query_files(files).await?;
+        let points = chall.points.clone().unwrap_or_default();
+        Ok(RctfChall {
+            id: chall_id(&chall.id),
+            name: chall.name.clone(),
+            description: chall.render_description(&config.hostname),
+            category: chall.category.clone(),
+            author: chall.author.clone(),
+            flag: chall.get_flag()?,
+            points: RctfPoints {
+                min: points.min(),
+                max: points.max(),
+            },
+            files: uploaded,
+            tiebreak_eligible: chall.tiebreak.unwrap_or(true),
+        })
+    }
+
+    /// Bring `chall` up to date on rCTF, given what's there now. Returns what changed, which is
+    /// nothing if it already was.
+    pub async fn update_chall(
+        &self,
+        config: &crate::Config,
+        chall: &Challenge,
+        current: Option<&RctfChall>,
+    ) -> Result<Vec<String>> {
+        // archived and queried once, what rCTF doesn't have yet is uploaded from the same files
+        let files = chall.provided_files()?;
+        let mut desired = self.local_chall(config, chall, &files).await?;
+        let changes = match current {
+            Some(current) => diff(current, &desired),
+            None => vec!["created".to_string()],
+        };
+        if changes.is_empty() {
+            return Ok(changes);
+        }
+        if current.is_none_or(|current| current.files != desired.files) {
+            self.upload_missing(files, &mut desired.files).await?;
+        }
+
+        self.call(
+            "Update challenge",
+            "PUT",
+            &format!("/api/v1/admin/challs/{}", desired.id),
+            Some(serde_json::json!({ "data": desired })),
+        )
+        .await?;
+        Ok(changes)
+    }
+
+    /// Urls of files rCTF already has, looked up by hash. Files it doesn't have get an empty url.
+    pub async fn query_files(&self, files: &[ProvidedFile]) -> Result<Vec<RctfUploadedFile>> {
+        if files.is_empty() {
+            return Ok(vec![]);
+        }
+        let uploads: Vec<serde_json::Value> = files
+            .iter()
+            .map(|f| ureq::json!({ "name": f.name, "sha256": f.sha256() }))
+            .collect();
+        #[derive(Deserialize)]
+        struct Queried {
+            name: String,
+            url: Option<String>,
+        }
+        let queried: Vec<Queried> = self
+            .data(
+                "Query files",
+                "POST",
+                "/api/v1/admin/upload/query",
+                Some(serde_json::json!({ "uploads": uploads })),
+            )
+            .await?;
+        Ok(queried
+            .into_iter()
+            .map(|f| RctfUploadedFile {
+                name: f.name,
+                url: f.url.unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    /// Upload the `files` that have no url in `uploaded`, what [`Self::query_files`] returned
+    /// for them, and fill in their urls.
+    async fn upload_missing(
+        &self,
+        files: Vec<ProvidedFile>,
+        uploaded: &mut [RctfUploadedFile],
+    ) -> Result<()> {
+        let missing = files
+            .into_iter()
+            .zip(uploaded.iter())
+            .filter(|(_, uploaded)| uploaded.url.is_empty())
+            .map(|(file, _)| file)
+            .collect::<Vec<ProvidedFile>>();
+        if !missing.is_empty() {
+            let payload: Vec<serde_json::Value> = missing.into_iter().map(|f| ureq::json!({ "name": f.name, "data": format!("data:image/png;base64,{}", base64::engine::general_purpose::URL_SAFE.encode(f.data)) })).collect();
+            let new: Vec<RctfUploadedFile> = self
+                .data(
+                    "Upload files",
+                    "POST",
+                    "/api/v1/admin/upload",
+                    Some(serde_json::json!({ "files": payload })),
+                )
+                .await?;
+            let mut new = new.into_iter();
+            for file in uploaded.iter_mut().filter(|f| f.url.is_empty()) {
+                *file = new
+                    .next()
+                    .ok_or(anyhow!("rCTF returned fewer files than were uploaded"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ScoreboardPlatform for Rctf {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn upsert(
+        &self,
+        config: &crate::Config,
+        chall: &Challenge,
+        state: &mut ScoreboardState,
+    ) -> Result<Vec<String>> {
+        let id = chall_id(&chall.id);
+        // rCTF has no hidden challenges, so they just aren't put there. One that was hidden after
+        // it went up is an orphan, which `prune` deletes
+        if chall.hidden == Some(true) {
+            if self.get_chall(&id).await?.is_some() {
+                eprintln!(
+                    "{} {} is hidden but still public on {}, `bear prune` deletes it",
+                    "WARNING:".yellow().bold(),
+                    chall.id,
+                    self.name
+                );
+                state.id = id;
+            }
+            return Ok(vec![]);
+        }
+        let current = self.get_chall(&id).await?;
+        let changes = self.update_chall(config, chall, current.as_ref()).await?;
+        state.id = id;
+        Ok(changes)
+    }
+
+    async fn delete(&self, state: &ScoreboardState) -> Result<()> {
+        self.delete_chall(&state.id).await
+    }
+
+    /// rCTF keeps uploads by content rather than by challenge, so `id` isn't needed and files it
+    /// already has aren't sent again
+    async fn upload_files(&self, _id: &str, files: Vec<ProvidedFile>) -> Result<Vec<String>> {
+        let mut uploaded = self.query_files(&files).await?;
+        self.upload_missing(files, &mut uploaded).await?;
+        Ok(uploaded.into_iter().map(|f| f.url).collect())
+    }
+
+    async fn scoreboard(&self) -> Result<serde_json::Value> {
+        self.call(
+            "Get leaderboard",
+            "GET",
+            "/api/v1/integrations/ctftime/leaderboard",
+            None,
+        )
+        .await?
+        .ok_or_else(|| anyhow!("Get leaderboard failed ({}): not found", self.name))
+    }
+
+    fn default_state(&self, chall_id: &str) -> Option<ScoreboardState> {
+        Some(ScoreboardState {
+            id: self::chall_id(chall_id),
+            files: None,
+        })
+    }
+
+    /// Every `bcds-` challenge that isn't in the repo or is hidden there, whether the state knows
+    /// about it or not
+    async fn orphans(&self, challs: &[Challenge], _state: &State) -> Result<Vec<RemoteChall>> {
+        let find = |id: &str| challs.iter().find(|chall| chall_id(&chall.id) == id);
+        Ok(self
+            .list_challs()
+            .await?
+            .into_iter()
+            .filter(|c| {
+                c.id.starts_with("bcds-")
+                    && find(&c.id).is_none_or(|chall| chall.hidden == Some(true))
+            })
+            .map(|c| RemoteChall {
+                label: match find(&c.id) {
+                    Some(_) => format!("{} \"{}\" (hidden)", c.id, c.name),
+                    None => format!("{} \"{}\"", c.id, c.name),
+                },
+                state: ScoreboardState {
+                    id: c.id,
+                    files: None,
+                },
+            })
+            .collect())
+    }
+}
+
+/// What changed between `current` and `desired`, one short line per field
+pub fn diff(current: &RctfChall, desired: &RctfChall) -> Vec<String> {
+    let mut changes = Vec::new();
+    let mut changed = |field: &str, from: &str, to: &str| {
+        changes.push(format!("{field}: {from} -> {to}"));
+    };
+    if current.name != desired.name {
+        changed("name", &current.name, &desired.name);
+    }
+    if current.category != desired.category {
+        changed("category", &current.category, &desired.category);
+    }
+    if current.author != desired.author {
+        changed("author", &current.author, &desired.author);
+    }
+    if current.points != desired.points {
+        changed(
+            "points",
+            &current.points.to_string(),
+            &desired.points.to_string(),
+        );
+    }
+    if current.tiebreak_eligible != desired.tiebreak_eligible {
+        changed(
+            "tiebreak",
+            &current.tiebreak_eligible.to_string(),
+            &desired.tiebreak_eligible.to_string(),
+        );
+    }
+    // these are too long or too secret to print
+    if current.description != desired.description {
+        changes.push("description".to_string());
+    }
+    if current.flag != desired.flag {
+        changes.push("flag".to_string());
+    }
+    if current.files != desired.files {
+        let names = |files: &[RctfUploadedFile]| {
+            files
+                .iter()
+                .map(|f| f.name.clone())
+                .collect::<Vec<String>>()
+        };
+        let (before, after) = (names(&current.files), names(&desired.files));
+        let mut files = after
+            .iter()
+            .filter(|name| !before.contains(name))
+            .map(|name| format!("+{name}"))
+            .chain(
+                before
+                    .iter()
+                    .filter(|name| !after.contains(name))
+                    .map(|name| format!("-{name}")),
+            )
+            .chain(
+                desired
+                    .files
+                    .iter()
+                    .filter(|file| {
+                        current
+                            .files
+                            .iter()
+                            .any(|c| c.name == file.name && c.url != file.url)
+                    })
+                    .map(|file| format!("~{}", file.name)),
+            )
+            .collect::<Vec<String>>();
+        if files.is_empty() {
+            files.push("reordered".to_string());
+        }
+        changes.push(format!("files: {}", files.join(" ")));
+    }
+    changes
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct RctfUploadedFile {
+    pub name: String,
+    /// Empty if the file hasn't been uploaded yet
+    #[serde(default)]
+    pub url: String,
+}
+
+/// What rCTF responds with, the payload is in `data`
+#[derive(Deserialize)]
+struct Response<T> {
+    data: T,
+}
+
+#[cfg(test)]
+impl Rctf {
+    /// A client for the mock server
+    pub fn mock(server: &crate::mock::Server) -> Rctf {
+        Rctf {
+            name: "rctf".to_string(),
+            url: server.url.clone(),
+            auth: "Bearer test".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{challenge::Category, mock::Server};
+    use serde_json::json;
+
+    fn chall(id: &str, extra: &str) -> Challenge {
+        let toml = format!(
+            "name = \"{id}\"\nauthor = \"bear\"\ndescription = \"hi\"\nflag = \"flag{{x}}\"\n{extra}"
+        );
+        let dir = crate::mock::scratch_dir("rctf");
+        Challenge::from_toml(id.to_string(), dir, &Category::default(), &toml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn orphans_are_gone_or_hidden() {
+        let server = Server::start(|request| match request.path.as_str() {
+            "/api/v1/admin/challs" => (
+                200,
+                json!({ "data": [
+                    { "id": "bcds-web-gone", "name": "Gone" },
+                    { "id": "bcds-web-hidden", "name": "Hidden" },
+                    { "id": "bcds-web-kept", "name": "Kept" },
+                    { "id": "by-hand", "name": "By hand" },
+                ] }),
+            ),
+            _ => (404, json!({})),
+        });
+        let challs = [chall("web/hidden", "hidden = true"), chall("web/kept", "")];
+        let orphans = Rctf::mock(&server)
+            .orphans(&challs, &State::default())
+            .await
+            .unwrap();
+        let labels = orphans.iter().map(|o| o.label.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            [
+                "bcds-web-gone \"Gone\"",
+                "bcds-web-hidden \"Hidden\" (hidden)"
+            ]
+        );
+        assert_eq!(orphans[1].state.id, "bcds-web-hidden");
+    }
+
+    fn remote(name: &str, url: &str) -> RctfUploadedFile {
+        RctfUploadedFile {
+            name: name.to_string(),
+            url: url.to_string(),
+        }
+    }
+
+    #[test]
+    fn diffs() {
+        let current = RctfChall {
+            id: "bcds-web-baby".to_string(),
+            name: "Baby".to_string(),
+            description: "hi".to_string(),
+            category: "web".to_string(),
+            author: "bear".to_string(),
+            flag: "flag{one}".to_string(),
+            points: RctfPoints { min: 100, max: 500 },
+            files: vec![remote("a", "/a1"), remote("b", "/b1")],
+            tiebreak_eligible: true,
+        };
+        assert!(diff(&current, &current).is_empty());
+
+        let mut desired = current.clone();
+        desired.name = "Baby 2".to_string();
+        desired.points = RctfPoints { min: 50, max: 50 };
+        desired.tiebreak_eligible = false;
+        desired.description = "hello".to_string();
+        desired.flag = "flag{two}".to_string();
+        desired.files = vec![remote("a", "/a2"), remote("c", "")];
+        assert_eq!(
+            diff(&current, &desired),
+            [
+                "name: Baby -> Baby 2",
+                "points: 100-500 -> 50",
+                "tiebreak: true -> false",
+                "description",
+                "flag",
+                "files: +c -b ~a",
+            ]
+        );
+
+        let mut reordered = current.clone();
+        reordered.files.reverse();
+        assert_eq!(diff(&current, &reordered), ["files: reordered"]);
+    }
+
+    /// rCTF that has `old.txt` and takes uploads and challenge updates
+    fn upload_server() -> Server {
+        Server::start(|request| match request.path.as_str() {
+            "/api/v1/admin/upload/query" => {
+                let uploads = request.json()["uploads"].as_array().unwrap().clone();
+                let data = uploads
+                    .iter()
+                    .map(|file| {
+                        let url = (file["name"] == "old.txt").then_some("/uploads/old");
+                        json!({ "name": file["name"], "url": url })
+                    })
+                    .collect::<Vec<_>>();
+                (200, json!({ "data": data }))
+            }
+            "/api/v1/admin/upload" => {
+                let files = request.json()["files"].as_array().unwrap().clone();
+                let data = files
+                    .iter()
+                    .map(|file| json!({ "name": file["name"], "url": "/uploads/new" }))
+                    .collect::<Vec<_>>();
+                (200, json!({ "data": data }))
+            }
+            path if path.starts_with("/api/v1/admin/challs/") => (200, json!({})),
+            _ => (404, json!({})),
+        })
+    }
+
+    #[tokio::test]
+    async fn only_files_rctf_doesnt_have_are_uploaded() {
+        let server = upload_server();
+        let file = |name: &str| ProvidedFile {
+            name: name.to_string(),
+            data: name.as_bytes().to_vec(),
+        };
+
+        let urls = Rctf::mock(&server)
+            .upload_files("", vec![file("old.txt"), file("new.txt")])
+            .await
+            .unwrap();
+        assert_eq!(urls, ["/uploads/old", "/uploads/new"]);
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].json()["uploads"][1]["sha256"],
+            file("new.txt").sha256()
+        );
+        let uploaded = requests[1].json()["files"].as_array().unwrap().clone();
+        assert_eq!(uploaded.len(), 1);
+        assert_eq!(uploaded[0]["name"], "new.txt");
+
+        server.clear();
+        let urls = Rctf::mock(&server)
+            .upload_files("", vec![file("old.txt")])
+            .await
+            .unwrap();
+        assert_eq!(urls, ["/uploads/old"]);
+        assert_eq!(server.changes(), ["POST /api/v1/admin/upload/query"]);
+    }
+
+    #[tokio::test]
+    async fn updates_archive_and_query_files_once() {
+        let server = upload_server();
+        let config: crate::Config = toml::from_str(
+            "hostname = \"example.com\"\nchall_root = \".\"\n[backend]\ntype = \"local\"\n",
+        )
+        .unwrap();
+        let chall = chall("web/files", "provide = [\"old.txt\", \"new.txt\"]");
+        for name in ["old.txt", "new.txt"] {
+            std::fs::write(chall.dir.join(name), name).unwrap();
+        }
+        let changes = Rctf::mock(&server)
+            .update_chall(&config, &chall, None)
+            .await
+            .unwrap();
+        assert_eq!(changes, ["created"]);
+        assert_eq!(
+            server.changes(),
+            [
+                "POST /api/v1/admin/upload/query",
+                "POST /api/v1/admin/upload",
+                "PUT /api/v1/admin/challs/bcds-web-files",
+            ]
+        );
+        let requests = server.requests();
+        let uploaded = requests[1].json()["files"].as_array().unwrap().clone();
+        assert_eq!(uploaded.len(), 1);
+        assert_eq!(uploaded[0]["name"], "new.txt");
+        let files = requests[2].json()["data"]["files"].clone();
+        assert_eq!(
+            files,
+            json!([
+                { "name": "old.txt", "url": "/uploads/old" },
+                { "name": "new.txt", "url": "/uploads/new" },
+            ])
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! What bear-cds remembers between runs.
+//!
+//! The state lives in `state.json` in `state_dir` (`.bear` unless bear.toml says otherwise) and
+//! records what was last deployed for every challenge. Commands that change anything take
+//! `state.lock` next to it first. Teams where more than one person deploys can point `state_dir`
+//! at a shared directory, so they all lock and record the same copy.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    process::{self, Stdio},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{challenge::Expose, Config, DOCKER};
+use anyhow::{anyhow, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct State {
+    /// challenge id -> what was deployed
+    #[serde(default)]
+    pub challenges: BTreeMap<String, ChallengeState>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ChallengeState {
+    #[serde(default)]
+    pub containers: BTreeMap<String, ContainerState>,
+    /// scoreboard name -> the challenge on it
+    #[serde(default)]
+    pub scoreboards: BTreeMap<String, ScoreboardState>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ScoreboardState {
+    /// Id of the challenge on the scoreboard
+    pub id: String,
+    /// Hash of the files last uploaded, for platforms that can't say what's in the files they have
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContainerState {
+    pub machine_id: String,
+    pub image: String,
+    /// Hash of the build context the image was built from
+    pub hash: Option<String>,
+    /// Fingerprint of the machine config, see [`crate::backend::MachineSpec::fingerprint`]
+    pub config: Option<String>,
+    /// Digest of the deployed image, see [`image_digest`]
+    #[serde(default)]
+    pub digest: Option<String>,
+    /// Where the container was exposed when it was deployed
+    #[serde(default)]
+    pub expose: Option<Expose>,
+}
+
+/// Held while a command is changing things, removes the lock file when dropped.
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Name of this machine, so a lock can tell whether it was taken here
+fn hostname() -> String {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("unknown".to_string())
+}
+
+/// Whether the process `pid` on this machine is still running
+fn alive(pid: u32) -> bool {
+    if Path::new("/proc/self").exists() {
+        return Path::new(&format!("/proc/{pid}")).exists();
+    }
+    process::Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(true)
+}
+
+/// Whether a lock held by `holder` was left behind by a command on this machine that crashed or
+/// was interrupted. Locks taken elsewhere can't be checked, so they never are.
+fn stale(holder: &str) -> bool {
+    let Some((who, rest)) = holder.split_once(" (pid ") else {
+        return false;
+    };
+    let host = who.rsplit_once('@').map(|(_, host)| host);
+    let pid = rest.split_once(')').and_then(|(pid, _)| pid.parse().ok());
+    match (host, pid) {
+        (Some(host), Some(pid)) => host != "unknown" && host == hostname() && !alive(pid),
+        _ => false,
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl State {
+    fn path(config: &Config) -> PathBuf {
+        config.state_dir.join("state.json")
+    }
+
+    pub fn load(config: &Config) -> Result<State> {
+        let path = State::path(config);
+        match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data)
+                .map_err(|e| anyhow!("failed to parse {}: {e}", path.display())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(State::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, config: &Config) -> Result<()> {
+        let dir = &config.state_dir;
+        fs::create_dir_all(dir)?;
+        // write then rename so a crash mid-write doesn't leave a truncated state behind
+        let tmp = dir.join("state.json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(tmp, State::path(config))?;
+        Ok(())
+    }
+
+    pub fn lock(config: &Config) -> Result<Lock> {
+        let dir = &config.state_dir;
+        fs::create_dir_all(dir)?;
+        let path = dir.join("state.lock");
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                writeln!(
+                    file,
+                    "{}@{} (pid {}) at {}",
+                    env::var("USER").unwrap_or("unknown".to_string()),
+                    hostname(),
+                    process::id(),
+                    now()
+                )?;
+                Ok(Lock { path })
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let holder = fs::read_to_string(&path).unwrap_or_default();
+                let holder = holder.trim();
+                if stale(holder) {
+                    eprintln!(
+                        "{} removing the lock left behind by {holder}, which isn't running anymore",
+                        "WARNING:".yellow().bold()
+                    );
+                    fs::remove_file(&path)?;
+                    return State::lock(config);
+                }
+                Err(anyhow!(
+                    "State is locked by {holder}. If nobody else is deploying, delete {}",
+                    path.display()
+                ))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Forget the container running on the given machine
+    pub fn remove_machine(&mut self, machine_id: &str) {
+        for chall in self.challenges.values_mut() {
+            chall
+                .containers
+                .retain(|_, container| container.machine_id != machine_id);
+        }
+    }
+}
+
+/// Digest of a pushed image, falling back to the local image id for backends that don't push.
+pub async fn image_digest(local: &str, pushed: &str) -> Option<String> {
+    let image = DOCKER.inspect_image(local).await.ok()?;
+    let repo = pushed
+        .rsplit_once(':')
+        .map(|(repo, _)| repo)
+        .unwrap_or(pushed);
+    image
+        .repo_digests
+        .unwrap_or_default()
+        .into_iter()
+        .find(|digest| digest.starts_with(&format!("{repo}@")))
+        .and_then(|digest| digest.split_once('@').map(|(_, d)| d.to_string()))
+        .or(image.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::scratch_dir;
+
+    fn config() -> Config {
+        let mut config: Config = toml::from_str(
+            "hostname = \"example.com\"\nchall_root = \".\"\n[backend]\ntype = \"local\"\n",
+        )
+        .unwrap();
+        config.state_dir = scratch_dir("state");
+        config
+    }
+
+    #[test]
+    fn stale_locks_are_taken_over() {
+        let config = config();
+        let lock = config.state_dir.join("state.lock");
+        let mut exited = process::Command::new("true").spawn().unwrap();
+        exited.wait().unwrap();
+        let here = hostname();
+
+        fs::write(&lock, format!("bear@{here} (pid {}) at 0\n", exited.id())).unwrap();
+        let taken = State::lock(&config).unwrap();
+        assert!(fs::read_to_string(&lock)
+            .unwrap()
+            .contains(&format!("(pid {})", process::id())));
+        drop(taken);
+        assert!(!lock.exists());
+
+        for holder in [
+            format!("bear@{here} (pid {}) at 0", process::id()),
+            format!("bear@somewhere-else (pid {}) at 0", exited.id()),
+        ] {
+            fs::write(&lock, &holder).unwrap();
+            let e = State::lock(&config).err().unwrap().to_string();
+            assert!(e.starts_with(&format!("State is locked by {holder}")));
+        }
+    }
+}
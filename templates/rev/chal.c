@@ -0,0 +1,18 @@
+#include <stdio.h>
+#include <string.h>
+
+int main(void) {
+    char input[128];
+    setbuf(stdout, NULL);
+    printf("password: ");
+    if (!fgets(input, sizeof(input), stdin)) return 1;
+    input[strcspn(input, "\n")] = 0;
+    if (strcmp(input, "hunter2") == 0) {
+        char flag[128] = {0};
+        FILE *f = fopen("flag.txt", "r");
+        if (f && fgets(flag, sizeof(flag), f)) printf("%s", flag);
+    } else {
+        puts("nope");
+    }
+    return 0;
+}
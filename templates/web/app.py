@@ -0,0 +1,9 @@
+from flask import Flask
+
+app = Flask(__name__)
+FLAG = open("flag.txt").read().strip()
+
+
+@app.route("/")
+def index():
+    return "hello!"
@@ -0,0 +1,8 @@
+import sys
+
+import requests
+
+url = sys.argv[1] if len(sys.argv) > 1 else "http://localhost:3000"
+
+r = requests.get(url)
+print(r.text)